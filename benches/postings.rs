@@ -0,0 +1,74 @@
+use audio_fingerprint::{PostingIter, PostingList};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+
+// Posting lists resembling those of a large catalogue: many songs, a few postings each, spread
+// over the length of a song.
+fn posting_lists(list_count: usize, list_len: usize) -> Vec<Vec<(u32, u32)>> {
+    let mut rng = fastrand::Rng::with_seed(7);
+    (0..list_count)
+        .map(|_| {
+            (0..list_len)
+                .map(|_| (rng.u32(0..500_000), rng.u32(0..300_000)))
+                .collect()
+        })
+        .collect()
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("postings");
+
+    for list_len in [8, 128, 2048] {
+        let raw = posting_lists(256, list_len);
+        let encoded: Vec<PostingList> = raw.iter().cloned().map(PostingList::new).collect();
+
+        group.throughput(Throughput::Elements((256 * list_len) as u64));
+        group.bench_function(format!("copy_raw/{list_len}"), |b| {
+            b.iter(|| {
+                for list in raw.iter() {
+                    black_box(list.to_vec());
+                }
+            })
+        });
+        group.bench_function(format!("decode/{list_len}"), |b| {
+            b.iter(|| {
+                for list in encoded.iter() {
+                    black_box(list.decode().unwrap());
+                }
+            })
+        });
+        group.bench_function(format!("iterate/{list_len}"), |b| {
+            b.iter(|| {
+                for list in encoded.iter() {
+                    for posting in PostingIter::new(list.as_bytes()) {
+                        black_box(posting.unwrap());
+                    }
+                }
+            })
+        });
+        group.bench_function(format!("encode/{list_len}"), |b| {
+            b.iter(|| {
+                for list in raw.iter() {
+                    black_box(PostingList::new(list.clone()));
+                }
+            })
+        });
+        group.bench_function(format!("append/{list_len}"), |b| {
+            // Songs are added one at a time, each onto the end of the list.
+            let mut songs: Vec<(u32, u32)> = raw[0].clone();
+            songs.sort_unstable();
+            b.iter(|| {
+                let mut list = PostingList::default();
+                for &posting in songs.iter() {
+                    list.extend(vec![posting]).unwrap();
+                }
+                black_box(list)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
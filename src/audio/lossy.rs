@@ -0,0 +1,252 @@
+use std::io::ErrorKind;
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{CODEC_TYPE_NULL, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track},
+    io::{MediaSource, MediaSourceStream},
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::Hint,
+    units::Time,
+};
+
+use crate::{
+    audio::{Audio, Downmix, TimeRange},
+    error::AudioError,
+};
+
+// Tags as (name, value), named like Vorbis comments where the tag has a standard meaning.
+type Tags = Vec<(String, String)>;
+
+// How far ahead of a range seeks land, in seconds. The first packets after a seek decode only
+// partly, lacking the overlapping block of a Vorbis stream or the bit reservoir of MP3, so these
+// are decoded and trimmed away by their timestamps.
+const SEEK_PREROLL_SECONDS: f32 = 0.1;
+
+// What the metadata of a song needs from a compressed file, read without decoding it.
+pub(crate) struct LossyInfo {
+    pub sample_rate: u32,
+    // The length of the stream in samples per channel, if the container says.
+    pub frames: Option<u64>,
+    pub tags: Tags,
+}
+
+// Decodes `range` of the first audio track of an MP3, Ogg or AAC file. Packets are read from
+// `source` and mixed down to mono one at a time, so only the mono samples are ever held in memory.
+// The reader is seeked to the start of the range where the container allows, and packets are
+// trimmed to the range by their timestamps, so it is decoded from the start otherwise.
+pub(crate) fn load_lossy<S: MediaSource + 'static>(
+    source: S,
+    range: &TimeRange,
+) -> Result<Audio, AudioError> {
+    let (mut reader, _) = open(source)?;
+    let track = audio_track(reader.as_ref())?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    let mut sample_rate = track.codec_params.sample_rate;
+    log::debug!("Lossy codec: {:?}", track.codec_params.codec);
+    log::debug!("Lossy sample_rate: {:?}", sample_rate);
+    log::debug!(
+        "Lossy duration in samples: {:?}",
+        track.codec_params.n_frames
+    );
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    if range.start > SEEK_PREROLL_SECONDS {
+        let seek_to = SeekTo::Time {
+            time: Time::from((range.start - SEEK_PREROLL_SECONDS) as f64),
+            track_id: Some(track_id),
+        };
+        match reader.seek(SeekMode::Accurate, seek_to) {
+            Ok(_) => decoder.reset(),
+            Err(err) => log::debug!("Decoding from the start, as seeking failed: {err}"),
+        }
+    }
+
+    let mut samples = Vec::new();
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet is skipped, like players do, rather than failing the whole file.
+            Err(SymphoniaError::DecodeError(err)) => {
+                log::warn!("Skipping a packet which failed to decode: {err}");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let spec = *decoded.spec();
+        let sample_rate = *sample_rate.get_or_insert(spec.rate);
+
+        // The frames of the packet within the range, by the frame the packet starts at.
+        let (start, frames) = range.frames(sample_rate);
+        let position = match time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(packet.ts());
+                ((time.seconds as f64 + time.frac) * sample_rate as f64).round() as u64
+            }
+            None => packet.ts(),
+        };
+        if frames.is_some_and(|frames| position >= start + frames) {
+            break;
+        }
+        let skipped = start.saturating_sub(position) as usize;
+        let kept = frames.map_or(usize::MAX, |frames| {
+            (start + frames).saturating_sub(position.max(start)) as usize
+        });
+
+        let capacity = decoded.capacity() as u64;
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * spec.channels.count() => {
+                buffer
+            }
+            buffer => buffer.insert(SampleBuffer::new(capacity, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        // The channels of a stream follow the bits of a WAV channel mask.
+        let channels = spec.channels.count().max(1);
+        let downmix = Downmix::new(channels, Some(spec.channels.bits()));
+        samples.extend(
+            buffer
+                .samples()
+                .chunks_exact(channels)
+                .skip(skipped)
+                .take(kept)
+                .map(|frame| downmix.mix(frame)),
+        );
+    }
+
+    let sample_rate = sample_rate.ok_or(AudioError::Lossy(SymphoniaError::DecodeError(
+        "the stream has no sample rate",
+    )))?;
+
+    Ok(Audio {
+        samples,
+        sample_rate,
+    })
+}
+
+// Reads the format and tags of a compressed file.
+pub(crate) fn read_info<S: MediaSource + 'static>(source: S) -> Result<LossyInfo, AudioError> {
+    let (reader, tags) = open(source)?;
+    let track = audio_track(reader.as_ref())?;
+
+    Ok(LossyInfo {
+        sample_rate: track.codec_params.sample_rate.unwrap_or(0),
+        frames: track.codec_params.n_frames,
+        tags,
+    })
+}
+
+fn open<S: MediaSource + 'static>(source: S) -> Result<(Box<dyn FormatReader>, Tags), AudioError> {
+    let stream = MediaSourceStream::new(Box::new(source), Default::default());
+    let mut probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    // Tags may come before the stream, like ID3 tags, or within the container.
+    let mut tags = Vec::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.extend(revision_tags(revision));
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend(revision_tags(revision));
+    }
+
+    Ok((probed.format, tags))
+}
+
+fn audio_track(reader: &dyn FormatReader) -> Result<&Track, AudioError> {
+    reader
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(AudioError::Lossy(SymphoniaError::Unsupported(
+            "no audio track",
+        )))
+}
+
+fn revision_tags(revision: &MetadataRevision) -> impl Iterator<Item = (String, String)> + '_ {
+    revision.tags().iter().map(|tag| {
+        let name = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => String::from("title"),
+            Some(StandardTagKey::Artist) => String::from("artist"),
+            Some(StandardTagKey::Album) => String::from("album"),
+            Some(StandardTagKey::IdentIsrc) => String::from("isrc"),
+            Some(StandardTagKey::Genre) => String::from("genre"),
+            Some(StandardTagKey::Date) => String::from("date"),
+            _ => tag.key.to_lowercase(),
+        };
+        (name, tag.value.to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{
+        audio::{TimeRange, load_audio},
+        metadata::SongMetaData,
+    };
+
+    // Decodes a fixture whole, and a quarter second of it from a quarter second in, which is
+    // seeked to. See tests/fixtures/lossy/README.md for what every fixture holds.
+    fn assert_fixture(name: &str, frames: usize, title: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/lossy")
+            .join(name);
+        let whole = load_audio(&path, &TimeRange::default()).unwrap();
+        assert_eq!(whole.sample_rate, 32000, "{name}");
+        assert_eq!(whole.samples.len(), frames, "{name}");
+        assert!(whole.samples.iter().all(|&sample| sample == 0.0), "{name}");
+
+        let range = TimeRange {
+            start: 0.25,
+            duration: Some(0.25),
+        };
+        assert_eq!(
+            load_audio(&path, &range).unwrap().samples.len(),
+            8000,
+            "{name}"
+        );
+
+        let metadata = SongMetaData::from_file(&path).unwrap();
+        assert_eq!(metadata.title, title, "{name}");
+        assert_eq!(metadata.sample_rate, 32000, "{name}");
+    }
+
+    #[cfg(feature = "mp3")]
+    #[test]
+    fn mp3() {
+        assert_fixture("silence.mp3", 20 * 1152, "Silence");
+    }
+
+    #[cfg(feature = "ogg")]
+    #[test]
+    fn ogg_vorbis() {
+        // The first packet only primes the decoder.
+        assert_fixture("silence.ogg", 249 * 128, "Silence");
+    }
+
+    // ADTS streams have no tags, so the title is the file name.
+    #[cfg(feature = "aac")]
+    #[test]
+    fn adts() {
+        assert_fixture("silence.aac", 30 * 1024, "silence");
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    f32::consts::FRAC_1_SQRT_2,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::error::{AudioError, Error, Result};
+
+#[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+pub(crate) mod lossy;
+pub(crate) mod pcm;
+pub(crate) mod raw;
+pub(crate) mod wav;
+
+pub use raw::{RawEncoding, RawFormat};
+
+// The containers audio can be decoded from. WAV and FLAC are always supported, while the lossy
+// formats need the cargo feature of their decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Mp3,
+    // Ogg Vorbis.
+    Ogg,
+    // AAC, either in an MP4 container such as an .m4a file, or as a raw ADTS stream.
+    Mp4,
+    Adts,
+    // Ogg Opus, which is recognized but cannot be decoded, as there is no pure Rust Opus decoder
+    // to build on.
+    Opus,
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 7] = [
+        AudioFormat::Wav,
+        AudioFormat::Flac,
+        AudioFormat::Mp3,
+        AudioFormat::Ogg,
+        AudioFormat::Mp4,
+        AudioFormat::Adts,
+        AudioFormat::Opus,
+    ];
+
+    // The number of bytes at the start of a file `detect` needs to tell every format apart.
+    pub const HEADER_LEN: usize = 36;
+
+    // Tells the format from the magic bytes at the start of a file, as extensions lie.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        match header {
+            [b'R', b'I', b'F', b'F', ..] | [b'R', b'I', b'F', b'X', ..] => Some(AudioFormat::Wav),
+            [b'f', b'L', b'a', b'C', ..] => Some(AudioFormat::Flac),
+            // The first page of an Ogg Opus stream holds only its 19 byte identification header,
+            // which follows the 27 byte page header and a segment table of one entry.
+            [b'O', b'g', b'g', b'S', ..] if header.get(28..36) == Some(b"OpusHead") => {
+                Some(AudioFormat::Opus)
+            }
+            [b'O', b'g', b'g', b'S', ..] => Some(AudioFormat::Ogg),
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(AudioFormat::Mp4),
+            // An ID3 tag, which is only ever found in front of MP3 frames, or a frame sync. The
+            // layer of ADTS frames is always 0, while MPEG audio uses 1 to 3.
+            [b'I', b'D', b'3', ..] => Some(AudioFormat::Mp3),
+            [0xff, second, ..] if second & 0xf6 == 0xf0 => Some(AudioFormat::Adts),
+            [0xff, second, ..] if second & 0xe0 == 0xe0 => Some(AudioFormat::Mp3),
+            _ => None,
+        }
+    }
+
+    // The cargo feature the format is decoded with, if it is not built in.
+    pub fn feature(self) -> Option<&'static str> {
+        match self {
+            AudioFormat::Wav | AudioFormat::Flac | AudioFormat::Opus => None,
+            AudioFormat::Mp3 => Some("mp3"),
+            AudioFormat::Ogg => Some("ogg"),
+            AudioFormat::Mp4 | AudioFormat::Adts => Some("aac"),
+        }
+    }
+
+    // The extensions files of the format usually have.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            AudioFormat::Wav => &["wav"],
+            AudioFormat::Flac => &["flac"],
+            AudioFormat::Mp3 => &["mp3"],
+            AudioFormat::Ogg => &["ogg", "oga"],
+            AudioFormat::Mp4 => &["m4a", "mp4"],
+            AudioFormat::Adts => &["aac"],
+            AudioFormat::Opus => &["opus"],
+        }
+    }
+
+    // Whether this build can decode the format.
+    pub fn is_supported(self) -> bool {
+        match self {
+            AudioFormat::Wav | AudioFormat::Flac => true,
+            AudioFormat::Mp3 => cfg!(feature = "mp3"),
+            AudioFormat::Ogg => cfg!(feature = "ogg"),
+            AudioFormat::Mp4 | AudioFormat::Adts => cfg!(feature = "aac"),
+            AudioFormat::Opus => false,
+        }
+    }
+
+    // Why this build cannot decode the format.
+    pub(crate) fn unsupported(self) -> AudioError {
+        match self.feature() {
+            Some(_) => AudioError::FeatureDisabled(self),
+            None => AudioError::NoDecoder(self),
+        }
+    }
+}
+
+// Decoded audio: mono samples between -1 and 1, whatever the format they were stored in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+// A span of audio, in seconds from its start. The default is the whole of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeRange {
+    pub start: f32,
+    // Up to the end of the audio when not given.
+    pub duration: Option<f32>,
+}
+
+impl TimeRange {
+    pub fn is_whole(&self) -> bool {
+        self.start <= 0.0 && self.duration.is_none()
+    }
+
+    // Fails with `Error::InvalidInput` for a negative start or duration.
+    pub fn validate(&self) -> Result<()> {
+        if !(self.start.is_finite() && self.start >= 0.0) {
+            return Err(Error::InvalidInput(String::from(
+                "the start of a time range must be 0 or more seconds",
+            )));
+        }
+        if let Some(duration) = self.duration
+            && !(duration.is_finite() && duration > 0.0)
+        {
+            return Err(Error::InvalidInput(String::from(
+                "the duration of a time range must be positive",
+            )));
+        }
+        Ok(())
+    }
+
+    // The first frame of the range at `sample_rate`, and the number of frames in it, if limited.
+    pub(crate) fn frames(&self, sample_rate: u32) -> (u64, Option<u64>) {
+        let frames = |seconds: f32| (seconds.max(0.0) as f64 * sample_rate as f64).round() as u64;
+        (frames(self.start), self.duration.map(frames))
+    }
+}
+
+// Decodes `range` of the audio file at `path`, in any format `AudioFormat::detect` recognizes.
+// WAV files are read from the start of the range, and lossy files seeked to it where their
+// container allows, while FLAC files are decoded from the start, as there is no seeking in them.
+pub fn load_audio(path: &Path, range: &TimeRange) -> Result<Audio, AudioError> {
+    log::debug!("Loading {range:?} of audio from {path:?}");
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = Vec::new();
+    (&mut reader)
+        .take(AudioFormat::HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    match AudioFormat::detect(&header) {
+        Some(AudioFormat::Wav) => wav::load_wav(reader, range),
+        Some(AudioFormat::Flac) => load_flac(reader, range),
+        #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+        Some(format) if format.is_supported() => lossy::load_lossy(reader.into_inner(), range),
+        Some(format) => Err(format.unsupported()),
+        None => Err(AudioError::UnknownFormat),
+    }
+}
+
+// Decodes `range` of the headerless PCM at `path`, which is in `format` as no file says what it
+// is in.
+pub fn load_raw(path: &Path, format: &RawFormat, range: &TimeRange) -> Result<Audio, AudioError> {
+    log::debug!("Loading {range:?} of raw audio from {path:?}");
+    raw::decode_raw(BufReader::new(File::open(path)?), format, range)
+}
+
+// Decodes `range` of a FLAC stream. claxon cannot seek, so every frame ahead of the range is
+// decoded only to be thrown away: a range starting an hour into a file takes as long as decoding
+// that hour, though no more memory than the range itself, as skipped samples are never kept.
+fn load_flac<R: Read>(reader: R, range: &TimeRange) -> Result<Audio, AudioError> {
+    let mut flac_reader = claxon::FlacReader::new(reader)?;
+
+    let info = flac_reader.streaminfo();
+    log::debug!("FLAC sample_rate: {:?}", info.sample_rate);
+    log::debug!("FLAC channels: {:?}", info.channels);
+    log::debug!("FLAC bits / sample: {:?}", info.bits_per_sample);
+    log::debug!("FLAC duration in samples: {:?}", info.samples);
+
+    // Samples are signed integers of up to 32 bits, scaled like those of a WAV file.
+    let scale = 1.0 / (1u64 << (info.bits_per_sample - 1)) as f32;
+    // Samples are interleaved, so the range covers every channel of its frames.
+    let channels = info.channels as u64;
+    let (start, frames) = range.frames(info.sample_rate);
+    let samples = flac_reader
+        .samples()
+        .skip((start * channels) as usize)
+        .take(frames.map_or(usize::MAX, |frames| (frames * channels) as usize))
+        .map(|x| x.map(|sample| sample as f32 * scale))
+        .collect::<Result<Vec<f32>, _>>()?;
+
+    // FLAC orders the channels of every layout like WAV files without a channel mask.
+    let downmix = Downmix::new(info.channels as usize, None);
+    Ok(Audio {
+        samples: samples
+            .chunks_exact(info.channels as usize)
+            .map(|frame| downmix.mix(frame))
+            .collect(),
+        sample_rate: info.sample_rate,
+    })
+}
+
+// Surround channels go into left and right at -3 dB, so into mono at half of that.
+const SURROUND: f32 = FRAC_1_SQRT_2 / 2.0;
+
+// The weight of every speaker of a channel mask, in the order of its bits, when mixed down to
+// mono as in ITU-R BS.775: the front left and right halved, as in (L + R) / 2, centres at -3 dB,
+// surrounds as above and the LFE left out.
+const SPEAKER_WEIGHTS: [f32; 18] = [
+    0.5,           // Front left
+    0.5,           // Front right
+    FRAC_1_SQRT_2, // Front centre
+    0.0,           // Low frequency effects
+    SURROUND,      // Back left
+    SURROUND,      // Back right
+    0.5,           // Front left of centre
+    0.5,           // Front right of centre
+    FRAC_1_SQRT_2, // Back centre
+    SURROUND,      // Side left
+    SURROUND,      // Side right
+    FRAC_1_SQRT_2, // Top centre
+    SURROUND,      // Top front left
+    FRAC_1_SQRT_2, // Top front centre
+    SURROUND,      // Top front right
+    SURROUND,      // Top back left
+    FRAC_1_SQRT_2, // Top back centre
+    SURROUND,      // Top back right
+];
+
+// The speakers of files which do not say, by the number of channels: mono, stereo, 3.0, quad,
+// 5.0, 5.1, 6.1 and 7.1.
+fn default_channel_mask(channels: usize) -> Option<u32> {
+    match channels {
+        1 => Some(0x4),
+        2 => Some(0x3),
+        3 => Some(0x7),
+        4 => Some(0x33),
+        5 => Some(0x37),
+        6 => Some(0x3f),
+        7 => Some(0x70f),
+        8 => Some(0x63f),
+        _ => None,
+    }
+}
+
+// Mixes frames of interleaved channels down to mono, weighing every channel by its speaker.
+pub(crate) struct Downmix {
+    weights: Vec<f32>,
+}
+
+impl Downmix {
+    // Channels are assigned the speakers of `channel_mask` in order, and those beyond it are left
+    // out. Without a usable mask, the channels of unknown layouts are averaged.
+    pub fn new(channels: usize, channel_mask: Option<u32>) -> Self {
+        if channels == 1 {
+            return Self { weights: vec![1.0] };
+        }
+        let mask = channel_mask
+            .filter(|mask| *mask != 0)
+            .or_else(|| default_channel_mask(channels));
+        let Some(mask) = mask else {
+            return Self {
+                weights: vec![1.0 / channels as f32; channels],
+            };
+        };
+
+        let mut weights: Vec<f32> = SPEAKER_WEIGHTS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| mask & (1 << bit) != 0)
+            .map(|(_, weight)| *weight)
+            .take(channels)
+            .collect();
+        weights.resize(channels, 0.0);
+
+        Self { weights }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn mix(&self, frame: &[f32]) -> f32 {
+        frame
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(sample, weight)| sample * weight)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::{fs, path::Path};
+
+    use crate::{
+        audio::{AudioFormat, TimeRange, load_audio},
+        error::AudioError,
+    };
+
+    // Encodes interleaved 16 bit samples as FLAC, with every channel stored verbatim.
+    fn encode_flac(samples: &[i16], channels: u8, sample_rate: u32) -> Vec<u8> {
+        const BLOCK_SIZE: usize = 1024;
+        let frames = samples.len() / channels as usize;
+
+        let mut flac = b"fLaC".to_vec();
+        // The only metadata block, STREAMINFO, of 34 bytes.
+        flac.extend([0x80, 0, 0, 34]);
+        flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+        flac.extend((BLOCK_SIZE as u16).to_be_bytes());
+        flac.extend([0; 6]);
+        let packed =
+            (sample_rate as u64) << 44 | ((channels - 1) as u64) << 41 | 15 << 36 | frames as u64;
+        flac.extend(packed.to_be_bytes());
+        flac.extend([0; 16]);
+
+        for (number, block) in samples.chunks(BLOCK_SIZE * channels as usize).enumerate() {
+            let start = flac.len();
+            let block_size = block.len() / channels as usize;
+            // Fixed block sizes, the size in 16 bits at the end of the header, the rate from
+            // STREAMINFO, independent channels and 16 bit samples.
+            flac.extend([0xff, 0xf8, 0x70, (channels - 1) << 4 | 0x08, number as u8]);
+            flac.extend(((block_size - 1) as u16).to_be_bytes());
+            flac.push(crc8(&flac[start..]));
+            for channel in 0..channels as usize {
+                flac.push(0x02);
+                for frame in block.chunks(channels as usize) {
+                    flac.extend(frame[channel].to_be_bytes());
+                }
+            }
+            flac.extend(crc16(&flac[start..]).to_be_bytes());
+        }
+
+        flac
+    }
+
+    fn crc8(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ byte, |crc, _| {
+                if crc & 0x80 != 0 {
+                    crc << 1 ^ 0x07
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    fn crc16(bytes: &[u8]) -> u16 {
+        bytes.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+                if crc & 0x8000 != 0 {
+                    crc << 1 ^ 0x8005
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    #[test]
+    fn flac_matches_wav() {
+        let samples: Vec<i16> = (0..5000)
+            .map(|i| ((i as f32 * 0.05).sin() * 20000.0) as i16)
+            .collect();
+        let directory = crate::temp_path("audio");
+        fs::create_dir_all(&directory).unwrap();
+
+        // The FLAC file is named .wav and the WAV file .flac, as only their contents count.
+        let flac_path = directory.join("song.wav");
+        fs::write(&flac_path, encode_flac(&samples, 2, 44100)).unwrap();
+        let wav_path = directory.join("song.flac");
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&wav_path, spec).unwrap();
+        for &sample in samples.iter() {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let whole = TimeRange::default();
+        let flac = load_audio(&flac_path, &whole).unwrap();
+        let wav = load_audio(&wav_path, &whole).unwrap();
+        assert_eq!(flac.sample_rate, 44100);
+        assert_eq!(flac.samples.len(), 2500);
+        assert_eq!(wav.samples.len(), 2500);
+        for (flac, wav) in flac.samples.iter().zip(wav.samples.iter()) {
+            assert!((flac - wav).abs() < 1e-4);
+        }
+
+        // Frames 1000 to 1500, skipped to in the FLAC file rather than seeked to.
+        let range = TimeRange {
+            start: 1000.0 / 44100.0,
+            duration: Some(500.0 / 44100.0),
+        };
+        let flac_range = load_audio(&flac_path, &range).unwrap();
+        assert_eq!(flac_range.samples, flac.samples[1000..1500]);
+
+        fs::write(&flac_path, b"not audio at all").unwrap();
+        assert!(matches!(
+            load_audio(&flac_path, &whole),
+            Err(AudioError::UnknownFormat)
+        ));
+        #[cfg(not(feature = "mp3"))]
+        {
+            fs::write(&flac_path, b"ID3 and some MP3 frames").unwrap();
+            assert!(matches!(
+                load_audio(&flac_path, &whole),
+                Err(AudioError::FeatureDisabled(AudioFormat::Mp3))
+            ));
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Lossy files are decoded when their feature is enabled, and fail naming it otherwise.
+    #[test]
+    fn lossy_dispatch() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lossy");
+        for (name, format) in [
+            ("silence.mp3", AudioFormat::Mp3),
+            ("silence.ogg", AudioFormat::Ogg),
+            ("silence.aac", AudioFormat::Adts),
+        ] {
+            match load_audio(&fixtures.join(name), &TimeRange::default()) {
+                Ok(audio) => assert!(format.is_supported() && audio.sample_rate == 32000),
+                Err(AudioError::FeatureDisabled(disabled)) => {
+                    assert!(!format.is_supported() && disabled == format)
+                }
+                Err(err) => panic!("{name}: {err}"),
+            }
+        }
+
+        // Ogg Opus is recognized, but never decoded.
+        let path = crate::temp_path("opus.ogg");
+        let mut opus = b"OggS\0\x02".to_vec();
+        // The granule position, serial number, sequence number and checksum, and one segment.
+        opus.extend([0; 20]);
+        opus.extend([1, 19]);
+        opus.extend(b"OpusHead\x01\x01\0\0\x80\xbb\0\0\0\0\0");
+        fs::write(&path, &opus).unwrap();
+        assert!(matches!(
+            load_audio(&path, &TimeRange::default()),
+            Err(AudioError::NoDecoder(AudioFormat::Opus))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn detect_formats() {
+        let detect = |header: &[u8]| AudioFormat::detect(header);
+        assert_eq!(detect(b"RIFF\x24\0\0\0WAVE"), Some(AudioFormat::Wav));
+        assert_eq!(detect(b"fLaC\0\0\0\x22"), Some(AudioFormat::Flac));
+        assert_eq!(detect(b"OggS\0\x02"), Some(AudioFormat::Ogg));
+        assert_eq!(detect(b"\0\0\0\x20ftypM4A "), Some(AudioFormat::Mp4));
+        assert_eq!(detect(b"ID3\x04\0"), Some(AudioFormat::Mp3));
+        // MPEG 1 layer 3, and ADTS with and without a CRC.
+        assert_eq!(detect(&[0xff, 0xfb, 0x90, 0x64]), Some(AudioFormat::Mp3));
+        assert_eq!(detect(&[0xff, 0xf1, 0x50, 0x80]), Some(AudioFormat::Adts));
+        assert_eq!(detect(&[0xff, 0xf0, 0x50, 0x80]), Some(AudioFormat::Adts));
+        assert_eq!(detect(b"RIF"), None);
+        assert_eq!(detect(&[]), None);
+    }
+}
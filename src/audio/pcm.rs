@@ -0,0 +1,100 @@
+use std::io::{self, Read};
+
+use crate::{audio::Downmix, error::AudioError};
+
+// How uncompressed samples are encoded, in WAV files and raw streams alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    // Little-endian integers, unsigned for 8 bits and signed otherwise.
+    Int,
+    // Little-endian IEEE floats of 32 or 64 bits.
+    Float,
+    // The 8 bit logarithmic encodings of G.711 telephony.
+    MuLaw,
+    ALaw,
+}
+
+// Decodes interleaved frames of `channels` samples of `sample_bytes` each, a block at a time,
+// straight into mono. A frame cut short at the end is dropped.
+pub(crate) fn decode_frames<R: Read>(
+    mut reader: R,
+    encoding: Encoding,
+    sample_bytes: usize,
+    downmix: &Downmix,
+) -> Result<Vec<f32>, AudioError> {
+    let channels = downmix.channels();
+    let block_align = sample_bytes * channels;
+
+    let mut samples = Vec::new();
+    let mut block = vec![0u8; block_align * 4096];
+    let mut frame = vec![0f32; channels];
+    let mut filled = 0;
+    loop {
+        let read = match reader.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        filled += read;
+
+        let whole = filled - filled % block_align;
+        for bytes in block[..whole].chunks_exact(block_align) {
+            for (sample, bytes) in frame.iter_mut().zip(bytes.chunks_exact(sample_bytes)) {
+                *sample = decode_sample(bytes, encoding);
+            }
+            samples.push(downmix.mix(&frame));
+        }
+        block.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+
+    Ok(samples)
+}
+
+// Integers are scaled by a power of two, so every width maps its range onto -1 to 1 alike.
+fn decode_sample(bytes: &[u8], encoding: Encoding) -> f32 {
+    match (encoding, bytes.len()) {
+        (Encoding::Int, 1) => (bytes[0] as f32 - 128.0) / 128.0,
+        (Encoding::Int, width) => {
+            // Place the sample in the top bytes of an i32, sign and all.
+            let mut word = [0u8; 4];
+            word[4 - width..].copy_from_slice(bytes);
+            i32::from_le_bytes(word) as f32 / 2f32.powi(31)
+        }
+        (Encoding::Float, 4) => f32::from_le_bytes(bytes.try_into().unwrap()),
+        (Encoding::Float, _) => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+        (Encoding::MuLaw, _) => mu_law(bytes[0]) as f32 / 32768.0,
+        (Encoding::ALaw, _) => a_law(bytes[0]) as f32 / 32768.0,
+    }
+}
+
+// Expands a G.711 µ-law byte into a 16 bit sample.
+fn mu_law(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i16;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+// Expands a G.711 A-law byte into a 16 bit sample.
+fn a_law(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i16;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    // Unlike µ-law, a set sign bit means a positive sample.
+    if byte & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{
+    audio::{
+        Audio, Downmix, TimeRange,
+        pcm::{self, Encoding},
+    },
+    error::{AudioError, Error, Result},
+};
+
+// How the samples of headerless PCM are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawEncoding {
+    // 16 bit signed little-endian integers.
+    S16le,
+    // 32 bit little-endian floats.
+    F32le,
+    // 8 bit G.711 µ-law and A-law, as used in telephony.
+    Mulaw,
+    Alaw,
+}
+
+impl RawEncoding {
+    fn encoding(self) -> (Encoding, usize) {
+        match self {
+            RawEncoding::S16le => (Encoding::Int, 2),
+            RawEncoding::F32le => (Encoding::Float, 4),
+            RawEncoding::Mulaw => (Encoding::MuLaw, 1),
+            RawEncoding::Alaw => (Encoding::ALaw, 1),
+        }
+    }
+
+    pub fn sample_bytes(self) -> usize {
+        self.encoding().1
+    }
+}
+
+// The format of headerless PCM, such as that of capture devices, which has to be given as it
+// cannot be read from the audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawFormat {
+    pub encoding: RawEncoding,
+    pub sample_rate: u32,
+    // Interleaved, in the order of a WAV file without a channel mask.
+    #[serde(default = "RawFormat::default_channels")]
+    pub channels: u16,
+}
+
+impl RawFormat {
+    fn default_channels() -> u16 {
+        1
+    }
+
+    // The bytes of one sample of every channel.
+    pub fn frame_bytes(&self) -> usize {
+        self.encoding.sample_bytes() * self.channels as usize
+    }
+
+    // Fails with `Error::InvalidInput` for formats audio cannot be in.
+    pub fn validate(&self) -> Result<()> {
+        if self.sample_rate == 0 {
+            return Err(Error::InvalidInput(String::from(
+                "the sample rate of raw audio must be positive",
+            )));
+        }
+        if self.channels == 0 {
+            return Err(Error::InvalidInput(String::from(
+                "raw audio must have at least 1 channel",
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Decodes `range` of headerless PCM in `format`. Whatever the reader holds is taken as samples,
+// and it is seeked straight to the start of the range.
+pub(crate) fn decode_raw<R: Read + Seek>(
+    mut reader: R,
+    format: &RawFormat,
+    range: &TimeRange,
+) -> Result<Audio, AudioError> {
+    log::debug!("Raw format: {:?}", format);
+    let (encoding, sample_bytes) = format.encoding.encoding();
+    let downmix = Downmix::new(format.channels as usize, None);
+
+    let frame_bytes = format.frame_bytes() as u64;
+    let (start, frames) = range.frames(format.sample_rate);
+    reader.seek(SeekFrom::Start(start * frame_bytes))?;
+    let data = reader.take(frames.map_or(u64::MAX, |frames| frames * frame_bytes));
+
+    Ok(Audio {
+        samples: pcm::decode_frames(data, encoding, sample_bytes, &downmix)?,
+        sample_rate: format.sample_rate,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::audio::{
+        TimeRange,
+        raw::{RawEncoding, RawFormat, decode_raw},
+    };
+
+    #[test]
+    fn raw_encodings() {
+        let load_range = |bytes: &[u8], encoding, channels, range: TimeRange| {
+            let format = RawFormat {
+                encoding,
+                sample_rate: 8000,
+                channels,
+            };
+            decode_raw(Cursor::new(bytes), &format, &range)
+                .unwrap()
+                .samples
+        };
+        let load = |bytes: &[u8], encoding, channels| {
+            load_range(bytes, encoding, channels, TimeRange::default())
+        };
+
+        let s16: Vec<u8> = [-32768i16, 0, 16384]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(load(&s16, RawEncoding::S16le, 1), [-1.0, 0.0, 0.5]);
+        // Stereo frames are mixed down, and the odd byte at the end dropped.
+        assert_eq!(load(&s16[..5], RawEncoding::S16le, 2), [-0.5]);
+        // A range of one sample from the second, at 8000 Hz.
+        let range = TimeRange {
+            start: 1.0 / 8000.0,
+            duration: Some(1.0 / 8000.0),
+        };
+        assert_eq!(load_range(&s16, RawEncoding::S16le, 1, range), [0.0]);
+
+        let f32le: Vec<u8> = [0.25f32, -0.75]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(load(&f32le, RawEncoding::F32le, 1), [0.25, -0.75]);
+
+        // Silence, and the loudest samples of either sign, from the G.711 tables.
+        let scale = |sample: i16| sample as f32 / 32768.0;
+        assert_eq!(
+            load(&[0xff, 0x80, 0x00], RawEncoding::Mulaw, 1),
+            [0.0, scale(32124), scale(-32124)]
+        );
+        assert_eq!(
+            load(&[0xd5, 0x55, 0xaa, 0x2a], RawEncoding::Alaw, 1),
+            [scale(8), scale(-8), scale(32256), scale(-32256)]
+        );
+    }
+}
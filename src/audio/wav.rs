@@ -0,0 +1,290 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+    audio::{
+        Audio, Downmix, TimeRange,
+        pcm::{self, Encoding},
+    },
+    error::AudioError,
+};
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_ALAW: u16 = 0x0006;
+const WAVE_FORMAT_MULAW: u16 = 0x0007;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// The `fmt ` chunk of a WAV file, along with the length of its `data` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WavHeader {
+    pub encoding: Encoding,
+    pub channels: u16,
+    pub sample_rate: u32,
+    // The bytes every sample takes. Samples with fewer valid bits are left-justified in them.
+    pub sample_bytes: u16,
+    // The speaker of every channel, from WAVE_FORMAT_EXTENSIBLE files.
+    pub channel_mask: Option<u32>,
+    pub data_len: u64,
+}
+
+impl WavHeader {
+    pub fn block_align(&self) -> u64 {
+        self.channels as u64 * self.sample_bytes as u64
+    }
+}
+
+// Reads the chunks of a WAV file up to the start of its samples, which `reader` is left at.
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<WavHeader, AudioError> {
+    let mut riff = [0u8; 12];
+    read_exact(reader, &mut riff)?;
+    if &riff[..4] == b"RIFX" {
+        return Err(AudioError::UnsupportedWav(String::from(
+            "big-endian RIFX files",
+        )));
+    }
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err(AudioError::InvalidWav("no RIFF/WAVE header"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        read_exact(reader, &mut chunk).map_err(|err| match err {
+            AudioError::InvalidWav(_) => AudioError::InvalidWav("no data chunk"),
+            err => err,
+        })?;
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as u64;
+
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut body = vec![0u8; size as usize];
+                read_exact(reader, &mut body)?;
+                skip(reader, size % 2)?;
+                format = Some(parse_format(&body)?);
+            }
+            b"data" => {
+                let Some(mut header) = format else {
+                    return Err(AudioError::InvalidWav("data chunk before the fmt chunk"));
+                };
+                header.data_len = size;
+                return Ok(header);
+            }
+            // Chunks are padded to an even size.
+            _ => skip(reader, size + size % 2)?,
+        }
+    }
+}
+
+fn parse_format(body: &[u8]) -> Result<WavHeader, AudioError> {
+    if body.len() < 16 {
+        return Err(AudioError::InvalidWav("fmt chunk too short"));
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes(body[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(body[offset..offset + 4].try_into().unwrap());
+
+    let mut format_tag = u16_at(0);
+    let channels = u16_at(2);
+    let sample_rate = u32_at(4);
+    let block_align = u16_at(12);
+    let bits_per_sample = u16_at(14);
+
+    let mut channel_mask = None;
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        if body.len() < 40 {
+            return Err(AudioError::InvalidWav("extensible fmt chunk too short"));
+        }
+        channel_mask = Some(u32_at(20));
+        // The sub format is a GUID, which starts with the format tag it stands for.
+        format_tag = u16_at(24);
+    }
+
+    if channels == 0 || sample_rate == 0 {
+        return Err(AudioError::InvalidWav("no channels or no sample rate"));
+    }
+    // The block alignment is the truth on the size of samples, as some writers set the bits per
+    // sample to the valid bits, such as 20 bits in 3 bytes.
+    let sample_bytes = if block_align > 0 && block_align % channels == 0 {
+        block_align / channels
+    } else {
+        bits_per_sample.div_ceil(8)
+    };
+
+    let encoding = match (format_tag, sample_bytes) {
+        (WAVE_FORMAT_PCM, 1..=4) => Encoding::Int,
+        (WAVE_FORMAT_IEEE_FLOAT, 4 | 8) => Encoding::Float,
+        (WAVE_FORMAT_ALAW, 1) => Encoding::ALaw,
+        (WAVE_FORMAT_MULAW, 1) => Encoding::MuLaw,
+        (WAVE_FORMAT_PCM | WAVE_FORMAT_IEEE_FLOAT, _) => {
+            return Err(AudioError::UnsupportedWav(format!(
+                "{} bit {} samples",
+                sample_bytes * 8,
+                if format_tag == WAVE_FORMAT_PCM {
+                    "integer"
+                } else {
+                    "float"
+                }
+            )));
+        }
+        _ => {
+            return Err(AudioError::UnsupportedWav(format!(
+                "format tag {format_tag:#06x}"
+            )));
+        }
+    };
+
+    Ok(WavHeader {
+        encoding,
+        channels,
+        sample_rate,
+        sample_bytes,
+        channel_mask,
+        data_len: 0,
+    })
+}
+
+// Decodes `range` of a WAV file, a block of samples at a time, straight into mono, seeking past
+// the samples before it. A `data` chunk cut short, as left behind by recordings which were never
+// finished, is decoded as far as it goes.
+pub(crate) fn load_wav<R: Read + Seek>(
+    mut reader: R,
+    range: &TimeRange,
+) -> Result<Audio, AudioError> {
+    let header = read_header(&mut reader)?;
+    log::debug!("WAV format: {:?}", header);
+    log::debug!(
+        "Song duration: {:?}s",
+        header.data_len as f32 / header.block_align() as f32 / header.sample_rate as f32
+    );
+
+    let block_align = header.block_align();
+    let (start, frames) = range.frames(header.sample_rate);
+    let skipped = (start * block_align).min(header.data_len);
+    reader.seek(SeekFrom::Current(skipped as i64))?;
+    let mut data_len = header.data_len - skipped;
+    if let Some(frames) = frames {
+        data_len = data_len.min(frames * block_align);
+    }
+
+    let downmix = Downmix::new(header.channels as usize, header.channel_mask);
+    let data = reader.take(data_len);
+
+    Ok(Audio {
+        samples: pcm::decode_frames(
+            data,
+            header.encoding,
+            header.sample_bytes as usize,
+            &downmix,
+        )?,
+        sample_rate: header.sample_rate,
+    })
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), AudioError> {
+    reader.read_exact(buffer).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => AudioError::InvalidWav("truncated chunk"),
+        _ => AudioError::Io(err),
+    })
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> Result<(), AudioError> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(AudioError::InvalidWav("truncated chunk"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{f32::consts::FRAC_1_SQRT_2, fs::File, path::Path};
+
+    use crate::{
+        audio::{TimeRange, wav::load_wav},
+        error::AudioError,
+    };
+
+    fn load_range(name: &str, range: TimeRange) -> Result<(Vec<f32>, u32), AudioError> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/wav")
+            .join(name);
+        let audio = load_wav(File::open(path).unwrap(), &range)?;
+        Ok((audio.samples, audio.sample_rate))
+    }
+
+    fn load(name: &str) -> Result<(Vec<f32>, u32), AudioError> {
+        load_range(name, TimeRange::default())
+    }
+
+    fn assert_samples(name: &str, expected: &[f32]) {
+        assert_range(name, TimeRange::default(), expected);
+    }
+
+    fn assert_range(name: &str, range: TimeRange, expected: &[f32]) {
+        let (samples, sample_rate) = load_range(name, range).unwrap();
+        assert_eq!(sample_rate, 8000, "{name}");
+        assert_eq!(samples.len(), expected.len(), "{name}");
+        for (sample, expected) in samples.iter().zip(expected) {
+            assert!(
+                (sample - expected).abs() < 1e-6,
+                "{name}: {samples:?} != {expected:?}"
+            );
+        }
+    }
+
+    // See tests/fixtures/wav/README.md for what every fixture holds.
+    #[test]
+    fn wav_fixtures() {
+        let ramp = [-1.0, -0.5, 0.0, 0.5, 0.75];
+        for name in [
+            "pcm_u8.wav",
+            "pcm_s16.wav",
+            "pcm_s24.wav",
+            "pcm_s32.wav",
+            "float32.wav",
+            "float64.wav",
+            "extensible_s24.wav",
+            "extensible_s20_in_s24.wav",
+            "with_list_chunk.wav",
+        ] {
+            assert_samples(name, &ramp);
+        }
+
+        assert_samples("stereo_s16.wav", &[0.25, -0.25, 0.0]);
+
+        let mu_law = 32124.0 / 32768.0;
+        assert_samples("mulaw.wav", &[0.0, mu_law, -mu_law]);
+        let a_law = 32256.0 / 32768.0;
+        assert_samples("alaw.wav", &[8.0 / 32768.0, a_law, -a_law]);
+
+        // Every channel holds a different level, and the downmix weighs them by their speaker.
+        let surround = FRAC_1_SQRT_2 / 2.0;
+        let five_one = 0.5 * 0.375 + 0.5 * 0.25 + FRAC_1_SQRT_2 * 0.125 + surround * (0.25 + 0.5);
+        assert_samples("surround_5_1_s16.wav", &[five_one, -five_one]);
+        assert_samples("extensible_5_1_s16.wav", &[five_one, -five_one]);
+        let seven_one = five_one + surround * (0.125 + 0.0625);
+        assert_samples("extensible_7_1_float32.wav", &[seven_one, -seven_one]);
+
+        // The data chunk claims more than the file holds, and the last frame is cut in half.
+        assert_samples("truncated_s16.wav", &ramp[..3]);
+
+        // Ranges of whole samples at 8000 Hz, some running past the end of the file.
+        let samples = |count: f32| count / 8000.0;
+        let range = |start, duration: Option<f32>| TimeRange {
+            start: samples(start),
+            duration: duration.map(samples),
+        };
+        assert_range("pcm_s24.wav", range(1.0, Some(3.0)), &ramp[1..4]);
+        assert_range("stereo_s16.wav", range(2.0, None), &[0.0]);
+        assert_range("truncated_s16.wav", range(2.0, Some(2.0)), &ramp[2..3]);
+        assert_range("float64.wav", range(10.0, None), &[]);
+
+        assert!(matches!(
+            load("adpcm.wav"),
+            Err(AudioError::UnsupportedWav(_))
+        ));
+        assert!(matches!(
+            load("no_data.wav"),
+            Err(AudioError::InvalidWav(_))
+        ));
+    }
+}
@@ -0,0 +1,171 @@
+use serde::Serialize;
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio::TimeRange,
+    error::Result,
+    fingerprint::MatchResult,
+    fingerprinter::Fingerprinter,
+    metadata::SongMetaData,
+    scan::{ScanOptions, find_audio_files},
+    storage::FingerprintStore,
+};
+
+// The outcome of recognizing one of many queries.
+pub struct QueryResult {
+    pub query: PathBuf,
+    // The best matching song, if any. A query which fails does not fail the others.
+    pub outcome: Result<Option<(SongMetaData, MatchResult)>>,
+    pub timings: QueryTimings,
+}
+
+// Where the time recognizing a query went. Steps the query did not get to take no time.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct QueryTimings {
+    // Reading and decoding the audio.
+    pub load: Duration,
+    // Computing the spectrogram and extracting its peaks.
+    pub fingerprint: Duration,
+    // Hashing the peaks and counting the votes of every matching posting in the store.
+    pub lookup: Duration,
+}
+
+impl QueryTimings {
+    pub fn total(&self) -> Duration {
+        self.load + self.fingerprint + self.lookup
+    }
+}
+
+// The queries to recognize: every file under `path` selected by `options` if it is a directory,
+// or else every path listed in the file, one per line and relative to it. Blank lines and lines
+// starting with `#` are skipped.
+pub fn find_queries(path: &Path, options: &ScanOptions) -> Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        return find_audio_files(path, options);
+    }
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let queries = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| directory.join(line))
+        .collect();
+
+    Ok(queries)
+}
+
+// Recognizes `range` of every query, returning the results in the order of `queries`. Every store
+// handle gets a thread of its own, which decodes, fingerprints and looks up queries through it, so
+// the handles may be references to one store shared by every thread, or separate connections.
+pub fn recognize_queries<H>(
+    stores: Vec<H>,
+    fingerprinter: &Fingerprinter,
+    queries: &[PathBuf],
+    range: &TimeRange,
+) -> Vec<QueryResult>
+where
+    H: Deref + Send,
+    H::Target: FingerprintStore,
+{
+    // Every thread takes the next query not yet taken, until none are left.
+    let next_query = AtomicUsize::new(0);
+    let mut results: Vec<(usize, QueryResult)> = thread::scope(|scope| {
+        let workers: Vec<_> = stores
+            .into_iter()
+            .map(|store| {
+                let next_query = &next_query;
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next_query.fetch_add(1, Ordering::Relaxed);
+                        let Some(query) = queries.get(index) else {
+                            break;
+                        };
+                        let mut timings = QueryTimings::default();
+                        let outcome =
+                            recognize_query(fingerprinter, &*store, query, range, &mut timings);
+                        if let Err(err) = &outcome {
+                            log::error!("Unable to recognize {:?}: {}", query, err);
+                        }
+                        results.push((
+                            index,
+                            QueryResult {
+                                query: query.clone(),
+                                outcome,
+                                timings,
+                            },
+                        ));
+                    }
+                    results
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Recognition thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn recognize_query<S: FingerprintStore + ?Sized>(
+    fingerprinter: &Fingerprinter,
+    store: &S,
+    query: &Path,
+    range: &TimeRange,
+    timings: &mut QueryTimings,
+) -> Result<Option<(SongMetaData, MatchResult)>> {
+    let start = Instant::now();
+    let samples = fingerprinter.load_range(query, range)?;
+    timings.load = start.elapsed();
+
+    let start = Instant::now();
+    let peaks = fingerprinter.query_peaks(&samples)?;
+    timings.fingerprint = start.elapsed();
+
+    let start = Instant::now();
+    let result = store.recognize_song(&peaks, fingerprinter.config());
+    timings.lookup = start.elapsed();
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{ScanOptions, batch::find_queries};
+
+    #[test]
+    fn query_list() {
+        let directory = crate::temp_path("batch");
+        fs::create_dir_all(directory.join("clips")).unwrap();
+        fs::write(directory.join("clips/b.wav"), b"").unwrap();
+        fs::write(directory.join("clips/a.wav"), b"").unwrap();
+        let list = directory.join("queries.txt");
+        fs::write(&list, "# nightly clips\nclips/b.wav\n\n  clips/a.wav \n").unwrap();
+
+        let options = ScanOptions::default();
+        assert_eq!(
+            find_queries(&list, &options).unwrap(),
+            [directory.join("clips/b.wav"), directory.join("clips/a.wav")]
+        );
+        assert_eq!(
+            find_queries(&directory, &options).unwrap(),
+            [directory.join("clips/a.wav"), directory.join("clips/b.wav")]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,40 +1,611 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use audio_fingerprint::{
+    AnalysisConfig, Backend, ColorMap, Distortion, FrequencyScale, MagnitudeScale, Normalization,
+    RawEncoding, ScanOptions, TimeRange,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::InfoLevel;
 
 #[derive(Debug, Parser)]
 #[command(name = "audio_fingerprint")]
 #[command(about = "An audio fingerprinting and song recognizing CLI", long_about = None)]
-
 pub(crate) struct Cli {
     #[command(subcommand)]
     pub command: Commands,
     #[command(flatten)]
     pub verbosity: clap_verbosity_flag::Verbosity<InfoLevel>,
+    /// How the fingerprint database is stored [default: memory]
+    #[arg(long, global = true, value_enum, env = "AUDIO_FINGERPRINT_BACKEND")]
+    pub backend: Option<StorageBackend>,
+    /// The fingerprint database, by default in the working directory
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_DB")]
+    pub db: Option<PathBuf>,
+    /// TOML file with the database and analysis parameters, overridden by options and their
+    /// environment variables
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Queries shorter than this many seconds are not recognized [default: 1]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_MIN_QUERY_SECONDS")]
+    pub min_query_seconds: Option<f32>,
+    #[command(flatten)]
+    pub analysis: AnalysisArgs,
+    #[command(flatten)]
+    pub raw: RawArgs,
+    /// How results are printed
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Format {
+    /// Lines meant to be read by people
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// One row per result, with a header
+    Csv,
+}
+
+// Overrides of the analysis parameters in the configuration file.
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Analysis parameters")]
+pub(crate) struct AnalysisArgs {
+    /// Remove the DC offset of audio before analyzing it
+    #[arg(
+        long,
+        global = true,
+        env = "AUDIO_FINGERPRINT_DC_BLOCK",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub dc_block: Option<bool>,
+    /// Cut frequencies below this, in Hz
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_LOW_CUT")]
+    pub low_cut: Option<f32>,
+    /// Cut frequencies above this, in Hz
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_HIGH_CUT")]
+    pub high_cut: Option<f32>,
+    /// Coefficient of a pre-emphasis filter lifting high frequencies, such as 0.97
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_PRE_EMPHASIS")]
+    pub pre_emphasis: Option<f32>,
+    /// Trim leading and trailing audio this many dB below the loudest part, such as -50
+    #[arg(
+        long,
+        global = true,
+        env = "AUDIO_FINGERPRINT_TRIM_SILENCE",
+        allow_negative_numbers = true
+    )]
+    pub trim_silence: Option<f32>,
+    /// Scale the level of audio before analyzing it [default: none]
+    #[arg(long, global = true, value_enum, env = "AUDIO_FINGERPRINT_NORMALIZE")]
+    pub normalize: Option<NormalizeMode>,
+    /// Level audio is normalized to, in dBFS [default: -1 for peak, -20 for rms]
+    #[arg(
+        long,
+        global = true,
+        env = "AUDIO_FINGERPRINT_TARGET_DB",
+        allow_negative_numbers = true
+    )]
+    pub target_db: Option<f32>,
+    /// Samples per FFT window [default: 1024]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_WINDOW_SIZE")]
+    pub window_size: Option<usize>,
+    /// Samples between the starts of consecutive FFT windows [default: 512]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_STRIDE")]
+    pub stride: Option<usize>,
+    /// Sample rate audio is assumed to have, in Hz [default: 48000]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_SAMPLE_RATE")]
+    pub sample_rate: Option<f32>,
+    /// How the frequency bins of the spectrogram are spaced [default: linear]
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "AUDIO_FINGERPRINT_FREQUENCY_SCALE"
+    )]
+    pub frequency_scale: Option<FrequencyMode>,
+    /// Number of mel or bark bands [default: 64]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_BANDS")]
+    pub bands: Option<usize>,
+    /// Lower edge of the lowest band, in Hz [default: 0 for mel and bark, 32.7 for cqt]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_MIN_FREQUENCY")]
+    pub min_frequency: Option<f32>,
+    /// Bands in every octave of the constant-Q transform [default: 12]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_BINS_PER_OCTAVE")]
+    pub bins_per_octave: Option<usize>,
+    /// How the magnitudes of the spectrogram are scaled [default: linear]
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "AUDIO_FINGERPRINT_MAGNITUDE_SCALE"
+    )]
+    pub magnitude_scale: Option<MagnitudeMode>,
+    /// Strongest peaks kept in every FFT window [default: 5]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_PEAKS_PER_WINDOW")]
+    pub peaks_per_window: Option<usize>,
+    /// Shortest time between the peaks of a fingerprint, in ms [default: 50]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_MIN_TIME_DELTA")]
+    pub min_time_delta: Option<u32>,
+    /// Longest time between the peaks of a fingerprint, in ms [default: 2000]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_MAX_TIME_DELTA")]
+    pub max_time_delta: Option<u32>,
+    /// Peaks every peak is paired with into fingerprints [default: 5]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_TARGET_PEAKS")]
+    pub target_peaks: Option<usize>,
+}
+
+impl AnalysisArgs {
+    pub fn apply(&self, config: &mut AnalysisConfig) {
+        let preprocess = &mut config.preprocess;
+        if let Some(dc_block) = self.dc_block {
+            preprocess.dc_block = dc_block;
+        }
+        if let Some(low_cut) = self.low_cut {
+            preprocess.low_cut_hz = Some(low_cut);
+        }
+        if let Some(high_cut) = self.high_cut {
+            preprocess.high_cut_hz = Some(high_cut);
+        }
+        if let Some(pre_emphasis) = self.pre_emphasis {
+            preprocess.pre_emphasis = Some(pre_emphasis);
+        }
+        if let Some(trim_silence) = self.trim_silence {
+            preprocess.trim_silence_db = Some(trim_silence);
+        }
+        if let Some(normalize) = self.normalize {
+            preprocess.normalize = normalize.into();
+        }
+        if let Some(target_db) = self.target_db {
+            preprocess.target_db = Some(target_db);
+        }
+        if let Some(window_size) = self.window_size {
+            config.spectrogram.window_size = window_size;
+        }
+        if let Some(stride) = self.stride {
+            config.spectrogram.stride = stride;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            config.spectrogram.sample_rate = sample_rate;
+        }
+        if let Some(frequency_scale) = self.frequency_scale {
+            config.spectrogram.frequency_scale = frequency_scale.into();
+        }
+        if let Some(bands) = self.bands {
+            config.spectrogram.bands = bands;
+        }
+        if let Some(min_frequency) = self.min_frequency {
+            config.spectrogram.min_frequency_hz = Some(min_frequency);
+        }
+        if let Some(bins_per_octave) = self.bins_per_octave {
+            config.spectrogram.bins_per_octave = bins_per_octave;
+        }
+        if let Some(magnitude_scale) = self.magnitude_scale {
+            config.spectrogram.magnitude_scale = magnitude_scale.into();
+        }
+        if let Some(peaks_per_window) = self.peaks_per_window {
+            config.peaks.peaks_per_window = peaks_per_window;
+        }
+        if let Some(min_time_delta) = self.min_time_delta {
+            config.fingerprint.min_time_delta_ms = min_time_delta;
+        }
+        if let Some(max_time_delta) = self.max_time_delta {
+            config.fingerprint.max_time_delta_ms = max_time_delta;
+        }
+        if let Some(target_peaks) = self.target_peaks {
+            config.fingerprint.num_target_peaks = target_peaks;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum NormalizeMode {
+    None,
+    /// Scale the loudest sample to the target level
+    Peak,
+    /// Scale the RMS level to the target level
+    Rms,
+}
+
+impl From<NormalizeMode> for Normalization {
+    fn from(mode: NormalizeMode) -> Self {
+        match mode {
+            NormalizeMode::None => Normalization::None,
+            NormalizeMode::Peak => Normalization::Peak,
+            NormalizeMode::Rms => Normalization::Rms,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum FrequencyMode {
+    /// The FFT bins, evenly spaced in Hz
+    Linear,
+    /// Bands evenly spaced on the mel scale of pitch
+    Mel,
+    /// Bands evenly spaced on the bark scale of critical bands
+    Bark,
+    /// A constant-Q transform, with bands evenly spaced in octaves
+    Cqt,
+}
+
+impl From<FrequencyMode> for FrequencyScale {
+    fn from(mode: FrequencyMode) -> Self {
+        match mode {
+            FrequencyMode::Linear => FrequencyScale::Linear,
+            FrequencyMode::Mel => FrequencyScale::Mel,
+            FrequencyMode::Bark => FrequencyScale::Bark,
+            FrequencyMode::Cqt => FrequencyScale::Cqt,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum MagnitudeMode {
+    Linear,
+    /// Decibels, floored at -100 dB
+    Db,
+    /// ln(1 + magnitude)
+    Log,
+}
+
+impl From<MagnitudeMode> for MagnitudeScale {
+    fn from(mode: MagnitudeMode) -> Self {
+        match mode {
+            MagnitudeMode::Linear => MagnitudeScale::Linear,
+            MagnitudeMode::Db => MagnitudeScale::Db,
+            MagnitudeMode::Log => MagnitudeScale::Log,
+        }
+    }
+}
+
+// Headerless PCM input, overriding the `[raw]` table of the configuration file.
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Raw input")]
+pub(crate) struct RawArgs {
+    /// Read songs and queries as headerless PCM in this encoding, instead of audio files
+    ///
+    /// This is the format of the input, named `--raw` as `--format` picks the format of the
+    /// output. `--input-format` is accepted as well.
+    #[arg(
+        long,
+        alias = "input-format",
+        global = true,
+        value_enum,
+        env = "AUDIO_FINGERPRINT_RAW"
+    )]
+    pub raw: Option<RawSampleEncoding>,
+    /// Sample rate of raw input, in Hz
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_RAW_RATE")]
+    pub rate: Option<u32>,
+    /// Interleaved channels of raw input [default: 1]
+    #[arg(long, global = true, env = "AUDIO_FINGERPRINT_RAW_CHANNELS")]
+    pub channels: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum RawSampleEncoding {
+    /// 16 bit signed little-endian integers
+    S16le,
+    /// 32 bit little-endian floats
+    F32le,
+    /// 8 bit G.711 µ-law
+    Mulaw,
+    /// 8 bit G.711 A-law
+    Alaw,
+}
+
+impl From<RawSampleEncoding> for RawEncoding {
+    fn from(encoding: RawSampleEncoding) -> Self {
+        match encoding {
+            RawSampleEncoding::S16le => RawEncoding::S16le,
+            RawSampleEncoding::F32le => RawEncoding::F32le,
+            RawSampleEncoding::Mulaw => RawEncoding::Mulaw,
+            RawSampleEncoding::Alaw => RawEncoding::Alaw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum StorageBackend {
+    Memory,
+    Mmap,
+    Sqlite,
+    Sharded,
+}
+
+impl From<StorageBackend> for Backend {
+    fn from(backend: StorageBackend) -> Self {
+        match backend {
+            StorageBackend::Memory => Backend::Memory,
+            StorageBackend::Mmap => Backend::Mmap,
+            StorageBackend::Sqlite => Backend::Sqlite,
+            StorageBackend::Sharded => Backend::Sharded,
+        }
+    }
 }
 
 #[derive(clap::Args, Debug)]
 pub(crate) struct AnalyzeArgs {
     #[arg(long, short = 'p')]
     pub path_to_song: String,
+    /// CSV, JSON or JSONL file with metadata overriding that read from the song
+    #[arg(long, short = 'm')]
+    pub metadata: Option<PathBuf>,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+// The span of a file to decode, rather than all of it.
+#[derive(clap::Args, Debug)]
+pub(crate) struct RangeArgs {
+    /// Seconds into the file to start at
+    #[arg(long, default_value_t = 0.0)]
+    pub start: f32,
+    /// Seconds of audio to use from the start, by default up to the end of the file
+    #[arg(long)]
+    pub duration: Option<f32>,
+}
+
+impl RangeArgs {
+    pub fn range(&self) -> TimeRange {
+        TimeRange {
+            start: self.start,
+            duration: self.duration,
+        }
+    }
 }
 
 #[derive(clap::Args, Debug)]
 pub(crate) struct AnalyzeDirectoryArgs {
     #[arg(long, short = 'p')]
     pub path_to_directory: PathBuf,
+    /// CSV, JSON or JSONL file with metadata overriding that read from the songs
+    #[arg(long, short = 'm')]
+    pub metadata: Option<PathBuf>,
+    /// Only analyze files whose path within the directory matches one of these globs
+    #[arg(long, default_values_t = ScanOptions::default().include)]
+    pub include: Vec<String>,
+    /// Skip files whose path within the directory matches one of these globs
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// Follow symlinks to files and directories instead of skipping them
+    #[arg(long)]
+    pub follow_symlinks: bool,
+    /// Number of files to fingerprint in parallel, by default one per CPU
+    #[arg(long, short = 'j')]
+    pub jobs: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct IngestArgs {
+    /// CSV or JSONL file with the path and metadata of one song per row
+    #[arg(long, short = 'm')]
+    pub manifest: PathBuf,
+    /// Write a JSON report of every ingested and failed row to this file
+    #[arg(long, short = 'r')]
+    pub report: Option<PathBuf>,
+    /// Ignore the checkpoint of a previous run and ingest every row again
+    #[arg(long)]
+    pub restart: bool,
 }
 
 #[derive(clap::Args, Debug)]
 pub(crate) struct RecognizeArgs {
     #[arg(long, short = 'p')]
     pub path_to_song: String,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct InspectArgs {
+    #[arg(long, short = 'p')]
+    pub path_to_song: String,
+    /// PNG file to render the spectrogram into
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+    /// Recognize the song, and overlay the peaks of the part of the matched song it lines up with
+    #[arg(long)]
+    pub compare: bool,
+    /// Colours of the spectrogram, from quiet to loud
+    #[arg(long, value_enum, default_value_t = Palette::Viridis)]
+    pub color_map: Palette,
+    /// Lowest frequency shown, in Hz
+    #[arg(long)]
+    pub min_freq: Option<f32>,
+    /// Highest frequency shown, in Hz
+    #[arg(long)]
+    pub max_freq: Option<f32>,
+    /// Magnitudes this many dB below the loudest are drawn in the quietest colour
+    #[arg(long, default_value_t = 80.0)]
+    pub dynamic_range: f32,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ExportArgs {
+    #[command(subcommand)]
+    pub command: ExportCommands,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ExportFileArgs {
+    #[arg(long, short = 'p')]
+    pub path_to_song: String,
+    /// File to write, in the format named by its extension
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ExportCommands {
+    /// The magnitude of every window and frequency bin, as .npy or .csv
+    Spectrogram(ExportFileArgs),
+    /// The time, frequency and magnitude of every peak, as .csv or .json
+    Peaks(ExportFileArgs),
+    /// Every fingerprint with its offset and decoded fields, as .csv or .json
+    Fingerprints(ExportFileArgs),
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum Palette {
+    /// Dark blue through green to yellow
+    Viridis,
+    /// Black through purple and orange to pale yellow
+    Magma,
+    /// Black to white
+    Gray,
+}
+
+impl From<Palette> for ColorMap {
+    fn from(palette: Palette) -> Self {
+        match palette {
+            Palette::Viridis => ColorMap::Viridis,
+            Palette::Magma => ColorMap::Magma,
+            Palette::Gray => ColorMap::Gray,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct RecognizeBatchArgs {
+    /// Directory of queries, or a file listing one query per line
+    #[arg(long, short = 'p')]
+    pub path: PathBuf,
+    /// Only recognize files in the directory whose path within it matches one of these globs
+    #[arg(long, default_values_t = ScanOptions::default().include)]
+    pub include: Vec<String>,
+    /// Skip files in the directory whose path within it matches one of these globs
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// Follow symlinks in the directory instead of skipping them
+    #[arg(long)]
+    pub follow_symlinks: bool,
+    /// Number of queries to recognize in parallel, by default one per CPU
+    #[arg(long, short = 'j')]
+    pub jobs: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct EvaluateArgs {
+    /// CSV file with the `query` path and `expected` song id, or `none`, of every query
+    #[arg(long, short = 'l')]
+    pub labels: PathBuf,
+    /// Score thresholds to report precision and recall at, by default a range of them
+    #[arg(long = "threshold", short = 't')]
+    pub thresholds: Vec<f32>,
+    /// Number of queries to recognize in parallel, by default one per CPU
+    #[arg(long, short = 'j')]
+    pub jobs: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct DegradeArgs {
+    /// Directory to write the queries and their `labels.csv` into
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+    /// Songs to make queries of, by default every song in the database
+    #[arg(long = "song-id", short = 's')]
+    pub song_ids: Vec<u32>,
+    /// Distortions to apply to every clip, in order, such as `white-noise=10` (SNR in dB),
+    /// `pink-noise=10`, `gain=-6` (dB), `clip=0.5`, `low-pass=4000` (Hz), `high-pass=300`,
+    /// `reverb=0.8,0.3` (decay in seconds, wet share), `resample=8000`, `speed=1.05` or
+    /// `band-limit=11000` (Hz)
+    #[arg(long = "distort", short = 'd')]
+    pub distortions: Vec<Distortion>,
+    /// Number of clips to cut from every song
+    #[arg(long, short = 'n', default_value_t = 1)]
+    pub clips_per_song: usize,
+    /// Length of the clips in seconds
+    #[arg(long, default_value_t = 10.0)]
+    pub clip_seconds: f32,
+    /// Seed of the clip offsets and noise, the same seed making the same queries
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct MergeArgs {
+    /// Databases to merge into the database of the memory backend
+    #[arg(long = "input", short = 'i', required = true)]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ExtractArgs {
+    /// Songs to export from the database of the memory backend
+    #[arg(long = "song-id", short = 's', required = true)]
+    pub song_ids: Vec<u32>,
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+    /// Also remove the exported songs from the source database
+    #[arg(long)]
+    pub remove: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ShardsArgs {
+    #[command(subcommand)]
+    pub command: ShardCommands,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct BuildShardsArgs {
+    /// The backend holding the database to partition
+    #[arg(long, value_enum, default_value_t = StorageBackend::Memory)]
+    pub from: StorageBackend,
+    #[arg(long, short = 'n', default_value_t = 16)]
+    pub shard_count: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct RebalanceShardsArgs {
+    /// Defaults to the current number of shards
+    #[arg(long, short = 'n')]
+    pub shard_count: Option<usize>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ShardCommands {
+    /// Partition an existing database into shards
+    Build(BuildShardsArgs),
+    /// Spread the postings evenly across the shards
+    Rebalance(RebalanceShardsArgs),
+    /// Check the shards for consistency
+    Verify,
 }
 
 #[derive(Debug, Subcommand)]
 pub(crate) enum Commands {
     Analyze(AnalyzeArgs),
     AnalyzeDirectory(AnalyzeDirectoryArgs),
+    /// Analyze every song listed in a catalogue manifest
+    Ingest(IngestArgs),
     Recognize(RecognizeArgs),
+    /// Render the spectrogram and peaks of a song as a PNG image
+    Inspect(InspectArgs),
+    /// Write what the analysis of a song computes, for use in other tools
+    Export(ExportArgs),
+    /// Recognize many queries with the database loaded once
+    RecognizeBatch(RecognizeBatchArgs),
+    /// Measure how well a labelled set of queries is recognized
+    Evaluate(EvaluateArgs),
+    /// Make distorted queries from clips of the songs in the database
+    Degrade(DegradeArgs),
+    /// Merge the journal of newly analyzed songs into the main index
+    Compact,
+    /// Manage the shards of the sharded backend
+    Shards(ShardsArgs),
+    /// Merge other databases into this one
+    Merge(MergeArgs),
+    /// Export a subset of the songs into a new database
+    Extract(ExtractArgs),
 }
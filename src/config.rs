@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::RawFormat,
+    error::{Error, Result},
+    fft::{FrequencyScale, SpectrogramConfig},
+    fingerprint::FingerprintConfig,
+    fingerprinter::Fingerprinter,
+    peaks::PeakConfig,
+    preprocess::PreprocessConfig,
+    storage::Backend,
+};
+
+// The largest time delta a fingerprint can encode, in 12 bits of 5 ms.
+const MAX_ENCODED_TIME_DELTA_MS: u32 = 4095 * 5;
+
+// Every parameter of turning audio into fingerprints. Songs can only be recognized against a
+// database analyzed with the same parameters.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisConfig {
+    pub preprocess: PreprocessConfig,
+    pub spectrogram: SpectrogramConfig,
+    pub peaks: PeakConfig,
+    pub fingerprint: FingerprintConfig,
+}
+
+impl AnalysisConfig {
+    // Fails with `Error::InvalidInput` for parameters which cannot be analyzed with.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::InvalidInput(String::from(message)));
+        let spectrogram = &self.spectrogram;
+        let fingerprint = &self.fingerprint;
+
+        if spectrogram.window_size < 8 {
+            return invalid("the window size must be at least 8 samples");
+        }
+        if spectrogram.stride == 0 {
+            return invalid("the stride must be at least 1 sample");
+        }
+        if !(spectrogram.sample_rate.is_finite() && spectrogram.sample_rate > 0.0) {
+            return invalid("the sample rate must be positive");
+        }
+        if spectrogram
+            .min_frequency_hz
+            .is_some_and(|hz| !(hz.is_finite() && hz >= 0.0 && hz < spectrogram.sample_rate / 2.0))
+        {
+            return invalid("the minimum frequency must be between 0 Hz and half the sample rate");
+        }
+        if spectrogram.frequency_scale == FrequencyScale::Cqt
+            && (spectrogram.bins_per_octave == 0 || spectrogram.min_frequency_hz == Some(0.0))
+        {
+            return invalid("a constant-Q transform needs a minimum frequency and bins per octave");
+        }
+        // Peaks are the bins louder than both their neighbours.
+        if spectrogram.num_bins() < 3 {
+            return invalid("the spectrogram must have at least 3 frequency bins");
+        }
+        if self.peaks.peaks_per_window == 0 {
+            return invalid("at least 1 peak must be kept per window");
+        }
+        if fingerprint.num_target_peaks == 0 {
+            return invalid("every anchor needs at least 1 target peak");
+        }
+        if fingerprint.min_time_delta_ms > fingerprint.max_time_delta_ms {
+            return invalid("the minimum time delta is larger than the maximum");
+        }
+        if fingerprint.max_time_delta_ms > MAX_ENCODED_TIME_DELTA_MS {
+            return Err(Error::InvalidInput(format!(
+                "the maximum time delta can be at most {MAX_ENCODED_TIME_DELTA_MS} ms"
+            )));
+        }
+
+        self.preprocess.validate(spectrogram.sample_rate)
+    }
+}
+
+// Where the database is stored, and how songs are analyzed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub backend: Backend,
+    // Defaults to the default path of the backend, in the working directory.
+    pub db: Option<PathBuf>,
+    pub analysis: AnalysisConfig,
+    // Songs and queries are headerless PCM in this format, rather than audio files.
+    pub raw: Option<RawFormat>,
+    // Queries shorter than this are not recognized. Defaults to `DEFAULT_MIN_QUERY_SECONDS`.
+    pub min_query_seconds: Option<f32>,
+}
+
+// The layout of a TOML configuration file, where every setting is optional:
+//
+//     backend = "sqlite"
+//     db = "catalogue.sqlite"
+//     min_query_seconds = 2.0
+//
+//     [preprocess]
+//     dc_block = true
+//
+//     [spectrogram]
+//     window_size = 2048
+//     frequency_scale = "mel"
+//
+//     [fingerprint]
+//     num_target_peaks = 10
+//
+//     [raw]
+//     encoding = "mulaw"
+//     sample_rate = 8000
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    backend: Option<Backend>,
+    db: Option<PathBuf>,
+    min_query_seconds: Option<f32>,
+    preprocess: PreprocessConfig,
+    spectrogram: SpectrogramConfig,
+    peaks: PeakConfig,
+    fingerprint: FingerprintConfig,
+    raw: Option<RawFormat>,
+}
+
+impl Config {
+    // Reads a TOML configuration file. A relative `db` is taken relative to the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        log::debug!("Loading configuration from {:?}", path);
+        let file: ConfigFile = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| Error::InvalidInput(format!("{path:?}: {err}")))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        Ok(Self {
+            backend: file.backend.unwrap_or_default(),
+            db: file.db.map(|db| directory.join(db)),
+            analysis: AnalysisConfig {
+                preprocess: file.preprocess,
+                spectrogram: file.spectrogram,
+                peaks: file.peaks,
+                fingerprint: file.fingerprint,
+            },
+            raw: file.raw,
+            min_query_seconds: file.min_query_seconds,
+        })
+    }
+
+    // Fingerprints with the analysis parameters, loading audio in the raw format if one is set.
+    pub fn fingerprinter(&self) -> Fingerprinter {
+        let mut fingerprinter = Fingerprinter::new(self.analysis);
+        if let Some(format) = self.raw {
+            fingerprinter = fingerprinter.with_raw(format);
+        }
+        if let Some(seconds) = self.min_query_seconds {
+            fingerprinter = fingerprinter.with_min_query_seconds(seconds);
+        }
+        fingerprinter
+    }
+
+    // Fails with `Error::InvalidInput` for settings which cannot be worked with.
+    pub fn validate(&self) -> Result<()> {
+        self.analysis.validate()?;
+        if let Some(raw) = &self.raw {
+            raw.validate()?;
+        }
+        if self
+            .min_query_seconds
+            .is_some_and(|seconds| !(seconds.is_finite() && seconds >= 0.0))
+        {
+            return Err(Error::InvalidInput(String::from(
+                "the minimum query length must be 0 or more seconds",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.path_for(self.backend)
+    }
+
+    // The database of `backend`. That is `db` if it is the configured backend, as commands such as
+    // `merge` always work on the memory backend, whichever backend is configured.
+    pub fn path_for(&self, backend: Backend) -> PathBuf {
+        match &self.db {
+            Some(db) if backend == self.backend => db.clone(),
+            _ => PathBuf::from(backend.default_path()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        Backend, Error, FrequencyScale, MagnitudeScale, Normalization, RawEncoding, RawFormat,
+        config::Config,
+    };
+
+    #[test]
+    fn load_config_file() {
+        let directory = crate::temp_path("config");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.toml");
+
+        fs::write(
+            &path,
+            "backend = \"sqlite\"\ndb = \"catalogue.sqlite\"\n\n[spectrogram]\nwindow_size = 2048\n\n[fingerprint]\nnum_target_peaks = 10\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.backend, Backend::Sqlite);
+        assert_eq!(config.db_path(), directory.join("catalogue.sqlite"));
+        assert_eq!(
+            config.path_for(Backend::Memory),
+            PathBuf::from("audio_fingerprint.db")
+        );
+        assert_eq!(config.analysis.spectrogram.window_size, 2048);
+        assert_eq!(config.analysis.spectrogram.stride, 512);
+        assert_eq!(config.analysis.fingerprint.num_target_peaks, 10);
+        assert_eq!(config.analysis.peaks.peaks_per_window, 5);
+        config.analysis.validate().unwrap();
+
+        assert_eq!(config.raw, None);
+        assert!(!config.analysis.preprocess.is_enabled());
+
+        fs::write(
+            &path,
+            "[preprocess]\ndc_block = true\ntrim_silence_db = -50.0\nnormalize = \"rms\"\n",
+        )
+        .unwrap();
+        let preprocess = Config::load(&path).unwrap().analysis.preprocess;
+        assert!(preprocess.dc_block);
+        assert_eq!(preprocess.trim_silence_db, Some(-50.0));
+        assert_eq!(preprocess.normalize, Normalization::Rms);
+
+        fs::write(
+            &path,
+            "min_query_seconds = 0.5\n\n[raw]\nencoding = \"mulaw\"\nsample_rate = 8000\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.min_query_seconds, Some(0.5));
+        assert_eq!(
+            config.raw,
+            Some(RawFormat {
+                encoding: RawEncoding::Mulaw,
+                sample_rate: 8000,
+                channels: 1,
+            })
+        );
+
+        fs::write(
+            &path,
+            "[spectrogram]\nfrequency_scale = \"cqt\"\nmagnitude_scale = \"db\"\n",
+        )
+        .unwrap();
+        let mut analysis = Config::load(&path).unwrap().analysis;
+        analysis.validate().unwrap();
+        assert_eq!(analysis.spectrogram.frequency_scale, FrequencyScale::Cqt);
+        assert_eq!(analysis.spectrogram.magnitude_scale, MagnitudeScale::Db);
+        // The constant-Q transform cannot start at 0 Hz.
+        analysis.spectrogram.min_frequency_hz = Some(0.0);
+        assert!(analysis.validate().is_err());
+
+        fs::write(&path, "[spectrogram]\nwindow_sise = 2048\n").unwrap();
+        assert!(matches!(Config::load(&path), Err(Error::InvalidInput(_))));
+
+        fs::write(&path, "[spectrogram]\nstride = 0\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        assert!(matches!(
+            config.analysis.validate(),
+            Err(Error::InvalidInput(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
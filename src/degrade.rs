@@ -0,0 +1,441 @@
+use rustfft::{FftPlanner, num_complex::Complex};
+use serde::Serialize;
+use std::{
+    f32::consts::PI,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    error::{AudioError, Error, Result},
+    fingerprinter::Fingerprinter,
+    metadata::SongMetaData,
+    preprocess::{Biquad, resample, stretch},
+};
+
+// A distortion applied to a clip to make a query, standing in for what happens to a song between
+// the speaker and a microphone, or on its way through a lossy codec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distortion {
+    // Additive noise at a signal to noise ratio, in dB. Pink noise has equal power per octave,
+    // like most background noise.
+    WhiteNoise { snr_db: f32 },
+    PinkNoise { snr_db: f32 },
+    // Amplification, or attenuation if negative, in dB.
+    Gain { db: f32 },
+    // Hard clipping of samples beyond an amplitude between 0 and 1.
+    Clip { threshold: f32 },
+    // Second order EQ filters, cutting frequencies above or below a cutoff, in Hz.
+    LowPass { cutoff_hz: f32 },
+    HighPass { cutoff_hz: f32 },
+    // A synthetic room: exponentially decaying noise which fades by 60 dB over the decay time, in
+    // seconds, mixed in with the share `wet`.
+    Reverb { decay_seconds: f32, wet: f32 },
+    // Down sampling to a lower sample rate and back, losing everything above its Nyquist
+    // frequency.
+    Resample { sample_rate: u32 },
+    // Playing faster, or slower if below 1, shifting the pitch along with the tempo.
+    Speed { factor: f32 },
+    // A steep cutoff like that of lossy codecs at low bitrates, in Hz.
+    BandLimit { cutoff_hz: f32 },
+}
+
+// Distortions are written as `name=value`, such as `white-noise=10` or `reverb=0.8,0.3`.
+impl FromStr for Distortion {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, values) = spec.split_once('=').unwrap_or((spec, ""));
+        let values: Vec<f32> = values
+            .split(',')
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|value| value.is_finite())
+                    .ok_or_else(|| format!("{value:?} is not a number in {spec:?}"))
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        let value = |index: usize| {
+            values
+                .get(index)
+                .copied()
+                .ok_or_else(|| format!("{spec:?} is missing a value, such as {name}=10"))
+        };
+        let positive = |index: usize| {
+            value(index).and_then(|value| {
+                if value > 0.0 {
+                    Ok(value)
+                } else {
+                    Err(format!("{spec:?} must be positive"))
+                }
+            })
+        };
+
+        let distortion = match name.trim() {
+            "white-noise" => Distortion::WhiteNoise { snr_db: value(0)? },
+            "pink-noise" => Distortion::PinkNoise { snr_db: value(0)? },
+            "gain" => Distortion::Gain { db: value(0)? },
+            "clip" => Distortion::Clip {
+                threshold: positive(0)?,
+            },
+            "low-pass" => Distortion::LowPass {
+                cutoff_hz: positive(0)?,
+            },
+            "high-pass" => Distortion::HighPass {
+                cutoff_hz: positive(0)?,
+            },
+            "reverb" => Distortion::Reverb {
+                decay_seconds: positive(0)?,
+                wet: values.get(1).copied().unwrap_or(0.3).clamp(0.0, 1.0),
+            },
+            "resample" => Distortion::Resample {
+                sample_rate: positive(0)? as u32,
+            },
+            "speed" => Distortion::Speed {
+                factor: positive(0)?,
+            },
+            "band-limit" => Distortion::BandLimit {
+                cutoff_hz: positive(0)?,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown distortion {name:?}, expected one of white-noise, pink-noise, gain, \
+                     clip, low-pass, high-pass, reverb, resample, speed or band-limit"
+                ));
+            }
+        };
+
+        Ok(distortion)
+    }
+}
+
+impl fmt::Display for Distortion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Distortion::WhiteNoise { snr_db } => write!(f, "white-noise={snr_db}"),
+            Distortion::PinkNoise { snr_db } => write!(f, "pink-noise={snr_db}"),
+            Distortion::Gain { db } => write!(f, "gain={db}"),
+            Distortion::Clip { threshold } => write!(f, "clip={threshold}"),
+            Distortion::LowPass { cutoff_hz } => write!(f, "low-pass={cutoff_hz}"),
+            Distortion::HighPass { cutoff_hz } => write!(f, "high-pass={cutoff_hz}"),
+            Distortion::Reverb { decay_seconds, wet } => write!(f, "reverb={decay_seconds},{wet}"),
+            Distortion::Resample { sample_rate } => write!(f, "resample={sample_rate}"),
+            Distortion::Speed { factor } => write!(f, "speed={factor}"),
+            Distortion::BandLimit { cutoff_hz } => write!(f, "band-limit={cutoff_hz}"),
+        }
+    }
+}
+
+impl Distortion {
+    // Applies the distortion to `samples` recorded at `sample_rate`. Noise is drawn from `rng`, so
+    // the same seed always gives the same result.
+    pub fn apply(&self, samples: &[f32], sample_rate: f32, rng: &mut fastrand::Rng) -> Vec<f32> {
+        match *self {
+            Distortion::WhiteNoise { snr_db } => {
+                let noise: Vec<f32> = (0..samples.len()).map(|_| gaussian(rng)).collect();
+                add_noise(samples, &noise, snr_db)
+            }
+            Distortion::PinkNoise { snr_db } => {
+                add_noise(samples, &pink_noise(samples.len(), rng), snr_db)
+            }
+            Distortion::Gain { db } => {
+                let gain = 10f32.powf(db / 20.0);
+                samples.iter().map(|sample| sample * gain).collect()
+            }
+            Distortion::Clip { threshold } => samples
+                .iter()
+                .map(|sample| sample.clamp(-threshold, threshold))
+                .collect(),
+            Distortion::LowPass { cutoff_hz } => {
+                Biquad::low_pass(cutoff_hz, sample_rate).filter(samples)
+            }
+            Distortion::HighPass { cutoff_hz } => {
+                Biquad::high_pass(cutoff_hz, sample_rate).filter(samples)
+            }
+            Distortion::Reverb { decay_seconds, wet } => {
+                let response = impulse_response(decay_seconds, sample_rate, rng);
+                let reverberated = convolve(samples, &response);
+                samples
+                    .iter()
+                    .zip(reverberated)
+                    .map(|(dry, wet_sample)| (1.0 - wet) * dry + wet * wet_sample)
+                    .collect()
+            }
+            Distortion::Resample {
+                sample_rate: target_rate,
+            } => {
+                let ratio = sample_rate / target_rate as f32;
+                if ratio <= 1.0 {
+                    return samples.to_vec();
+                }
+                let downsampled = resample(samples, sample_rate, target_rate as f32);
+                let mut resampled = stretch(&downsampled, 1.0 / ratio);
+                resampled.resize(samples.len(), 0.0);
+                resampled
+            }
+            Distortion::Speed { factor } => stretch(samples, factor),
+            Distortion::BandLimit { cutoff_hz } => {
+                // Four filters in a row roll off at 48 dB per octave.
+                let filter = Biquad::low_pass(cutoff_hz, sample_rate);
+                (0..4).fold(samples.to_vec(), |samples, _| filter.filter(&samples))
+            }
+        }
+    }
+}
+
+// A normally distributed sample with a standard deviation of 1, by the Box-Muller transform.
+fn gaussian(rng: &mut fastrand::Rng) -> f32 {
+    let u1 = rng.f32().max(f32::MIN_POSITIVE);
+    let u2 = rng.f32();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+// White noise shaped to fall by 3 dB per octave, using Paul Kellet's filter.
+fn pink_noise(len: usize, rng: &mut fastrand::Rng) -> Vec<f32> {
+    let mut b = [0f32; 7];
+    (0..len)
+        .map(|_| {
+            let white = gaussian(rng);
+            b[0] = 0.99886 * b[0] + white * 0.0555179;
+            b[1] = 0.99332 * b[1] + white * 0.0750759;
+            b[2] = 0.96900 * b[2] + white * 0.153852;
+            b[3] = 0.86650 * b[3] + white * 0.3104856;
+            b[4] = 0.55000 * b[4] + white * 0.5329522;
+            b[5] = -0.7616 * b[5] - white * 0.0168980;
+            let pink = b.iter().sum::<f32>() + white * 0.5362;
+            b[6] = white * 0.115926;
+            pink
+        })
+        .collect()
+}
+
+fn power(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32
+}
+
+// Mixes `noise` into `samples`, scaled so the ratio of their powers is `snr_db`.
+fn add_noise(samples: &[f32], noise: &[f32], snr_db: f32) -> Vec<f32> {
+    let noise_power = power(noise);
+    let scale = if noise_power > 0.0 {
+        (power(samples) / 10f32.powf(snr_db / 10.0) / noise_power).sqrt()
+    } else {
+        0.0
+    };
+    samples
+        .iter()
+        .zip(noise)
+        .map(|(sample, noise)| sample + scale * noise)
+        .collect()
+}
+
+// Decaying noise with the energy of a single impulse, so reverb keeps the level of the clip.
+fn impulse_response(decay_seconds: f32, sample_rate: f32, rng: &mut fastrand::Rng) -> Vec<f32> {
+    let len = ((decay_seconds * sample_rate) as usize).max(1);
+    let mut response: Vec<f32> = (0..len)
+        .map(|n| gaussian(rng) * 10f32.powf(-3.0 * n as f32 / len as f32))
+        .collect();
+    let energy = response
+        .iter()
+        .map(|sample| sample * sample)
+        .sum::<f32>()
+        .sqrt();
+    if energy > 0.0 {
+        response.iter_mut().for_each(|sample| *sample /= energy);
+    }
+    response
+}
+
+// The convolution of `samples` with `response`, cut to the length of `samples`. It is computed
+// with FFTs, as responses are tens of thousands of samples long.
+fn convolve(samples: &[f32], response: &[f32]) -> Vec<f32> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+    let len = (samples.len() + response.len() - 1).next_power_of_two();
+    let mut planner = FftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(len);
+    let inverse = planner.plan_fft_inverse(len);
+
+    let spectrum = |signal: &[f32]| {
+        let mut buffer: Vec<Complex<f32>> = signal
+            .iter()
+            .map(|&sample| Complex::new(sample, 0.0))
+            .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+            .take(len)
+            .collect();
+        forward.process(&mut buffer);
+        buffer
+    };
+    let mut product: Vec<Complex<f32>> = spectrum(samples)
+        .iter()
+        .zip(spectrum(response))
+        .map(|(a, b)| a * b)
+        .collect();
+    inverse.process(&mut product);
+
+    product
+        .iter()
+        .take(samples.len())
+        .map(|value| value.re / len as f32)
+        .collect()
+}
+
+// How to make queries out of the songs in the database.
+#[derive(Debug, Clone)]
+pub struct DegradeOptions {
+    // The seed of every random choice, so the same options always give the same queries.
+    pub seed: u64,
+    pub clips_per_song: usize,
+    pub clip_seconds: f32,
+    // Applied in order to every clip.
+    pub distortions: Vec<Distortion>,
+}
+
+impl Default for DegradeOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clips_per_song: 1,
+            clip_seconds: 10.0,
+            distortions: Vec::new(),
+        }
+    }
+}
+
+// A query made by `degrade_songs`, and what it was made of.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DegradedQuery {
+    // Relative to the output directory.
+    pub query: PathBuf,
+    pub expected: u32,
+    pub offset_seconds: f32,
+    pub distortions: String,
+}
+
+// Cuts clips from `songs` at random offsets and distorts them, writing them as WAV files into
+// `output` along with a `labels.csv` for `evaluate`. Songs shorter than a clip are used whole.
+pub fn degrade_songs(
+    fingerprinter: &Fingerprinter,
+    songs: &[SongMetaData],
+    options: &DegradeOptions,
+    output: &Path,
+) -> Result<Vec<DegradedQuery>> {
+    if options.clip_seconds.is_nan() || options.clip_seconds <= 0.0 {
+        return Err(Error::InvalidInput(format!(
+            "clips must be longer than 0 seconds, not {}",
+            options.clip_seconds
+        )));
+    }
+    fs::create_dir_all(output)?;
+    let mut rng = fastrand::Rng::with_seed(options.seed);
+    let distortions: Vec<String> = options.distortions.iter().map(|d| d.to_string()).collect();
+    let distortions = distortions.join(" ");
+
+    let mut queries = Vec::new();
+    for song in songs {
+        log::info!(
+            "Making {} queries of {}",
+            options.clips_per_song,
+            song.source_path
+        );
+        let samples = fingerprinter.load(&song.source_path)?;
+        let sample_rate = if song.sample_rate > 0 {
+            song.sample_rate
+        } else {
+            fingerprinter.config().spectrogram.sample_rate as u32
+        };
+        let clip_len = ((options.clip_seconds * sample_rate as f32) as usize).min(samples.len());
+
+        for clip in 0..options.clips_per_song {
+            let offset = rng.usize(0..=samples.len() - clip_len);
+            let mut samples = samples[offset..offset + clip_len].to_vec();
+            for distortion in options.distortions.iter() {
+                samples = distortion.apply(&samples, sample_rate as f32, &mut rng);
+            }
+
+            let query = PathBuf::from(format!("song{}_clip{}.wav", song.song_id, clip));
+            write_clip(&output.join(&query), &samples, sample_rate)?;
+            queries.push(DegradedQuery {
+                query,
+                expected: song.song_id,
+                offset_seconds: offset as f32 / sample_rate as f32,
+                distortions: distortions.clone(),
+            });
+        }
+    }
+
+    let mut labels = csv::Writer::from_path(output.join("labels.csv"))?;
+    for query in queries.iter() {
+        labels.serialize(query)?;
+    }
+    labels.flush()?;
+
+    Ok(queries)
+}
+
+// Clips are written as floats, as distortions may take samples beyond the range of integers.
+fn write_clip(path: &Path, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let error = |err: hound::Error| Error::from(AudioError::from(err));
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).map_err(error)?;
+    for &sample in samples {
+        writer.write_sample(sample).map_err(error)?;
+    }
+    writer.finalize().map_err(error)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::degrade::{Distortion, power};
+
+    #[test]
+    fn distortions() {
+        let sample_rate = 8000.0;
+        let tone: Vec<f32> = (0..8000)
+            .map(|n| (2.0 * std::f32::consts::PI * 440.0 * n as f32 / sample_rate).sin() * 0.5)
+            .collect();
+
+        // The same seed gives the same noise, at the requested SNR.
+        let noise: Distortion = "white-noise=10".parse().unwrap();
+        let noisy = noise.apply(&tone, sample_rate, &mut fastrand::Rng::with_seed(7));
+        assert_eq!(
+            noisy,
+            noise.apply(&tone, sample_rate, &mut fastrand::Rng::with_seed(7))
+        );
+        let residual: Vec<f32> = noisy.iter().zip(&tone).map(|(a, b)| a - b).collect();
+        let snr = 10.0 * (power(&tone) / power(&residual)).log10();
+        assert!((snr - 10.0).abs() < 0.01, "SNR {snr}");
+
+        // A low pass filter far below the tone removes most of it.
+        let low_pass: Distortion = "low-pass=50".parse().unwrap();
+        let filtered = low_pass.apply(&tone, sample_rate, &mut fastrand::Rng::with_seed(7));
+        assert!(power(&filtered) < power(&tone) * 0.01);
+
+        let speed: Distortion = "speed=2".parse().unwrap();
+        assert_eq!(
+            speed
+                .apply(&tone, sample_rate, &mut fastrand::Rng::new())
+                .len(),
+            4000
+        );
+
+        let reverb: Distortion = "reverb=0.5,0.4".parse().unwrap();
+        assert_eq!(reverb.to_string(), "reverb=0.5,0.4");
+        assert!("chorus=3".parse::<Distortion>().is_err());
+        assert!("speed=0".parse::<Distortion>().is_err());
+    }
+}
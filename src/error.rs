@@ -1,9 +1,243 @@
-use hound;
+use std::{fmt, io};
+
+use crate::{audio::AudioFormat, fingerprint::AnalysisParameters};
+
+// Everything that can go wrong in the library, grouped by what the caller can do about it.
+#[derive(Debug)]
+pub enum Error {
+    // Reading or writing a file failed.
+    Io(io::Error),
+    // The audio could not be decoded.
+    Decoding(String),
+    // The audio is valid, but in a format we cannot handle.
+    UnsupportedFormat(String),
+    // There is not enough audio to fingerprint, or to recognize a query by.
+    InsufficientAudio(InsufficientAudio),
+    // A database, journal or shard file is not what it should be.
+    Corruption(String),
+    // Fingerprints generated with different parameters were combined. Boxed, as the parameters
+    // are large next to every other error.
+    ParameterMismatch {
+        expected: Box<AnalysisParameters>,
+        found: Box<AnalysisParameters>,
+    },
+    // The SQLite backend failed.
+    Sqlite(rusqlite::Error),
+    // A manifest, pattern or argument given by the user is invalid.
+    InvalidInput(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Why there is not enough audio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsufficientAudio {
+    // Not a single sample, such as from an empty file or a range past the end of one.
+    Empty,
+    // Fewer samples than a query must have to be recognized.
+    TooShort { samples: usize, required: usize },
+    // No peaks to fingerprint, as in digital silence.
+    Silent,
+}
+
+impl fmt::Display for InsufficientAudio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsufficientAudio::Empty => write!(f, "there are no samples"),
+            InsufficientAudio::TooShort { samples, required } => {
+                write!(f, "{samples} samples, at least {required} are needed")
+            }
+            InsufficientAudio::Silent => write!(f, "it is silent, without any peaks"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::Decoding(message) => write!(f, "Unable to decode audio: {message}"),
+            Error::UnsupportedFormat(format) => write!(f, "Unsupported audio format: {format}"),
+            Error::InsufficientAudio(reason) => write!(f, "Insufficient audio: {reason}"),
+            Error::Corruption(message) => write!(f, "Database is corrupt: {message}"),
+            Error::ParameterMismatch { expected, found } => write!(
+                f,
+                "Analysis parameters {found:?} do not match those of the database {expected:?}"
+            ),
+            Error::Sqlite(err) => write!(f, "SQLite error: {err}"),
+            Error::InvalidInput(message) => write!(f, "Invalid input: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Sqlite(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<AudioError> for Error {
+    fn from(err: AudioError) -> Self {
+        match err {
+            AudioError::Io(err) | AudioError::Hound(hound::Error::IoError(err)) => Error::Io(err),
+            AudioError::Hound(hound::Error::Unsupported) => {
+                Error::UnsupportedFormat(String::from("unsupported WAV feature"))
+            }
+            AudioError::Hound(err) => Error::Decoding(err.to_string()),
+            AudioError::Flac(claxon::Error::IoError(err)) => Error::Io(err),
+            AudioError::Flac(claxon::Error::Unsupported(feature)) => {
+                Error::UnsupportedFormat(format!("unsupported FLAC feature: {feature}"))
+            }
+            AudioError::Flac(err) => Error::Decoding(err.to_string()),
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            AudioError::Lossy(symphonia::core::errors::Error::IoError(err)) => Error::Io(err),
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            AudioError::Lossy(symphonia::core::errors::Error::Unsupported(feature)) => {
+                Error::UnsupportedFormat(format!("unsupported feature: {feature}"))
+            }
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            AudioError::Lossy(err) => Error::Decoding(err.to_string()),
+            AudioError::InvalidWav(_) => Error::Decoding(err.to_string()),
+            AudioError::UnsupportedWav(_)
+            | AudioError::FeatureDisabled(_)
+            | AudioError::NoDecoder(_)
+            | AudioError::UnknownFormat => Error::UnsupportedFormat(err.to_string()),
+        }
+    }
+}
+
+// Files written by the library are only ever decoded again by it, so failing to decode one means
+// it was damaged. That includes ending early, as a truncated file does.
+impl From<bincode::error::DecodeError> for Error {
+    fn from(err: bincode::error::DecodeError) -> Self {
+        match err {
+            bincode::error::DecodeError::Io { inner, .. }
+                if inner.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Error::Corruption(String::from("the file ends early"))
+            }
+            bincode::error::DecodeError::Io { inner, .. } => Error::Io(inner),
+            err => Error::Corruption(err.to_string()),
+        }
+    }
+}
+
+impl From<bincode::error::EncodeError> for Error {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        match err {
+            bincode::error::EncodeError::Io { inner, .. } => Error::Io(inner),
+            err => Error::Io(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::InvalidInput(err.to_string())
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::InvalidInput(err.to_string())
+    }
+}
+
+impl From<globset::Error> for Error {
+    fn from(err: globset::Error) -> Self {
+        Error::InvalidInput(err.to_string())
+    }
+}
+
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum AudioError {
+    Io(io::Error),
     Hound(hound::Error),
-    UnsupportedFormat(hound::SampleFormat, u16),
+    Flac(claxon::Error),
+    #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+    Lossy(symphonia::core::errors::Error),
+    // A WAV file of a format or sample width we cannot decode, or which is not valid at all.
+    UnsupportedWav(String),
+    InvalidWav(&'static str),
+    // A lossy format whose decoder was left out of the build.
+    FeatureDisabled(AudioFormat),
+    // A format recognized, but which there is no decoder for.
+    NoDecoder(AudioFormat),
+    // The file starts with the magic bytes of no format we can decode.
+    UnknownFormat,
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Io(err) => write!(f, "{err}"),
+            AudioError::Hound(err) => write!(f, "{err}"),
+            AudioError::Flac(err) => write!(f, "{err}"),
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            AudioError::Lossy(err) => write!(f, "{err}"),
+            AudioError::UnsupportedWav(format) => write!(f, "WAV files of {format}"),
+            AudioError::InvalidWav(message) => write!(f, "invalid WAV file: {message}"),
+            AudioError::FeatureDisabled(format) => write!(
+                f,
+                "{format:?} files need the `{}` feature",
+                format.feature().unwrap_or_default()
+            ),
+            AudioError::NoDecoder(format) => write!(f, "{format:?} files cannot be decoded"),
+            AudioError::UnknownFormat => write!(f, "not a file of any known audio format"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioError::Io(err) => Some(err),
+            AudioError::Hound(err) => Some(err),
+            AudioError::Flac(err) => Some(err),
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            AudioError::Lossy(err) => Some(err),
+            AudioError::UnsupportedWav(_)
+            | AudioError::InvalidWav(_)
+            | AudioError::FeatureDisabled(_)
+            | AudioError::NoDecoder(_)
+            | AudioError::UnknownFormat => None,
+        }
+    }
+}
+
+impl From<io::Error> for AudioError {
+    fn from(err: io::Error) -> Self {
+        AudioError::Io(err)
+    }
+}
+
+impl From<claxon::Error> for AudioError {
+    fn from(err: claxon::Error) -> Self {
+        AudioError::Flac(err)
+    }
+}
+
+#[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+impl From<symphonia::core::errors::Error> for AudioError {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        AudioError::Lossy(err)
+    }
 }
 
 impl From<hound::Error> for AudioError {
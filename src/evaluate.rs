@@ -0,0 +1,404 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    batch::QueryResult,
+    error::{Error, Result},
+};
+
+// The score thresholds precision and recall are reported at, unless others are given. Scores are
+// the share of query fingerprints voting for the match, which is small even for a clear match.
+pub const DEFAULT_THRESHOLDS: [f32; 8] = [0.0, 0.001, 0.002, 0.003, 0.005, 0.01, 0.02, 0.05];
+
+// A query along with the song it should be recognized as, or `None` if it is not in the
+// database and should not be recognized at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelledQuery {
+    pub query: PathBuf,
+    pub expected: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct LabelRow {
+    query: String,
+    expected: String,
+}
+
+// Reads a CSV file with a `query` column, the path of the query relative to the file, and an
+// `expected` column, the id of the song it is of, or `none`.
+pub fn load_labels(path: &Path) -> Result<Vec<LabelledQuery>> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+
+    let mut labels = Vec::new();
+    for (index, row) in reader.deserialize::<LabelRow>().enumerate() {
+        let row = row?;
+        let expected = match row.expected.to_lowercase().as_str() {
+            "" | "none" => None,
+            song_id => Some(song_id.parse().map_err(|_| {
+                Error::InvalidInput(format!(
+                    "row {}: expected song {:?} is neither a song id nor \"none\"",
+                    index + 1,
+                    row.expected
+                ))
+            })?),
+        };
+        labels.push(LabelledQuery {
+            query: directory.join(row.query),
+            expected,
+        });
+    }
+
+    Ok(labels)
+}
+
+// How well the queries were recognized.
+#[derive(Debug, Serialize)]
+pub struct Evaluation {
+    pub queries: usize,
+    // Queries of a song in the database, and of none.
+    pub positives: usize,
+    pub negatives: usize,
+    // Queries which could not be recognized at all, such as unreadable files. They count as not
+    // having matched.
+    pub failed: usize,
+    // The share of queries whose best match, if any, is the expected song.
+    pub accuracy: f64,
+    // The average time it took to recognize the queries matched to their expected song, and all
+    // queries.
+    pub mean_time_to_identify_ms: Option<f64>,
+    pub mean_query_time_ms: Option<f64>,
+    pub thresholds: Vec<ThresholdMetrics>,
+    // The points of the ROC and DET curves, one at every score of a match. Only set with both
+    // positive and negative queries.
+    pub curve: Vec<CurvePoint>,
+    // Pairs of expected song and best match which differ, most frequent first.
+    pub confusions: Vec<Confusion>,
+    pub results: Vec<EvaluatedQuery>,
+}
+
+// A query is identified at a threshold when its best match has at least that score. Identifying a
+// song other than the expected one is both a false positive and a false negative.
+#[derive(Debug, Clone, Serialize)]
+pub struct ThresholdMetrics {
+    pub threshold: f32,
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub true_negatives: usize,
+    pub false_negatives: usize,
+    pub accuracy: f64,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+    // The share of negative queries identified as any song.
+    pub false_positive_rate: Option<f64>,
+}
+
+// The ROC curve plots the true positive rate against the false positive rate, and the DET curve
+// the false negative rate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CurvePoint {
+    // Unset for the point at which no query is identified.
+    pub threshold: Option<f32>,
+    pub false_positive_rate: f64,
+    pub true_positive_rate: f64,
+    pub false_negative_rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Confusion {
+    pub expected: Option<u32>,
+    pub matched: Option<u32>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvaluatedQuery {
+    pub query: PathBuf,
+    pub expected: Option<u32>,
+    pub matched: Option<u32>,
+    pub score: Option<f32>,
+    pub correct: bool,
+    pub error: Option<String>,
+    pub time_ms: f64,
+}
+
+// Scores the results of recognizing `labels`, given in the same order.
+pub fn evaluate(
+    labels: &[LabelledQuery],
+    results: &[QueryResult],
+    thresholds: &[f32],
+) -> Evaluation {
+    let evaluated: Vec<EvaluatedQuery> = labels
+        .iter()
+        .zip(results)
+        .map(|(label, result)| {
+            let (matched, score) = match &result.outcome {
+                Ok(Some((_, matched))) => (Some(matched.song_id), Some(matched.confidence)),
+                _ => (None, None),
+            };
+            EvaluatedQuery {
+                query: label.query.clone(),
+                expected: label.expected,
+                matched,
+                score,
+                correct: matched == label.expected,
+                error: result.outcome.as_ref().err().map(|err| err.to_string()),
+                time_ms: milliseconds(result.timings.total()),
+            }
+        })
+        .collect();
+
+    let positives = evaluated
+        .iter()
+        .filter(|query| query.expected.is_some())
+        .count();
+    let negatives = evaluated.len() - positives;
+
+    let mut scores: Vec<f32> = evaluated.iter().filter_map(|query| query.score).collect();
+    scores.sort_by(|a, b| b.total_cmp(a));
+    scores.dedup();
+    let curve = if positives > 0 && negatives > 0 {
+        // From the strictest threshold, at which nothing is identified, to accepting every match.
+        std::iter::once(None)
+            .chain(scores.into_iter().map(Some))
+            .map(|threshold| {
+                let metrics = metrics_at(
+                    &evaluated,
+                    threshold.unwrap_or(f32::INFINITY),
+                    positives,
+                    negatives,
+                );
+                let true_positive_rate = metrics.recall.unwrap_or_default();
+                CurvePoint {
+                    threshold,
+                    false_positive_rate: metrics.false_positive_rate.unwrap_or_default(),
+                    true_positive_rate,
+                    false_negative_rate: 1.0 - true_positive_rate,
+                }
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut confusion_counts: HashMap<(Option<u32>, Option<u32>), usize> = HashMap::new();
+    for query in evaluated.iter().filter(|query| !query.correct) {
+        *confusion_counts
+            .entry((query.expected, query.matched))
+            .or_default() += 1;
+    }
+    let mut confusions: Vec<Confusion> = confusion_counts
+        .into_iter()
+        .map(|((expected, matched), count)| Confusion {
+            expected,
+            matched,
+            count,
+        })
+        .collect();
+    confusions.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(a.expected.cmp(&b.expected))
+            .then(a.matched.cmp(&b.matched))
+    });
+
+    let identified_times: Vec<f64> = evaluated
+        .iter()
+        .filter(|query| query.correct && query.expected.is_some())
+        .map(|query| query.time_ms)
+        .collect();
+    let query_times: Vec<f64> = evaluated.iter().map(|query| query.time_ms).collect();
+
+    Evaluation {
+        queries: evaluated.len(),
+        positives,
+        negatives,
+        failed: evaluated
+            .iter()
+            .filter(|query| query.error.is_some())
+            .count(),
+        accuracy: ratio(
+            evaluated.iter().filter(|query| query.correct).count(),
+            evaluated.len(),
+        )
+        .unwrap_or_default(),
+        mean_time_to_identify_ms: mean(&identified_times),
+        mean_query_time_ms: mean(&query_times),
+        thresholds: thresholds
+            .iter()
+            .map(|&threshold| metrics_at(&evaluated, threshold, positives, negatives))
+            .collect(),
+        curve,
+        confusions,
+        results: evaluated,
+    }
+}
+
+fn metrics_at(
+    evaluated: &[EvaluatedQuery],
+    threshold: f32,
+    positives: usize,
+    negatives: usize,
+) -> ThresholdMetrics {
+    let mut metrics = ThresholdMetrics {
+        threshold,
+        true_positives: 0,
+        false_positives: 0,
+        true_negatives: 0,
+        false_negatives: 0,
+        accuracy: 0.0,
+        precision: None,
+        recall: None,
+        false_positive_rate: None,
+    };
+    let mut identified_negatives = 0;
+    for query in evaluated {
+        let identified = query
+            .score
+            .is_some_and(|score| score >= threshold)
+            .then_some(query.matched)
+            .flatten();
+        match (query.expected, identified) {
+            (Some(expected), Some(matched)) if expected == matched => metrics.true_positives += 1,
+            (Some(_), Some(_)) => {
+                metrics.false_positives += 1;
+                metrics.false_negatives += 1;
+            }
+            (Some(_), None) => metrics.false_negatives += 1,
+            (None, Some(_)) => {
+                metrics.false_positives += 1;
+                identified_negatives += 1;
+            }
+            (None, None) => metrics.true_negatives += 1,
+        }
+    }
+
+    metrics.accuracy = ratio(
+        metrics.true_positives + metrics.true_negatives,
+        evaluated.len(),
+    )
+    .unwrap_or_default();
+    metrics.precision = ratio(
+        metrics.true_positives,
+        metrics.true_positives + metrics.false_positives,
+    );
+    metrics.recall = ratio(metrics.true_positives, positives);
+    metrics.false_positive_rate = ratio(identified_negatives, negatives);
+    metrics
+}
+
+fn ratio(numerator: usize, denominator: usize) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use crate::{
+        Error, MatchResult, QueryResult, QueryTimings, SongMetaData,
+        evaluate::{Confusion, LabelledQuery, evaluate, load_labels},
+    };
+
+    #[test]
+    fn precision_and_recall() {
+        let path = crate::temp_path("labels.csv");
+        fs::write(
+            &path,
+            "query,expected\na.wav,1\nb.wav,2\nc.wav, none\nd.wav,NONE\ne.wav,3\n",
+        )
+        .unwrap();
+        let labels = load_labels(&path).unwrap();
+        let directory = path.parent().unwrap();
+        assert_eq!(
+            labels[2],
+            LabelledQuery {
+                query: directory.join("c.wav"),
+                expected: None,
+            }
+        );
+        fs::write(&path, "query,expected\na.wav,first\n").unwrap();
+        assert!(matches!(load_labels(&path), Err(Error::InvalidInput(_))));
+        fs::remove_file(&path).unwrap();
+
+        let result = |query: &str, outcome: Option<(u32, f32)>| QueryResult {
+            query: PathBuf::from(query),
+            outcome: Ok(outcome.map(|(song_id, score)| {
+                let metadata = SongMetaData {
+                    song_id,
+                    ..SongMetaData::default()
+                };
+                (metadata, MatchResult::new(song_id, score, 0, 10))
+            })),
+            timings: QueryTimings {
+                load: Duration::from_millis(10),
+                ..QueryTimings::default()
+            },
+        };
+        let results = [
+            // Correct, with a high score.
+            result("a.wav", Some((1, 0.05))),
+            // The wrong song.
+            result("b.wav", Some((1, 0.01))),
+            // A song, with a low score.
+            result("c.wav", Some((2, 0.001))),
+            // Correctly not matched.
+            result("d.wav", None),
+            QueryResult {
+                query: PathBuf::from("e.wav"),
+                outcome: Err(Error::Decoding(String::from("bad header"))),
+                timings: QueryTimings::default(),
+            },
+        ];
+
+        let evaluation = evaluate(&labels, &results, &[0.0, 0.005]);
+        assert_eq!(evaluation.positives, 3);
+        assert_eq!(evaluation.negatives, 2);
+        assert_eq!(evaluation.failed, 1);
+        assert_eq!(evaluation.accuracy, 0.4);
+        assert_eq!(evaluation.mean_time_to_identify_ms, Some(10.0));
+        assert_eq!(evaluation.mean_query_time_ms, Some(8.0));
+
+        let at_zero = &evaluation.thresholds[0];
+        assert_eq!(at_zero.true_positives, 1);
+        assert_eq!(at_zero.false_positives, 2);
+        assert_eq!(at_zero.false_negatives, 2);
+        assert_eq!(at_zero.true_negatives, 1);
+        assert_eq!(at_zero.false_positive_rate, Some(0.5));
+        // The low scoring match of a negative query is no longer identified.
+        let strict = &evaluation.thresholds[1];
+        assert_eq!(strict.false_positives, 1);
+        assert_eq!(strict.true_negatives, 2);
+        assert_eq!(strict.precision, Some(0.5));
+        assert_eq!(strict.false_positive_rate, Some(0.0));
+
+        let first = &evaluation.curve[0];
+        assert_eq!(first.threshold, None);
+        assert_eq!(first.false_positive_rate, 0.0);
+        assert_eq!(first.true_positive_rate, 0.0);
+        let last = evaluation.curve.last().unwrap();
+        assert_eq!(last.threshold, Some(0.001));
+        assert_eq!(last.false_positive_rate, 0.5);
+        assert_eq!(last.true_positive_rate, 1.0 / 3.0);
+        assert_eq!(evaluation.confusions.len(), 3);
+        assert!(evaluation.confusions.contains(&Confusion {
+            expected: Some(2),
+            matched: Some(1),
+            count: 1,
+        }));
+    }
+}
@@ -0,0 +1,299 @@
+use serde::Serialize;
+use std::{io::Write, path::Path};
+
+use crate::{
+    error::{Error, Result},
+    fft::{Spectrogram, SpectrogramConfig},
+    fingerprint::Fingerprint,
+    peaks::Peak,
+};
+
+// What is exported of the analysis of a song.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportData {
+    // The magnitude of every window and frequency bin.
+    Spectrogram,
+    Peaks,
+    Fingerprints,
+}
+
+impl ExportData {
+    // Fails with `Error::InvalidInput` for a format the data cannot be exported in.
+    pub fn check_format(self, format: ExportFormat) -> Result<()> {
+        match (self, format) {
+            (ExportData::Spectrogram, ExportFormat::Json) => Err(Error::InvalidInput(
+                String::from("spectrograms are exported as .npy or .csv"),
+            )),
+            (ExportData::Peaks | ExportData::Fingerprints, ExportFormat::Npy) => {
+                Err(Error::InvalidInput(String::from(
+                    "peaks and fingerprints are exported as .csv or .json",
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// The numeric formats analysis data is exported in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    // A NumPy array, as read by `numpy.load`. Only for spectrograms.
+    Npy,
+    // Spectrograms a row per window, with the frequency of every bin in the header, and peaks
+    // and fingerprints a row each.
+    Csv,
+    // An array of objects. Only for peaks and fingerprints.
+    Json,
+}
+
+impl ExportFormat {
+    // The format named by the extension of `path`.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("npy") => Ok(ExportFormat::Npy),
+            Some("csv") => Ok(ExportFormat::Csv),
+            Some("json") => Ok(ExportFormat::Json),
+            _ => Err(Error::InvalidInput(format!(
+                "{path:?} must end in .npy, .csv or .json to export to"
+            ))),
+        }
+    }
+}
+
+// A peak in physical units, along with the bins it was found at.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeakRecord {
+    pub time_bin: usize,
+    pub freq_bin: usize,
+    pub time_seconds: f32,
+    pub frequency_hz: f32,
+    pub magnitude: f32,
+}
+
+impl PeakRecord {
+    pub fn new(peak: &Peak, config: &SpectrogramConfig) -> Self {
+        Self {
+            time_bin: peak.time_bin,
+            freq_bin: peak.freq_bin,
+            time_seconds: peak.time_seconds(config),
+            frequency_hz: peak.frequency_hz(config),
+            magnitude: peak.magnitude,
+        }
+    }
+}
+
+// A fingerprint with its offset into the audio, and the fields it encodes, see
+// `Fingerprint::decode`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FingerprintRecord {
+    // The encoded fingerprint, as stored in the database.
+    pub hash: u32,
+    pub offset_ms: u32,
+    pub freq1_hz: u32,
+    pub freq2_hz: u32,
+    pub time_delta_ms: u32,
+}
+
+impl FingerprintRecord {
+    pub fn new(fingerprint: &Fingerprint, offset_ms: u32) -> Self {
+        let (freq1_hz, freq2_hz, time_delta_ms) = fingerprint.decode();
+        Self {
+            hash: fingerprint.to_bits(),
+            offset_ms,
+            freq1_hz,
+            freq2_hz,
+            time_delta_ms,
+        }
+    }
+}
+
+// Writes the magnitudes of a spectrogram as .npy, a float32 array of windows by frequency bins,
+// or as CSV.
+pub fn export_spectrogram<W: Write>(
+    spectrogram: &Spectrogram,
+    format: ExportFormat,
+    writer: W,
+) -> Result<()> {
+    ExportData::Spectrogram.check_format(format)?;
+    match format {
+        ExportFormat::Csv => write_spectrogram_csv(spectrogram, writer),
+        _ => write_npy(spectrogram, writer),
+    }
+}
+
+pub fn export_peaks<W: Write>(
+    peaks: &[Peak],
+    config: &SpectrogramConfig,
+    format: ExportFormat,
+    writer: W,
+) -> Result<()> {
+    ExportData::Peaks.check_format(format)?;
+    let records: Vec<PeakRecord> = peaks
+        .iter()
+        .map(|peak| PeakRecord::new(peak, config))
+        .collect();
+    write_records(&records, format, writer)
+}
+
+// Writes fingerprints along with their offset into the audio in ms.
+pub fn export_fingerprints<W: Write>(
+    fingerprints: &[(Fingerprint, u32)],
+    format: ExportFormat,
+    writer: W,
+) -> Result<()> {
+    ExportData::Fingerprints.check_format(format)?;
+    let records: Vec<FingerprintRecord> = fingerprints
+        .iter()
+        .map(|(fingerprint, offset_ms)| FingerprintRecord::new(fingerprint, *offset_ms))
+        .collect();
+    write_records(&records, format, writer)
+}
+
+// Writes records as CSV or JSON, the formats checked by the caller.
+fn write_records<R: Serialize, W: Write>(
+    records: &[R],
+    format: ExportFormat,
+    mut writer: W,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in records {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+        _ => {
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+// Version 1.0 of the format: a magic string, the length of the header, and a header describing
+// the array, padded so that the data starts at a multiple of 64 bytes, followed by the data in C
+// order.
+fn write_npy<W: Write>(spectrogram: &Spectrogram, mut writer: W) -> Result<()> {
+    let windows = spectrogram.data.len();
+    let bins = spectrogram.config.num_bins();
+    let mut header =
+        format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({windows}, {bins}), }}");
+    // The magic string, version and header length take 10 bytes, and the header ends in a
+    // newline.
+    let padding = (64 - (10 + header.len() + 1) % 64) % 64;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for window in spectrogram.data.iter() {
+        let bytes: Vec<u8> = window
+            .iter()
+            .flat_map(|magnitude| magnitude.to_le_bytes())
+            .collect();
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// A row per window, starting with its time in seconds, followed by the magnitude of every bin.
+fn write_spectrogram_csv<W: Write>(spectrogram: &Spectrogram, writer: W) -> Result<()> {
+    let config = &spectrogram.config;
+    let mut writer = csv::Writer::from_writer(writer);
+
+    let mut header = vec![String::from("time_seconds")];
+    header.extend((0..config.num_bins()).map(|bin| config.bin_frequency_hz(bin).to_string()));
+    writer.write_record(&header)?;
+    for (time_bin, window) in spectrogram.data.iter().enumerate() {
+        let time_seconds = (time_bin * config.stride) as f32 / config.sample_rate;
+        let mut row = vec![time_seconds.to_string()];
+        row.extend(window.iter().map(|magnitude| magnitude.to_string()));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{
+        export::{ExportFormat, export_fingerprints, export_peaks, export_spectrogram},
+        fft::{Spectrogram, SpectrogramConfig},
+        fingerprint::Fingerprint,
+        peaks::Peak,
+    };
+
+    #[test]
+    fn export_formats() {
+        // Two windows of four bins, 2000 Hz and 4 ms apart.
+        let config = SpectrogramConfig {
+            window_size: 8,
+            stride: 64,
+            sample_rate: 16000.0,
+            ..SpectrogramConfig::default()
+        };
+        let spectrogram = Spectrogram {
+            data: vec![vec![0.0, 1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0, 7.5]],
+            config,
+        };
+
+        let mut npy = Vec::new();
+        export_spectrogram(&spectrogram, ExportFormat::Npy, &mut npy).unwrap();
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 4), }"));
+        assert!(header.ends_with('\n'));
+        assert_eq!((10 + header_len) % 64, 0);
+        let data = &npy[10 + header_len..];
+        assert_eq!(data.len(), 8 * 4);
+        assert_eq!(&data[28..], 7.5f32.to_le_bytes());
+
+        let mut csv = Vec::new();
+        export_spectrogram(&spectrogram, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time_seconds,0,2000,4000,6000\n0,0,1,2,3\n0.004,4,5,6,7.5\n"
+        );
+
+        let peaks = [Peak::new(1, 2, 6.0)];
+        let mut csv = Vec::new();
+        export_peaks(&peaks, &config, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time_bin,freq_bin,time_seconds,frequency_hz,magnitude\n1,2,0.004,4000.0,6.0\n"
+        );
+
+        let fingerprints = [(Fingerprint::new(1000, 4000, 250), 120)];
+        let mut json = Vec::new();
+        export_fingerprints(&fingerprints, ExportFormat::Json, &mut json).unwrap();
+        let records: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            records,
+            serde_json::json!([{
+                "hash": Fingerprint::new(1000, 4000, 250).to_bits(),
+                "offset_ms": 120,
+                "freq1_hz": 1000,
+                "freq2_hz": 4000,
+                "time_delta_ms": 250,
+            }])
+        );
+
+        // Spectrograms are arrays, and peaks and fingerprints records.
+        assert!(export_spectrogram(&spectrogram, ExportFormat::Json, Vec::new()).is_err());
+        assert!(export_peaks(&peaks, &config, ExportFormat::Npy, Vec::new()).is_err());
+        assert_eq!(
+            ExportFormat::from_path(Path::new("peaks.JSON")).unwrap(),
+            ExportFormat::Json
+        );
+        assert!(ExportFormat::from_path(Path::new("peaks.txt")).is_err());
+    }
+}
@@ -1,23 +1,96 @@
 use rustfft::{FftPlanner, num_complex::Complex};
+use serde::{Deserialize, Serialize};
+
+use crate::filterbank::{self, Filterbank};
 
 // Converts time-domain samples into a spectrogram using FFT. Given this info, we find frequency
 // peaks.
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct SpectrogramConfig {
     pub window_size: usize, // The FFT window size
     pub stride: usize,      // The stride we slide the window along with.
     pub sample_rate: f32,
+    // How the FFT bins are spaced, or grouped into bands.
+    pub frequency_scale: FrequencyScale,
+    // The number of mel or bark bands.
+    pub bands: usize,
+    // The lower edge of the lowest band, in Hz. Defaults to 0 for mel and bark bands, and to C1,
+    // 32.7 Hz, for a constant-Q transform.
+    pub min_frequency_hz: Option<f32>,
+    // The bands of every octave of a constant-Q transform.
+    pub bins_per_octave: usize,
+    pub magnitude_scale: MagnitudeScale,
 }
-impl SpectrogramConfig {
-    pub(crate) fn default() -> SpectrogramConfig {
+impl Default for SpectrogramConfig {
+    fn default() -> SpectrogramConfig {
         Self {
             window_size: 1024,
             stride: 512,
             sample_rate: 48000.0,
+            frequency_scale: FrequencyScale::Linear,
+            bands: 64,
+            min_frequency_hz: None,
+            bins_per_octave: 12,
+            magnitude_scale: MagnitudeScale::Linear,
         }
     }
 }
 
+impl SpectrogramConfig {
+    // The number of frequency bins in every window of the spectrogram.
+    pub fn num_bins(&self) -> usize {
+        filterbank::num_bins(self)
+    }
+
+    // The frequency at the centre of a frequency bin, in Hz.
+    pub fn bin_frequency_hz(&self, bin: usize) -> f32 {
+        filterbank::bin_frequency_hz(self, bin)
+    }
+}
+
+// The frequency axis of the spectrogram.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrequencyScale {
+    // The FFT bins themselves, evenly spaced from 0 Hz up to the Nyquist frequency.
+    #[default]
+    Linear,
+    // Triangular bands evenly spaced in mels, a scale of perceived pitch.
+    Mel,
+    // Triangular bands evenly spaced in barks, the critical bands of hearing.
+    Bark,
+    // A constant-Q transform, with bands evenly spaced in octaves and as wide as they are apart,
+    // taken from the FFT bins. The window size limits its resolution at low frequencies.
+    Cqt,
+}
+
+// The magnitudes held by the spectrogram.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MagnitudeScale {
+    #[default]
+    Linear,
+    // Decibels, 20 log10(m), floored at -100 dB.
+    Db,
+    // ln(1 + m), compressing loud bins while keeping silence at 0.
+    Log,
+}
+
+impl MagnitudeScale {
+    fn apply(self, magnitude: f32) -> f32 {
+        match self {
+            MagnitudeScale::Linear => magnitude,
+            MagnitudeScale::Db => 20.0 * magnitude.max(DB_FLOOR_MAGNITUDE).log10(),
+            MagnitudeScale::Log => magnitude.ln_1p(),
+        }
+    }
+}
+
+// The magnitude of -100 dB, below which magnitudes are floored in decibels.
+const DB_FLOOR_MAGNITUDE: f32 = 1e-5;
+
+#[derive(Debug, Clone)]
 pub struct Spectrogram {
     pub data: Vec<Vec<f32>>,
     pub config: SpectrogramConfig,
@@ -25,10 +98,7 @@ pub struct Spectrogram {
 
 impl Spectrogram {
     pub(crate) fn new(data: Vec<Vec<f32>>, config: SpectrogramConfig) -> Spectrogram {
-        Self {
-            data: data,
-            config: config,
-        }
+        Self { data, config }
     }
 }
 
@@ -53,36 +123,157 @@ pub fn compute_spectrogram(samples: &[f32], config: SpectrogramConfig) -> Spectr
         config.stride,
         config.sample_rate
     );
-    // 1. Split samples
-    let num_windows = (samples.len() - config.window_size) / config.stride + 1;
+    // 1. Split samples. Samples past the last full window are zero-padded into one more window,
+    // so the tail of the audio, or audio shorter than a window, is not dropped.
+    let num_windows = num_windows(samples.len(), &config);
     log::debug!("Using num_windows: {}", num_windows);
     // 2. Apply FFT
 
     log::debug! {"Running FFT"}
     let mut planner = FftPlanner::<f32>::new();
     let fft = planner.plan_fft_forward(config.window_size);
+    let filterbank = Filterbank::new(&config);
 
     // This vec holds the spectrogram data from each sample window
     let mut spectrogram_data = Vec::new();
 
     for i in 0..num_windows {
         let start = i * config.stride;
-        let window = &samples[start..start + config.window_size];
+        let window = &samples[start..(start + config.window_size).min(samples.len())];
 
         let mut complex_samples: Vec<Complex<f32>> =
             window.iter().map(|&x| Complex::new(x, 0.0)).collect();
+        complex_samples.resize(config.window_size, Complex::new(0.0, 0.0));
         fft.process(&mut complex_samples);
 
         // 3. Convert to magnitudes
-        let magnitudes: Vec<f32> = complex_samples
+        let mut magnitudes: Vec<f32> = complex_samples
             .iter()
             .take(config.window_size / 2)
             .map(|&c| c.norm())
             .collect();
+        // 4. Group into bands, and scale the magnitudes, as configured
+        if let Some(filterbank) = &filterbank {
+            magnitudes = filterbank.apply(&magnitudes);
+        }
+        if config.magnitude_scale != MagnitudeScale::Linear {
+            magnitudes
+                .iter_mut()
+                .for_each(|magnitude| *magnitude = config.magnitude_scale.apply(*magnitude));
+        }
 
         spectrogram_data.push(magnitudes);
     }
     log::debug!("Done running FFT");
-    // 4. Store in spectrogram struct
+    // 5. Store in spectrogram struct
     Spectrogram::new(spectrogram_data, config)
 }
+
+// The windows covering every sample, the last of which may run past the end. None without any.
+fn num_windows(samples: usize, config: &SpectrogramConfig) -> usize {
+    if samples == 0 {
+        return 0;
+    }
+    samples
+        .saturating_sub(config.window_size)
+        .div_ceil(config.stride)
+        + 1
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+
+    use crate::fft::{FrequencyScale, MagnitudeScale, SpectrogramConfig, compute_spectrogram};
+
+    #[test]
+    fn partial_windows() {
+        let config = SpectrogramConfig {
+            window_size: 8,
+            stride: 4,
+            sample_rate: 8000.0,
+            ..SpectrogramConfig::default()
+        };
+        let windows = |samples: usize| compute_spectrogram(&vec![0.5; samples], config).data;
+
+        assert!(windows(0).is_empty());
+        // Audio shorter than a window is zero-padded into one.
+        let short = windows(3);
+        assert_eq!(short.len(), 1);
+        assert_eq!(short[0].len(), 4);
+        assert_eq!(short[0][0], 1.5);
+        assert_eq!(windows(8).len(), 1);
+        // The last 2 samples are covered by one more window, padded with zeros.
+        let padded = windows(14);
+        assert_eq!(padded.len(), 3);
+        assert_eq!(padded[2][0], 3.0);
+        assert_eq!(windows(16).len(), 3);
+    }
+
+    #[test]
+    fn spectrogram_variants() {
+        // A second of a 1000 Hz tone.
+        let sample_rate = 8000.0;
+        let samples: Vec<f32> = (0..8000)
+            .map(|i| (2.0 * PI * 1000.0 * i as f32 / sample_rate).sin())
+            .collect();
+        let linear = SpectrogramConfig {
+            window_size: 512,
+            stride: 256,
+            sample_rate,
+            ..SpectrogramConfig::default()
+        };
+
+        // The loudest bin of the first window, and its frequency.
+        let loudest = |config: SpectrogramConfig| {
+            let spectrogram = compute_spectrogram(&samples, config);
+            let window = &spectrogram.data[0];
+            assert_eq!(window.len(), config.num_bins());
+            let (bin, &magnitude) = window
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            (config.bin_frequency_hz(bin), magnitude)
+        };
+
+        let (hz, magnitude) = loudest(linear);
+        assert_eq!(linear.num_bins(), 256);
+        assert_eq!(hz, 1000.0);
+
+        // The bands are evenly spread along their scale, so the tone falls within half a band of
+        // the centre of the loudest.
+        for (frequency_scale, bins, tolerance_hz) in [
+            (FrequencyScale::Mel, 40, 50.0),
+            (FrequencyScale::Bark, 40, 50.0),
+            // The octaves from C1 up to 4000 Hz.
+            (FrequencyScale::Cqt, 83, 30.0),
+        ] {
+            let config = SpectrogramConfig {
+                frequency_scale,
+                bands: 40,
+                ..linear
+            };
+            assert_eq!(config.num_bins(), bins, "{frequency_scale:?}");
+            let (band_hz, _) = loudest(config);
+            assert!(
+                (band_hz - 1000.0).abs() < tolerance_hz,
+                "{frequency_scale:?}: {band_hz}"
+            );
+        }
+
+        let db = SpectrogramConfig {
+            magnitude_scale: MagnitudeScale::Db,
+            ..linear
+        };
+        assert!((loudest(db).1 - 20.0 * magnitude.log10()).abs() < 1e-3);
+        let log = SpectrogramConfig {
+            magnitude_scale: MagnitudeScale::Log,
+            ..linear
+        };
+        assert!((loudest(log).1 - magnitude.ln_1p()).abs() < 1e-3);
+        // Silence is floored at -100 dB rather than going to minus infinity.
+        let silence = compute_spectrogram(&[0.0; 512], db);
+        assert!(silence.data[0].iter().all(|&magnitude| magnitude == -100.0));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::fft::{FrequencyScale, SpectrogramConfig};
+
+// The lowest band of a constant-Q transform unless configured otherwise: C1, in Hz.
+const DEFAULT_CQT_MIN_FREQUENCY_HZ: f32 = 32.703;
+
+// Groups the FFT bins of a window into bands spaced along a perceptual or logarithmic frequency
+// scale. Every band is a triangle, rising from the centre of the band below it to its own centre
+// and falling to the centre of the band above it, so that neighbouring bands overlap by half.
+pub(crate) struct Filterbank {
+    // The first FFT bin every band weighs, and the weights of the bins from there, summing to 1.
+    bands: Vec<(usize, Vec<f32>)>,
+}
+
+impl Filterbank {
+    // None for a linear frequency scale, which keeps the FFT bins as they are.
+    pub fn new(config: &SpectrogramConfig) -> Option<Self> {
+        if config.frequency_scale == FrequencyScale::Linear {
+            return None;
+        }
+
+        let hz_per_bin = config.sample_rate / config.window_size as f32;
+        let fft_bins = config.window_size / 2;
+        let bands = (0..num_bands(config))
+            .map(|band| {
+                let lower = edge_hz(config, band);
+                let centre = edge_hz(config, band + 1);
+                let upper = edge_hz(config, band + 2);
+
+                let first = ((lower / hz_per_bin).ceil() as usize).min(fft_bins);
+                let last = ((upper / hz_per_bin).floor() as usize).min(fft_bins - 1);
+                let mut weights: Vec<f32> = (first..=last)
+                    .map(|bin| {
+                        let hz = bin as f32 * hz_per_bin;
+                        if hz <= centre {
+                            (hz - lower) / (centre - lower)
+                        } else {
+                            (upper - hz) / (upper - centre)
+                        }
+                        .max(0.0)
+                    })
+                    .collect();
+
+                // Bands narrower than an FFT bin, low in a constant-Q transform, take the bin
+                // nearest to their centre.
+                let total: f32 = weights.iter().sum();
+                if total <= 0.0 {
+                    let nearest = ((centre / hz_per_bin).round() as usize).min(fft_bins - 1);
+                    return (nearest, vec![1.0]);
+                }
+                weights.iter_mut().for_each(|weight| *weight /= total);
+                (first, weights)
+            })
+            .collect();
+
+        Some(Self { bands })
+    }
+
+    // The energy of every band from the magnitudes of the FFT bins, as the root of the weighted
+    // mean of their power, so that bands are on the same scale as the bins.
+    pub fn apply(&self, magnitudes: &[f32]) -> Vec<f32> {
+        self.bands
+            .iter()
+            .map(|(first, weights)| {
+                weights
+                    .iter()
+                    .zip(&magnitudes[*first..])
+                    .map(|(weight, magnitude)| weight * magnitude * magnitude)
+                    .sum::<f32>()
+                    .sqrt()
+            })
+            .collect()
+    }
+}
+
+// The frequency bins of a spectrogram: FFT bins, or bands of the frequency scale.
+pub(crate) fn num_bins(config: &SpectrogramConfig) -> usize {
+    match config.frequency_scale {
+        FrequencyScale::Linear => config.window_size / 2,
+        _ => num_bands(config),
+    }
+}
+
+// The frequency at the centre of a frequency bin, in Hz.
+pub(crate) fn bin_frequency_hz(config: &SpectrogramConfig, bin: usize) -> f32 {
+    match config.frequency_scale {
+        FrequencyScale::Linear => bin as f32 * config.sample_rate / config.window_size as f32,
+        _ => edge_hz(config, bin + 1),
+    }
+}
+
+fn min_frequency_hz(config: &SpectrogramConfig) -> f32 {
+    config
+        .min_frequency_hz
+        .unwrap_or(match config.frequency_scale {
+            FrequencyScale::Cqt => DEFAULT_CQT_MIN_FREQUENCY_HZ,
+            _ => 0.0,
+        })
+}
+
+fn num_bands(config: &SpectrogramConfig) -> usize {
+    match config.frequency_scale {
+        FrequencyScale::Linear => 0,
+        FrequencyScale::Mel | FrequencyScale::Bark => config.bands,
+        // As many bands as fit below the Nyquist frequency, the edge of the last included.
+        FrequencyScale::Cqt => {
+            let octaves = (config.sample_rate / 2.0 / min_frequency_hz(config)).log2();
+            (config.bins_per_octave as f32 * octaves).max(0.0).floor() as usize
+        }
+    }
+}
+
+// The edges between bands, where the edge below band `i` is edge `i`, and its centre edge
+// `i + 1`. Mel and bark bands are evenly spread along their scale from the minimum frequency up
+// to the Nyquist frequency, and constant-Q bands evenly along octaves.
+fn edge_hz(config: &SpectrogramConfig, edge: usize) -> f32 {
+    let min_hz = min_frequency_hz(config);
+    let max_hz = config.sample_rate / 2.0;
+    let along = |to_scale: fn(f32) -> f32, from_scale: fn(f32) -> f32| {
+        let (low, high) = (to_scale(min_hz), to_scale(max_hz));
+        from_scale(low + (high - low) * edge as f32 / (config.bands + 1) as f32)
+    };
+
+    match config.frequency_scale {
+        FrequencyScale::Linear => edge as f32 * config.sample_rate / config.window_size as f32,
+        FrequencyScale::Mel => along(hz_to_mel, mel_to_hz),
+        FrequencyScale::Bark => along(hz_to_bark, bark_to_hz),
+        FrequencyScale::Cqt => {
+            min_hz * 2f32.powf((edge as f32 - 1.0) / config.bins_per_octave as f32)
+        }
+    }
+}
+
+// The mel scale of HTK.
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+// The bark scale of Traunmüller (1990).
+fn hz_to_bark(hz: f32) -> f32 {
+    26.81 * hz / (1960.0 + hz) - 0.53
+}
+
+fn bark_to_hz(bark: f32) -> f32 {
+    1960.0 * (bark + 0.53) / (26.28 - bark)
+}
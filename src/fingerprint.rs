@@ -2,16 +2,43 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{self, BufReader, Read, Seek, Write},
     path::Path,
 };
 
-use crate::{fft::SpectrogramConfig, peaks::Peak};
+use crate::{
+    config::AnalysisConfig,
+    error::{Error, Result},
+    fft::{FrequencyScale, MagnitudeScale, SpectrogramConfig},
+    metadata::SongMetaData,
+    peaks::Peak,
+    postings::PostingList,
+    preprocess::PreprocessConfig,
+    segment::Journal,
+    storage::{FingerprintStore, IndexSummary, write_atomically},
+};
+
+// How peaks are paired up into fingerprints. Every peak is an anchor, paired with up to
+// `num_target_peaks` of the peaks following it by at least `min_time_delta_ms` and at most
+// `max_time_delta_ms`.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FingerprintConfig {
+    pub min_time_delta_ms: u32,
+    // At most 20475 ms, the largest time delta a fingerprint can encode.
+    pub max_time_delta_ms: u32,
+    pub num_target_peaks: usize,
+}
 
-// Max 2 seconds diff between target and anchor when creating
-// fingerprints
-const MAX_TIME_DELTA_MS: u32 = 2000;
-const NUM_TARGET_PEAKS: usize = 5;
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        Self {
+            min_time_delta_ms: 50,
+            max_time_delta_ms: 2000,
+            num_target_peaks: 5,
+        }
+    }
+}
 
 // We define a fingerprint as a relationship between two peaks
 #[derive(Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Copy)]
@@ -39,7 +66,17 @@ impl Fingerprint {
         Self(encoded)
     }
 
-    #[allow(dead_code)]
+    // The raw encoded value, used by storage backends that key on plain integers.
+    pub fn to_bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    // The (lower frequency in Hz, higher frequency in Hz, time delta in ms) the fingerprint was
+    // generated from, at the resolution it was encoded with.
     pub fn decode(&self) -> (u32, u32, u32) {
         let freq1 = ((self.0 >> Self::FREQ1_SHIFT) & Self::FREQ_MASK) * 20;
         let freq2 = ((self.0 >> Self::FREQ2_SHIFT) & Self::FREQ_MASK) * 20;
@@ -49,138 +86,390 @@ impl Fingerprint {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct SongMetaData {
-    pub song_id: u32,
-    pub title: String,
+// The parameters fingerprints were generated with. Fingerprints generated with different
+// parameters do not line up, so databases can only be combined when these match. Parameters
+// recorded before the preprocessing and the frequency and magnitude scales were default to those
+// of the time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisParameters {
+    #[serde(default)]
+    pub preprocess: PreprocessConfig,
+    pub window_size: usize,
+    pub stride: usize,
+    pub sample_rate: f32,
+    #[serde(default)]
+    pub frequency_scale: FrequencyScale,
+    #[serde(default = "default_bands")]
+    pub bands: usize,
+    #[serde(default)]
+    pub min_frequency_hz: Option<f32>,
+    #[serde(default = "default_bins_per_octave")]
+    pub bins_per_octave: usize,
+    #[serde(default)]
+    pub magnitude_scale: MagnitudeScale,
+    pub peaks_per_window: usize,
+    pub min_time_delta_ms: u32,
+    pub max_time_delta_ms: u32,
+    pub num_target_peaks: usize,
 }
-// The fingerprint database maps a fingerprint to where it was found (song_id, time_offset)
+
+impl AnalysisParameters {
+    pub fn new(config: &AnalysisConfig) -> Self {
+        Self {
+            preprocess: config.preprocess,
+            window_size: config.spectrogram.window_size,
+            stride: config.spectrogram.stride,
+            sample_rate: config.spectrogram.sample_rate,
+            frequency_scale: config.spectrogram.frequency_scale,
+            bands: config.spectrogram.bands,
+            min_frequency_hz: config.spectrogram.min_frequency_hz,
+            bins_per_octave: config.spectrogram.bins_per_octave,
+            magnitude_scale: config.spectrogram.magnitude_scale,
+            peaks_per_window: config.peaks.peaks_per_window,
+            min_time_delta_ms: config.fingerprint.min_time_delta_ms,
+            max_time_delta_ms: config.fingerprint.max_time_delta_ms,
+            num_target_peaks: config.fingerprint.num_target_peaks,
+        }
+    }
+
+    // Fails if fingerprints generated with `found` would not line up with those generated with
+    // these parameters.
+    pub fn check(&self, found: &AnalysisParameters) -> Result<()> {
+        if self != found {
+            return Err(Error::ParameterMismatch {
+                expected: Box::new(*self),
+                found: Box::new(*found),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn default_bands() -> usize {
+    SpectrogramConfig::default().bands
+}
+
+fn default_bins_per_octave() -> usize {
+    SpectrogramConfig::default().bins_per_octave
+}
+
+// Every index starts with these bytes, followed by the version of its layout as a little-endian
+// u32. Indexes written before the header was introduced start straight with the encoded database,
+// and are migrated when loaded.
+const INDEX_MAGIC: &[u8; 8] = b"AFPINDEX";
+const INDEX_VERSION: u32 = 1;
+
+// The fingerprint database maps a fingerprint to where it was found (song_id, time_offset), with
+// the postings of each fingerprint held as a compressed `PostingList`.
+//
+// `songs` and `parameters` come first, see `IndexSummary`.
 #[derive(Serialize, Deserialize)]
 pub struct FingerprintDB {
-    pub database: HashMap<Fingerprint, Vec<(u32, u32)>>,
     pub songs: HashMap<u32, SongMetaData>,
+    // Unset until the first song is added.
+    pub parameters: Option<AnalysisParameters>,
+    pub database: HashMap<Fingerprint, PostingList>,
     pub total_fingerprints: usize,
 }
 
+// The leading part of an encoded `FingerprintDB`. Decoding only this avoids reading every posting
+// when all we need is the songs and parameters, e.g., for allocating the next song id.
+#[derive(Deserialize)]
+struct IndexHeader {
+    songs: HashMap<u32, SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+}
+
+// The layout of indexes written before the header, which held the postings of every fingerprint as
+// a plain list and only the title of every song.
+#[derive(Deserialize)]
+struct BaselineIndex {
+    database: HashMap<Fingerprint, Vec<(u32, u32)>>,
+    songs: HashMap<u32, BaselineSong>,
+    total_fingerprints: usize,
+}
+
+#[derive(Deserialize)]
+struct BaselineSong {
+    song_id: u32,
+    title: String,
+}
+
+impl BaselineIndex {
+    fn migrate(self) -> FingerprintDB {
+        // Those indexes were always generated with what are still the default parameters.
+        let config = AnalysisConfig::default();
+
+        FingerprintDB {
+            songs: self
+                .songs
+                .into_values()
+                .map(|song| {
+                    let metadata = SongMetaData {
+                        song_id: song.song_id,
+                        title: song.title,
+                        ..SongMetaData::default()
+                    };
+                    (metadata.song_id, metadata)
+                })
+                .collect(),
+            parameters: Some(AnalysisParameters::new(&config)),
+            database: self
+                .database
+                .into_iter()
+                .map(|(fingerprint, postings)| (fingerprint, PostingList::new(postings)))
+                .collect(),
+            total_fingerprints: self.total_fingerprints,
+        }
+    }
+}
+
+impl Default for FingerprintDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FingerprintDB {
     pub fn new() -> Self {
         Self {
             database: HashMap::new(),
             songs: HashMap::new(),
+            parameters: None,
             total_fingerprints: 0,
         }
     }
 
-    pub fn add_song(&mut self, metadata: SongMetaData, peaks: &[Peak], config: &SpectrogramConfig) {
+    // Merges the songs and postings of `other` into this database. Songs of `other` whose id is
+    // already taken are given new ids after the highest one in use. Returns the mapping from the
+    // song ids in `other` to the ids they were given in this database.
+    pub fn merge(&mut self, other: FingerprintDB) -> Result<HashMap<u32, u32>> {
+        if let Some(parameters) = &other.parameters {
+            self.check_parameters(parameters)?;
+        }
         log::info!(
-            "Adding song: {} with title: {}",
-            metadata.song_id,
-            metadata.title
+            "Merging database with {} songs and {} fingerprints",
+            other.songs.len(),
+            other.total_fingerprints
         );
-        let fingerprints = generate_fingerprints(peaks, &config);
-        for (fingerprint, time_offset) in fingerprints {
+
+        let mut next_song_id = self.next_song_id()?.max(other.next_song_id()?);
+        let mut other_song_ids: Vec<u32> = other.songs.keys().copied().collect();
+        other_song_ids.sort();
+
+        let mut song_id_map = HashMap::new();
+        for song_id in other_song_ids {
+            let new_song_id = if self.songs.contains_key(&song_id) {
+                next_song_id += 1;
+                next_song_id - 1
+            } else {
+                song_id
+            };
+            if new_song_id != song_id {
+                log::info!("Remapping song {} to {}", song_id, new_song_id);
+            }
+            song_id_map.insert(song_id, new_song_id);
+        }
+
+        for (fingerprint, postings) in other.database {
+            let remapped_postings = postings
+                .iter()
+                .map(|posting| {
+                    let (song_id, time_offset) = posting?;
+                    // Postings of songs missing from `other`'s metadata keep their id.
+                    let song_id = song_id_map.get(&song_id).copied().unwrap_or(song_id);
+                    Ok((song_id, time_offset))
+                })
+                .collect::<Result<_>>()?;
             self.database
                 .entry(fingerprint)
-                .or_insert_with(Vec::new)
-                .push((metadata.song_id, time_offset))
+                .or_default()
+                .extend(remapped_postings)?;
+        }
+        for (song_id, mut metadata) in other.songs {
+            metadata.song_id = song_id_map[&song_id];
+            self.songs.insert(metadata.song_id, metadata);
         }
+        self.total_fingerprints += other.total_fingerprints;
 
-        self.songs.insert(metadata.song_id, metadata);
-        self.total_fingerprints += self.database.len();
+        Ok(song_id_map)
     }
 
-    pub fn recognize_song(
-        &self,
-        peaks: &[Peak],
-        config: &SpectrogramConfig,
-    ) -> Option<(SongMetaData, MatchResult)> {
-        log::info!("Recognizing song");
-
-        let query_fingerprints = generate_fingerprints(peaks, config);
-        let total_query_fingerprints = query_fingerprints.len();
-        // Map (song_id, alignment_offset) to counter
-        let mut vote_counter: HashMap<(u32, u32), u32> = HashMap::new(); // (song_id, alignment_offset)
-
-        for (query_fingerprint, time_offset) in query_fingerprints {
-            let fingerprint_match = match self.database.get(&query_fingerprint) {
-                Some(fingerprint) => fingerprint,
-                None => continue,
-            };
-
-            for (song_id, offset) in fingerprint_match {
-                let alignment_offset = offset - time_offset; // Database time - query time
-                *vote_counter
-                    .entry((*song_id, alignment_offset))
-                    .or_insert(0) += 1
+    // Copies the given songs, along with their postings, into a new database with the same
+    // parameters. Song ids are kept as they are.
+    pub fn extract(&self, song_ids: &[u32]) -> Result<FingerprintDB> {
+        let mut extracted = FingerprintDB::new();
+        extracted.parameters = self.parameters;
+
+        for song_id in song_ids {
+            match self.songs.get(song_id) {
+                Some(metadata) => extracted.insert_song(metadata.clone())?,
+                None => {
+                    return Err(Error::InvalidInput(format!(
+                        "Song {song_id} is not in the database"
+                    )));
+                }
             }
         }
-
-        // Fetch the key corresponding to the highest number of votes
-        let result = vote_counter.iter().max_by_key(|(_key, value)| **value);
-
-        match result {
-            Some(((song_id, offset), votes)) => {
-                let confidence = *votes as f32 / total_query_fingerprints as f32;
-                let match_result = MatchResult::new(*song_id, confidence, *offset, *votes);
-                if let Some(metadata) = self.get_song_metadata_by_match_result(&match_result) {
-                    Some((metadata, match_result))
-                } else {
-                    return None;
-                }
+        for (fingerprint, postings) in self.database.iter() {
+            let mut extracted_postings = postings.clone();
+            extracted_postings.retain_songs(|song_id| extracted.songs.contains_key(&song_id))?;
+            if !extracted_postings.is_empty() {
+                extracted.total_fingerprints += extracted_postings.len();
+                extracted.database.insert(*fingerprint, extracted_postings);
             }
-            None => None,
         }
+        log::info!(
+            "Extracted {} songs and {} fingerprints",
+            extracted.songs.len(),
+            extracted.total_fingerprints
+        );
+
+        Ok(extracted)
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         log::info!(
             "Saving fingerprint database with {} songs and {} fingerprints",
             self.songs.len(),
             self.total_fingerprints
         );
 
-        let file = File::create(path)?;
-        let mut writer = BufWriter::new(file);
-        let bincode_config = bincode::config::standard();
-        bincode::serde::encode_into_std_write(self, &mut writer, bincode_config)?;
-
-        Ok(())
+        write_atomically(path.as_ref(), |writer| {
+            writer.write_all(INDEX_MAGIC)?;
+            writer.write_all(&INDEX_VERSION.to_le_bytes())?;
+            let bincode_config = bincode::config::standard();
+            bincode::serde::encode_into_std_write(self, writer, bincode_config)?;
+            Ok(())
+        })
     }
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        log::info!("Loading fingerprint database");
 
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-
-        let bincode_config = bincode::config::standard();
-        let db = bincode::serde::decode_from_reader(reader, bincode_config)?;
+    // Loads the main index and replays any segments appended to its journal since the last
+    // compaction.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let journal = Journal::for_database(&path);
+        let mut db = match Self::load_index(&path) {
+            Ok(db) => db,
+            // Until the first compaction, all songs live in the journal.
+            Err(Error::Io(err))
+                if err.kind() == io::ErrorKind::NotFound
+                    && !journal.segment_paths()?.is_empty() =>
+            {
+                Self::new()
+            }
+            Err(err) => return Err(err),
+        };
+        db.replay_journal(&journal)?;
 
         Ok(db)
     }
 
-    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        match Self::load(&path) {
-            Ok(db) => Ok(db),
-            Err(_) => {
+    // Like `load`, but starts a new database if there is no index yet. An index which cannot be
+    // read is an error rather than a reason to start over, which would lose every song in it.
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut db = match Self::load_index(&path) {
+            Ok(db) => db,
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
                 log::info!("Database not found, creating new one");
-                Ok(Self::new())
+                Self::new()
             }
+            Err(err) => return Err(err),
+        };
+        db.replay_journal(&Journal::for_database(&path))?;
+
+        Ok(db)
+    }
+
+    // Loads only the main index, ignoring the journal.
+    pub fn load_index<P: AsRef<Path>>(path: P) -> Result<Self> {
+        log::info!("Loading fingerprint database");
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let bincode_config = bincode::config::standard();
+        if Self::read_index_header(&mut reader)? {
+            return Ok(bincode::serde::decode_from_reader(reader, bincode_config)?);
+        }
+
+        log::info!("Migrating a database written without a header");
+        reader.rewind()?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        match bincode::serde::decode_from_slice::<BaselineIndex, _>(&bytes, bincode_config) {
+            Ok((index, read)) if read == bytes.len() => Ok(index.migrate()),
+            _ => Err(Error::Corruption(String::from(
+                "the file is not a fingerprint database",
+            ))),
         }
     }
 
-    pub(crate) fn get_song_metadata_by_match_result(
-        &self,
-        result: &MatchResult,
-    ) -> Option<SongMetaData> {
-        self.songs.get(&result.song_id).cloned()
+    // Reads the magic bytes and version at the start of an index. Returns false if they are
+    // missing, as in indexes written before them, and fails for versions we cannot read.
+    fn read_index_header<R: Read>(reader: &mut R) -> Result<bool> {
+        let mut header = [0; 12];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        if header[..8] != INDEX_MAGIC[..] {
+            return Ok(false);
+        }
+
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != INDEX_VERSION {
+            return Err(Error::Corruption(format!(
+                "version {version} of the index is not supported, only {INDEX_VERSION}"
+            )));
+        }
+        Ok(true)
+    }
+
+    // Writes the journal segments into the main index and clears the journal.
+    pub fn compact<P: AsRef<Path>>(path: P) -> Result<Self> {
+        log::info!("Compacting fingerprint database");
+        let journal = Journal::for_database(&path);
+        let db = Self::load_or_create(&path)?;
+
+        db.save(&path)?;
+        journal.clear()?;
+
+        Ok(db)
+    }
+
+    // Returns the songs and parameters of the main index, without decoding any postings.
+    pub fn index_summary<P: AsRef<Path>>(path: P) -> Result<IndexSummary> {
+        match File::open(&path) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                if !Self::read_index_header(&mut reader)? {
+                    let db = Self::load_index(path)?;
+                    return Ok(IndexSummary {
+                        song_ids: db.songs.into_keys().collect(),
+                        parameters: db.parameters,
+                    });
+                }
+                let bincode_config = bincode::config::standard();
+                let header: IndexHeader =
+                    bincode::serde::decode_from_reader(reader, bincode_config)?;
+                Ok(IndexSummary {
+                    song_ids: header.songs.into_keys().collect(),
+                    parameters: header.parameters,
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(IndexSummary::default()),
+            Err(err) => Err(err.into()),
+        }
     }
 }
 
-pub(crate) fn generate_fingerprints(
-    peaks: &[Peak],
-    config: &SpectrogramConfig,
-) -> Vec<(Fingerprint, u32)> {
+pub fn generate_fingerprints(peaks: &[Peak], config: &AnalysisConfig) -> Vec<(Fingerprint, u32)> {
     log::info!("Generating fingerprint");
+    let FingerprintConfig {
+        min_time_delta_ms,
+        max_time_delta_ms,
+        num_target_peaks,
+    } = config.fingerprint;
+    let config = &config.spectrogram;
     let mut fingerprints = Vec::new();
     let mut peak_indices: Vec<usize> = (0..peaks.len()).collect();
     peak_indices.sort_by_key(|&i| peaks[i].time_bin);
@@ -195,23 +484,23 @@ pub(crate) fn generate_fingerprints(
             let time_diff_ms =
                 ((target.time_seconds(config) - anchor.time_seconds(config)) * 1000.0) as u32;
 
-            if time_diff_ms > MAX_TIME_DELTA_MS {
+            if time_diff_ms > max_time_delta_ms {
                 break;
             }
 
-            if time_diff_ms >= 50 {
+            if time_diff_ms >= min_time_delta_ms {
                 valid_targets.push((target_i, time_diff_ms));
             }
         }
 
-        // Shuffle the valid targets, and take up to NUM_TARGET_PEAKS
+        // Shuffle the valid targets, and take up to num_target_peaks
         if !valid_targets.is_empty() {
             for j in 0..valid_targets.len() {
                 let k = fastrand::usize(j..valid_targets.len());
                 valid_targets.swap(j, k);
             }
 
-            let num_to_take = NUM_TARGET_PEAKS.min(valid_targets.len());
+            let num_to_take = num_target_peaks.min(valid_targets.len());
             for &(target_i, _time_diff) in valid_targets.iter().take(num_to_take) {
                 let target = &peaks[target_i];
                 let fingerprint = create_fingerprint(anchor, target, config);
@@ -240,16 +529,16 @@ fn create_fingerprint(anchor: &Peak, target: &Peak, config: &SpectrogramConfig)
     Fingerprint::new(f1, f2, td_ms)
 }
 
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MatchResult {
     pub song_id: u32,
     pub confidence: f32,  // 0.0 to 1.0
-    pub time_offset: u32, // Where in the original song (ms)
+    pub time_offset: i64, // Song time - query time, negative if the query starts first (ms)
     pub votes: u32,       // Number of matching fingerprints
 }
 
 impl MatchResult {
-    pub fn new(song_id: u32, confidence: f32, time_offset: u32, votes: u32) -> MatchResult {
+    pub fn new(song_id: u32, confidence: f32, time_offset: i64, votes: u32) -> MatchResult {
         Self {
             song_id,
             confidence,
@@ -257,4 +546,196 @@ impl MatchResult {
             votes,
         }
     }
+
+    // The time offset is the time in the song minus the time in the query, which is negative when
+    // the query starts before the song does. Split into the offset into the song the query starts
+    // at, and the offset into the query the song starts at, one of which is 0.
+    pub fn song_offset_ms(&self) -> u32 {
+        self.time_offset.max(0) as u32
+    }
+
+    pub fn query_offset_ms(&self) -> u32 {
+        (-self.time_offset).max(0) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::{
+        config::AnalysisConfig,
+        error::Error,
+        fft::{FrequencyScale, MagnitudeScale, SpectrogramConfig},
+        fingerprint::{AnalysisParameters, Fingerprint, FingerprintDB},
+        metadata::SongMetaData,
+        peaks::Peak,
+        preprocess::PreprocessConfig,
+        segment::Segment,
+        storage::FingerprintStore,
+    };
+
+    fn database(titles: &[&str], config: &AnalysisConfig) -> FingerprintDB {
+        let mut db = FingerprintDB::new();
+        for (song_id, title) in titles.iter().enumerate() {
+            let peaks: Vec<Peak> = (0..50)
+                .map(|i| Peak::new(i * 4, 10 + (i * 7 + song_id * 3) % 200, 1.0))
+                .collect();
+            let metadata = SongMetaData {
+                song_id: song_id as u32,
+                title: String::from(*title),
+                ..SongMetaData::default()
+            };
+            db.add_segment(Segment::new(metadata, &peaks, config))
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn merge_and_extract() {
+        let config = AnalysisConfig::default();
+        let mut db = database(&["a", "b"], &config);
+        let other = database(&["c", "d", "e"], &config);
+        let total_fingerprints = db.total_fingerprints + other.total_fingerprints;
+
+        // Songs 0 and 1 of `other` conflict and are moved after its own highest id.
+        let song_id_map = db.merge(other).unwrap();
+        assert_eq!(song_id_map[&0], 3);
+        assert_eq!(song_id_map[&1], 4);
+        assert_eq!(song_id_map[&2], 2);
+        assert_eq!(db.songs[&3].title, "c");
+        assert_eq!(db.total_fingerprints, total_fingerprints);
+
+        let extracted = db.extract(&[3, 2]).unwrap();
+        assert_eq!(extracted.songs.len(), 2);
+        assert_eq!(extracted.parameters, db.parameters);
+        assert!(
+            extracted
+                .postings()
+                .unwrap()
+                .all(|posting| matches!(posting, Ok((_, 2 | 3, _))))
+        );
+
+        let mismatched = database(
+            &["f"],
+            &AnalysisConfig {
+                spectrogram: SpectrogramConfig {
+                    stride: 256,
+                    ..config.spectrogram
+                },
+                ..config
+            },
+        );
+        assert!(db.merge(mismatched).is_err());
+    }
+
+    #[test]
+    fn mismatched_analysis() {
+        let config = AnalysisConfig::default();
+        let db = database(&["a"], &config);
+        let peaks: Vec<Peak> = (0..50).map(|i| Peak::new(i * 4, 10 + i * 7, 1.0)).collect();
+
+        for spectrogram in [
+            SpectrogramConfig {
+                frequency_scale: FrequencyScale::Mel,
+                ..config.spectrogram
+            },
+            SpectrogramConfig {
+                magnitude_scale: MagnitudeScale::Db,
+                ..config.spectrogram
+            },
+        ] {
+            let query_config = AnalysisConfig {
+                spectrogram,
+                ..config
+            };
+            assert!(matches!(
+                db.recognize_song(&peaks, &query_config),
+                Err(Error::ParameterMismatch { .. })
+            ));
+        }
+
+        // Songs and queries must be conditioned alike.
+        let preprocessed = database(
+            &["b"],
+            &AnalysisConfig {
+                preprocess: PreprocessConfig {
+                    dc_block: true,
+                    ..PreprocessConfig::default()
+                },
+                ..config
+            },
+        );
+        assert!(matches!(
+            preprocessed.recognize_song(&peaks, &config),
+            Err(Error::ParameterMismatch { .. })
+        ));
+
+        // Parameters recorded without the preprocessing and the scales are those of the default
+        // linear spectrogram of unconditioned samples.
+        let mut recorded = serde_json::to_value(AnalysisParameters::new(&config)).unwrap();
+        for field in [
+            "preprocess",
+            "frequency_scale",
+            "bands",
+            "min_frequency_hz",
+            "bins_per_octave",
+            "magnitude_scale",
+        ] {
+            recorded.as_object_mut().unwrap().remove(field);
+        }
+        let parameters: AnalysisParameters = serde_json::from_value(recorded).unwrap();
+        assert_eq!(parameters, AnalysisParameters::new(&config));
+    }
+
+    #[test]
+    fn index_header_and_migration() {
+        let path = crate::temp_path("index.db");
+        let config = AnalysisConfig::default();
+        let db = database(&["a", "b"], &config);
+        db.save(&path).unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(b"AFPINDEX"));
+        let loaded = FingerprintDB::load_or_create(&path).unwrap();
+        assert_eq!(loaded.total_fingerprints, db.total_fingerprints);
+        assert_eq!(loaded.parameters, db.parameters);
+
+        // A truncated index is corrupt, and is neither replaced nor compacted away.
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        for result in [
+            FingerprintDB::load_or_create(&path),
+            FingerprintDB::compact(&path),
+        ] {
+            assert!(matches!(result, Err(Error::Corruption(_))));
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            bytes.len() / 2
+        );
+
+        // Indexes written before the header are migrated, with the parameters they were
+        // generated with.
+        let postings = HashMap::from([(Fingerprint::new(1000, 2000, 100), vec![(0u32, 40u32)])]);
+        let songs = HashMap::from([(0u32, (0u32, String::from("baseline")))]);
+        let baseline =
+            bincode::serde::encode_to_vec((postings, songs, 1usize), bincode::config::standard())
+                .unwrap();
+        std::fs::write(&path, baseline).unwrap();
+        let migrated = FingerprintDB::load(&path).unwrap();
+        assert_eq!(migrated.songs[&0].title, "baseline");
+        assert_eq!(
+            migrated.lookup(Fingerprint::new(1000, 2000, 100)).unwrap()[..],
+            [(0, 40)]
+        );
+        assert_eq!(migrated.parameters.unwrap().sample_rate, 48000.0);
+        assert_eq!(FingerprintDB::index_summary(&path).unwrap().song_ids, [0]);
+
+        std::fs::write(&path, b"not a database").unwrap();
+        assert!(matches!(
+            FingerprintDB::load(&path),
+            Err(Error::Corruption(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,415 @@
+use std::{borrow::Cow, path::Path};
+
+use crate::{
+    audio::{self, RawFormat, TimeRange},
+    config::AnalysisConfig,
+    error::{Error, InsufficientAudio, Result},
+    fft::{self, Spectrogram},
+    fingerprint::{Fingerprint, MatchResult, generate_fingerprints},
+    metadata::SongMetaData,
+    peaks::{self, Peak},
+    preprocess,
+    segment::Segment,
+    storage::FingerprintStore,
+};
+
+// Turns audio into fingerprints with a fixed configuration, exposing every step of the way: the
+// spectrogram, its peaks, and the fingerprints hashed from pairs of peaks. Audio is given either
+// as a path to an audio file, or as mono samples in [-1, 1] at the configured sample rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Fingerprinter {
+    config: AnalysisConfig,
+    // The format of files holding headerless PCM, when those are what is loaded.
+    raw: Option<RawFormat>,
+    // Queries shorter than this are not recognized, as too few fingerprints match by chance.
+    min_query_seconds: f32,
+}
+
+// The shortest query recognized unless configured otherwise, in seconds.
+pub const DEFAULT_MIN_QUERY_SECONDS: f32 = 1.0;
+
+// Everything computed while fingerprinting a piece of audio.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub spectrogram: Spectrogram,
+    pub peaks: Vec<Peak>,
+    // Every fingerprint along with its offset into the audio in ms.
+    pub fingerprints: Vec<(Fingerprint, u32)>,
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Self {
+        Self::new(AnalysisConfig::default())
+    }
+}
+
+impl Fingerprinter {
+    pub fn new(config: AnalysisConfig) -> Self {
+        Self {
+            config,
+            raw: None,
+            min_query_seconds: DEFAULT_MIN_QUERY_SECONDS,
+        }
+    }
+
+    // Loads every file as headerless PCM in `format`, rather than detecting its format.
+    pub fn with_raw(mut self, format: RawFormat) -> Self {
+        self.raw = Some(format);
+        self
+    }
+
+    pub fn with_min_query_seconds(mut self, seconds: f32) -> Self {
+        self.min_query_seconds = seconds;
+        self
+    }
+
+    pub fn config(&self) -> &AnalysisConfig {
+        &self.config
+    }
+
+    pub fn raw(&self) -> Option<&RawFormat> {
+        self.raw.as_ref()
+    }
+
+    // Loads an audio file, or headerless PCM if a raw format is set, as mono samples at the
+    // configured sample rate, ready to be fingerprinted. Audio at any other rate is resampled.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<Vec<f32>> {
+        self.load_range(path, &TimeRange::default())
+    }
+
+    // Like `load`, decoding only `range` of the file.
+    pub fn load_range<P: AsRef<Path>>(&self, path: P, range: &TimeRange) -> Result<Vec<f32>> {
+        range.validate()?;
+        let audio = match &self.raw {
+            Some(format) => audio::load_raw(path.as_ref(), format, range)?,
+            None => audio::load_audio(path.as_ref(), range)?,
+        };
+
+        let sample_rate = self.config.spectrogram.sample_rate;
+        if audio.sample_rate as f32 == sample_rate {
+            return Ok(audio.samples);
+        }
+        log::debug!(
+            "Resampling from {} Hz to {sample_rate} Hz",
+            audio.sample_rate
+        );
+        Ok(preprocess::resample(
+            &audio.samples,
+            audio.sample_rate as f32,
+            sample_rate,
+        ))
+    }
+
+    // Reads the metadata of a file loaded by `load`.
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<SongMetaData> {
+        match &self.raw {
+            Some(format) => SongMetaData::from_raw(path, format),
+            None => SongMetaData::from_file(path),
+        }
+    }
+
+    // Conditions samples as configured, see `PreprocessConfig`, before their spectrogram is
+    // computed. Samples are only copied when some step is enabled.
+    pub fn preprocess<'a>(&self, samples: &'a [f32]) -> Cow<'a, [f32]> {
+        let config = &self.config.preprocess;
+        if !config.is_enabled() {
+            return Cow::Borrowed(samples);
+        }
+        let sample_rate = self.config.spectrogram.sample_rate;
+        Cow::Owned(preprocess::preprocess(samples, config, sample_rate))
+    }
+
+    // Fails with `InsufficientAudio::Empty` when no samples are left once preprocessed. Audio
+    // shorter than an FFT window is zero-padded to one.
+    pub fn spectrogram(&self, samples: &[f32]) -> Result<Spectrogram> {
+        let samples = self.preprocess(samples);
+        if samples.is_empty() {
+            return Err(Error::InsufficientAudio(InsufficientAudio::Empty));
+        }
+
+        Ok(fft::compute_spectrogram(&samples, self.config.spectrogram))
+    }
+
+    pub fn peaks(&self, spectrogram: &Spectrogram) -> Vec<Peak> {
+        peaks::extract_peaks(spectrogram, &self.config.peaks)
+    }
+
+    pub fn fingerprints(&self, peaks: &[Peak]) -> Vec<(Fingerprint, u32)> {
+        generate_fingerprints(peaks, &self.config)
+    }
+
+    pub fn analyze(&self, samples: &[f32]) -> Result<Analysis> {
+        let spectrogram = self.spectrogram(samples)?;
+        let peaks = self.peaks(&spectrogram);
+        let fingerprints = self.fingerprints(&peaks);
+
+        Ok(Analysis {
+            spectrogram,
+            peaks,
+            fingerprints,
+        })
+    }
+
+    pub fn analyze_file<P: AsRef<Path>>(&self, path: P) -> Result<Analysis> {
+        self.analyze(&self.load(path)?)
+    }
+
+    // Only the peaks, which is all that is needed to index or recognize a song. Fails with
+    // `InsufficientAudio::Silent` when there are none, as nothing could ever match the song.
+    pub fn extract_peaks(&self, samples: &[f32]) -> Result<Vec<Peak>> {
+        let peaks = self.peaks(&self.spectrogram(samples)?);
+        if peaks.is_empty() {
+            return Err(Error::InsufficientAudio(InsufficientAudio::Silent));
+        }
+        Ok(peaks)
+    }
+
+    // Like `extract_peaks`, for a query, which fails with `InsufficientAudio::TooShort` when it
+    // is shorter than the minimum query length.
+    pub fn query_peaks(&self, samples: &[f32]) -> Result<Vec<Peak>> {
+        let required =
+            (self.min_query_seconds * self.config.spectrogram.sample_rate).ceil() as usize;
+        if samples.is_empty() {
+            return Err(Error::InsufficientAudio(InsufficientAudio::Empty));
+        }
+        if samples.len() < required {
+            return Err(Error::InsufficientAudio(InsufficientAudio::TooShort {
+                samples: samples.len(),
+                required,
+            }));
+        }
+        self.extract_peaks(samples)
+    }
+}
+
+// Indexes songs into, and recognizes queries against, a fingerprint store held by the caller,
+// such as a `FingerprintDB` or one opened with `open_store`. Songs added to a store with a
+// journal are only held in memory, and it is up to the caller to save the store.
+pub struct Recognizer<S: FingerprintStore> {
+    store: S,
+    fingerprinter: Fingerprinter,
+}
+
+impl<S: FingerprintStore> Recognizer<S> {
+    pub fn new(store: S, config: AnalysisConfig) -> Self {
+        Self::with_fingerprinter(store, Fingerprinter::new(config))
+    }
+
+    pub fn with_fingerprinter(store: S, fingerprinter: Fingerprinter) -> Self {
+        Self {
+            store,
+            fingerprinter,
+        }
+    }
+
+    pub fn fingerprinter(&self) -> &Fingerprinter {
+        &self.fingerprinter
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    // Fingerprints the samples of a song and adds it to the store under the next free song id,
+    // which is returned. The song id in `metadata` is ignored.
+    pub fn index(&mut self, mut metadata: SongMetaData, samples: &[f32]) -> Result<u32> {
+        let peaks = self.fingerprinter.extract_peaks(samples)?;
+        metadata.song_id = self.store.next_song_id()?;
+        let song_id = metadata.song_id;
+
+        let segment = Segment::new(metadata, &peaks, self.fingerprinter.config());
+        self.store.add_segment(segment)?;
+
+        Ok(song_id)
+    }
+
+    // Like `index`, with the metadata read from the audio file.
+    pub fn index_file<P: AsRef<Path>>(&mut self, path: P) -> Result<u32> {
+        let samples = self.fingerprinter.load(&path)?;
+        let mut metadata = self.fingerprinter.metadata(&path)?;
+        metadata.set_ingested_now();
+
+        self.index(metadata, &samples)
+    }
+
+    // Returns the song in the store best matching the samples, if any matches at all.
+    pub fn recognize(&self, samples: &[f32]) -> Result<Option<(SongMetaData, MatchResult)>> {
+        let peaks = self.fingerprinter.query_peaks(samples)?;
+
+        self.store
+            .recognize_song(&peaks, self.fingerprinter.config())
+    }
+
+    pub fn recognize_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<(SongMetaData, MatchResult)>> {
+        self.recognize(&self.fingerprinter.load(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::f32::consts::PI;
+
+    use crate::{
+        Error, InsufficientAudio,
+        config::AnalysisConfig,
+        fft::SpectrogramConfig,
+        fingerprint::FingerprintDB,
+        fingerprinter::{Fingerprinter, Recognizer},
+        metadata::SongMetaData,
+    };
+
+    // A few seconds of a melody, with a different tone every quarter second.
+    fn melody(seed: usize, config: &SpectrogramConfig) -> Vec<f32> {
+        let tone_length = config.sample_rate as usize / 4;
+        (0..tone_length * 16)
+            .map(|i| {
+                let tone = (i / tone_length * 7 + seed * 13) % 40;
+                let frequency = 300.0 + tone as f32 * 90.0;
+                (2.0 * PI * frequency * i as f32 / config.sample_rate).sin() * 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_and_recognize_samples() {
+        let analysis_config = AnalysisConfig::default();
+        let config = analysis_config.spectrogram;
+        let fingerprinter = Fingerprinter::new(analysis_config);
+
+        let analysis = fingerprinter.analyze(&melody(0, &config)).unwrap();
+        assert!(!analysis.spectrogram.data.is_empty());
+        assert!(!analysis.peaks.is_empty());
+        assert!(!analysis.fingerprints.is_empty());
+
+        // Audio shorter than a window is zero-padded to one, while no audio or silence leaves
+        // nothing to fingerprint.
+        let short = fingerprinter.analyze(&melody(0, &config)[..100]).unwrap();
+        assert_eq!(short.spectrogram.data.len(), 1);
+        assert!(!short.peaks.is_empty());
+        let insufficient = |result| match result {
+            Err(Error::InsufficientAudio(reason)) => reason,
+            result => panic!("{result:?}"),
+        };
+        assert_eq!(
+            insufficient(fingerprinter.extract_peaks(&[])),
+            InsufficientAudio::Empty
+        );
+        assert_eq!(
+            insufficient(fingerprinter.extract_peaks(&[0.0; 10000])),
+            InsufficientAudio::Silent
+        );
+
+        let mut recognizer = Recognizer::new(FingerprintDB::new(), analysis_config);
+        for seed in 0..3 {
+            let metadata = SongMetaData {
+                title: format!("Melody {seed}"),
+                ..SongMetaData::default()
+            };
+            let song_id = recognizer.index(metadata, &melody(seed, &config)).unwrap();
+            assert_eq!(song_id, seed as u32);
+        }
+
+        // A query starting a second into the second song.
+        let query = &melody(1, &config)[config.sample_rate as usize..];
+        let (metadata, result) = recognizer.recognize(query).unwrap().unwrap();
+        assert_eq!(metadata.title, "Melody 1");
+        assert_eq!(result.song_id, 1);
+
+        // Half a second is shorter than the shortest query.
+        let short_query = &query[..config.sample_rate as usize / 2];
+        assert_eq!(
+            insufficient(recognizer.recognize(short_query).map(|_| Vec::new())),
+            InsufficientAudio::TooShort {
+                samples: 24000,
+                required: 48000
+            }
+        );
+        assert_eq!(recognizer.into_store().songs.len(), 3);
+    }
+
+    #[test]
+    fn load_other_sample_rates() {
+        let analysis_config = AnalysisConfig::default();
+        let config = analysis_config.spectrogram;
+        let mut recognizer = Recognizer::new(FingerprintDB::new(), analysis_config);
+        for seed in 0..3 {
+            recognizer
+                .index(SongMetaData::default(), &melody(seed, &config))
+                .unwrap();
+        }
+
+        // The second song as files at 44.1 and 22.05 kHz, loaded at the configured 48 kHz.
+        for sample_rate in [44100, 22050] {
+            let file_config = SpectrogramConfig {
+                sample_rate: sample_rate as f32,
+                ..config
+            };
+            let path = crate::temp_path(&format!("rate_{sample_rate}.wav"));
+            let spec = WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            };
+            let song = melody(1, &file_config);
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            for &sample in &song {
+                writer.write_sample((sample * 32767.0) as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+
+            let samples = recognizer.fingerprinter().load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let expected = song.len() as f32 * config.sample_rate / sample_rate as f32;
+            assert_eq!(samples.len(), expected.round() as usize);
+
+            let (_, result) = recognizer.recognize(&samples).unwrap().unwrap();
+            assert_eq!(result.song_id, 1);
+        }
+    }
+
+    #[test]
+    fn load_44100_hz_wav() {
+        // A second of a 1 kHz tone at 44.1 kHz, loaded at the default 48 kHz.
+        let path = crate::temp_path("tone_44100.wav");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..44100 {
+            let sample = (2.0 * PI * 1000.0 * i as f32 / 44100.0).sin() * 0.5;
+            writer.write_sample((sample * 32767.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let samples = Fingerprinter::new(AnalysisConfig::default())
+            .load(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 48000);
+
+        // Still a 1 kHz tone at the same level: a thousand rising zero crossings in the second.
+        let rising = samples
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        assert!((999..=1001).contains(&rising), "{rising}");
+        let peak = samples.iter().fold(0.0f32, |peak, s| s.abs().max(peak));
+        assert!((peak - 0.5).abs() < 0.01, "{peak}");
+    }
+}
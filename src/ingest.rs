@@ -0,0 +1,340 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    Backend,
+    config::AnalysisConfig,
+    fingerprint::{AnalysisParameters, FingerprintDB},
+    metadata::SongMetaData,
+    peaks::Peak,
+    segment::{Journal, Segment},
+    storage::{FingerprintStore, MmapStore, ShardedStore, SqliteStore, StoreResult},
+};
+
+// The metadata and peaks of a song, ready to be fingerprinted and written.
+pub type AnalyzedSong = (SongMetaData, Vec<Peak>);
+
+// Where analyzed songs are written: the journal of the backend, or straight into the SQLite
+// store. Song ids and parameters are read once when opening, so writing many songs does not
+// re-read the journal for every one of them.
+pub enum SongWriter {
+    Journal {
+        journal: Journal,
+        next_song_id: u32,
+        // Those of the index, or of the journal if the index has none yet.
+        parameters: Option<AnalysisParameters>,
+    },
+    Sqlite(SqliteStore),
+}
+
+impl SongWriter {
+    pub fn open(backend: Backend, db_path: &Path) -> StoreResult<Self> {
+        if backend.uses_journal() {
+            let journal = Journal::for_database(db_path);
+            let index = match backend {
+                Backend::Mmap => MmapStore::index_summary(db_path)?,
+                Backend::Sharded => ShardedStore::index_summary(db_path)?,
+                _ => FingerprintDB::index_summary(db_path)?,
+            };
+            let parameters = match index.parameters {
+                Some(parameters) => Some(parameters),
+                None => journal.summary()?.parameters,
+            };
+            let next_song_id = journal.next_song_id(index.song_ids)?;
+
+            Ok(Self::Journal {
+                journal,
+                next_song_id,
+                parameters,
+            })
+        } else {
+            Ok(Self::Sqlite(SqliteStore::open(db_path)?))
+        }
+    }
+
+    // Assigns the song its id and writes it along with its fingerprints, returning the id.
+    pub fn write(
+        &mut self,
+        metadata: SongMetaData,
+        peaks: &[Peak],
+        config: &AnalysisConfig,
+    ) -> StoreResult<u32> {
+        let song_ids = self.write_batch(vec![(metadata, peaks.to_vec())], config)?;
+        Ok(song_ids[0])
+    }
+
+    // Assigns the songs consecutive ids and writes them as a single segment, returning the ids.
+    pub fn write_batch(
+        &mut self,
+        songs: Vec<AnalyzedSong>,
+        config: &AnalysisConfig,
+    ) -> StoreResult<Vec<u32>> {
+        // Fail before writing anything, rather than leaving a segment behind which makes the
+        // database impossible to load.
+        let parameters = AnalysisParameters::new(config);
+        let existing = match self {
+            Self::Journal { parameters, .. } => *parameters,
+            Self::Sqlite(store) => store.parameters()?,
+        };
+        if let Some(existing) = existing {
+            existing.check(&parameters)?;
+        }
+
+        let first_song_id = match self {
+            Self::Journal { next_song_id, .. } => *next_song_id,
+            Self::Sqlite(store) => store.next_song_id()?,
+        };
+
+        let mut batch: Option<Segment> = None;
+        let mut song_ids = Vec::new();
+        for (song_id, (mut metadata, peaks)) in (first_song_id..).zip(songs) {
+            metadata.song_id = song_id;
+            let segment = Segment::new(metadata, &peaks, config);
+            match &mut batch {
+                Some(batch) => batch.append(segment)?,
+                None => batch = Some(segment),
+            }
+            song_ids.push(song_id);
+        }
+        let Some(segment) = batch else {
+            return Ok(song_ids);
+        };
+        log::debug!(
+            "Generated {} fingerprints for {} songs",
+            segment.postings.len(),
+            segment.songs.len()
+        );
+
+        match self {
+            Self::Journal {
+                journal,
+                next_song_id,
+                parameters: existing,
+            } => {
+                journal.append(&segment)?;
+                *next_song_id += song_ids.len() as u32;
+                *existing = Some(parameters);
+            }
+            Self::Sqlite(store) => store.add_segment(segment)?,
+        }
+
+        Ok(song_ids)
+    }
+}
+
+// Records which rows of a manifest were ingested, so an interrupted ingestion can pick up where
+// it stopped. Lives next to the manifest in `<manifest>.checkpoint`, holding the `FileStamp` and
+// path of every ingested row on its own line, separated by tabs. Each line is appended and flushed
+// once the song was written, so a crash can at most lose the record of the one song being written
+// at the time, which is then ingested again on resuming.
+pub struct Checkpoint {
+    path: PathBuf,
+    completed: HashMap<String, FileStamp>,
+    file: File,
+}
+
+// The size and modification time of a file, which tell a file replaced or edited since it was
+// ingested from the one that was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    // Nanoseconds since the Unix epoch, or 0 where the platform does not record it.
+    pub modified: u128,
+}
+
+impl FileStamp {
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_nanos());
+
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+impl Checkpoint {
+    pub fn for_manifest<P: AsRef<Path>>(manifest_path: P) -> PathBuf {
+        let mut path = OsString::from(manifest_path.as_ref());
+        path.push(".checkpoint");
+        PathBuf::from(path)
+    }
+
+    // Opens the checkpoint of a manifest, creating it if needed. With `restart`, rows recorded by
+    // previous runs are forgotten.
+    pub fn open<P: AsRef<Path>>(manifest_path: P, restart: bool) -> Result<Self, io::Error> {
+        let path = Self::for_manifest(manifest_path);
+        if restart && path.exists() {
+            fs::remove_file(&path)?;
+        }
+
+        let mut completed = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // A line cut short by a crash is left out, so its row is ingested again.
+                    let line = line?;
+                    let mut fields = line.splitn(3, '\t');
+                    let (Some(size), Some(modified), Some(key)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        continue;
+                    };
+                    if let (Ok(size), Ok(modified)) = (size.parse(), modified.parse()) {
+                        completed.insert(String::from(key), FileStamp { size, modified });
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        if !completed.is_empty() {
+            log::info!(
+                "Resuming from checkpoint {:?} with {} ingested rows",
+                path,
+                completed.len()
+            );
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            completed,
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Whether the row with `key` was ingested from a file with the same `stamp`.
+    pub fn contains(&self, key: &str, stamp: &FileStamp) -> bool {
+        self.completed.get(key) == Some(stamp)
+    }
+
+    // Whether the row with `key` was ingested, whatever its file was like at the time.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.completed.contains_key(key)
+    }
+
+    pub fn record(&mut self, key: &str, stamp: FileStamp) -> Result<(), io::Error> {
+        writeln!(self.file, "{}\t{}\t{key}", stamp.size, stamp.modified)?;
+        self.file.flush()?;
+        self.file.sync_data()?;
+        self.completed.insert(String::from(key), stamp);
+
+        Ok(())
+    }
+}
+
+// The outcome of ingesting a manifest or analyzing a directory.
+#[derive(Debug, Default, Serialize)]
+pub struct IngestReport {
+    // Where progress is recorded, for resuming the ingestion.
+    pub checkpoint: Option<PathBuf>,
+    pub rows: usize,
+    pub ingested: Vec<IngestedRow>,
+    // Rows already ingested by a previous run, according to the checkpoint.
+    pub skipped: usize,
+    pub failed: Vec<FailedRow>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestedRow {
+    // Counting from 1, excluding any header.
+    pub row: usize,
+    pub path: String,
+    pub song_id: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedRow {
+    pub row: usize,
+    pub path: String,
+    pub error: String,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        Backend,
+        config::AnalysisConfig,
+        error::Error,
+        ingest::{Checkpoint, FileStamp, SongWriter},
+        metadata::SongMetaData,
+        peaks::Peak,
+        segment::Journal,
+    };
+
+    #[test]
+    fn checkpoint_resume() {
+        let manifest_path = crate::temp_path("ingest.csv");
+
+        let stamp = |size| FileStamp {
+            size,
+            modified: 1_700_000_000_000_000_000,
+        };
+
+        let mut checkpoint = Checkpoint::open(&manifest_path, true).unwrap();
+        checkpoint.record("a.wav", stamp(10)).unwrap();
+        checkpoint.record("b\tc.wav", stamp(20)).unwrap();
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::open(&manifest_path, false).unwrap();
+        assert!(checkpoint.contains("a.wav", &stamp(10)));
+        assert!(checkpoint.contains("b\tc.wav", &stamp(20)));
+        assert!(!checkpoint.contains("c.wav", &stamp(10)));
+        // The file of a row was replaced since it was ingested.
+        assert!(!checkpoint.contains("a.wav", &stamp(11)));
+        assert!(checkpoint.contains_key("a.wav"));
+
+        let checkpoint = Checkpoint::open(&manifest_path, true).unwrap();
+        assert!(!checkpoint.contains_key("a.wav"));
+        std::fs::remove_file(checkpoint.path()).unwrap();
+    }
+
+    #[test]
+    fn mismatched_parameters() {
+        let db_path = crate::temp_path("mismatch.db");
+        let peaks: Vec<Peak> = (0..50)
+            .map(|i| Peak::new(i * 4, 10 + (i * 7) % 200, 1.0))
+            .collect();
+        let config = AnalysisConfig::default();
+        let mut mismatched = config;
+        mismatched.spectrogram.sample_rate = 44100.0;
+
+        let mut writer = SongWriter::open(Backend::Memory, &db_path).unwrap();
+        writer
+            .write(SongMetaData::default(), &peaks, &config)
+            .unwrap();
+
+        // Both the open writer and one opened afterwards refuse the song, without writing it.
+        let mut reopened = SongWriter::open(Backend::Memory, &db_path).unwrap();
+        for writer in [&mut writer, &mut reopened] {
+            assert!(matches!(
+                writer.write(SongMetaData::default(), &peaks, &mismatched),
+                Err(Error::ParameterMismatch { .. })
+            ));
+        }
+        let journal = Journal::for_database(&db_path);
+        assert_eq!(journal.segment_paths().unwrap().len(), 1);
+
+        journal.clear().unwrap();
+        let mut journal_path = db_path.into_os_string();
+        journal_path.push(".journal");
+        std::fs::remove_dir(journal_path).unwrap();
+    }
+}
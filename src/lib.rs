@@ -1,50 +1,687 @@
-use crate::fingerprint::{MatchResult, SongMetaData};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use crate::{
+    ingest::{AnalyzedSong, Checkpoint, FailedRow, FileStamp, IngestedRow, SongWriter},
+    segment::Journal,
+};
 
 mod audio;
+mod batch;
+mod config;
+mod degrade;
 mod error;
+mod evaluate;
+mod export;
 mod fft;
+mod filterbank;
 mod fingerprint;
+mod fingerprinter;
+mod ingest;
+mod metadata;
 mod peaks;
+mod postings;
+mod preprocess;
+mod render;
+mod scan;
+mod segment;
+mod storage;
+
+pub use audio::{Audio, AudioFormat, RawEncoding, RawFormat, TimeRange, load_audio, load_raw};
+pub use batch::{QueryResult, QueryTimings, find_queries};
+pub use config::{AnalysisConfig, Config};
+pub use degrade::{DegradeOptions, DegradedQuery, Distortion};
+pub use error::{Error, InsufficientAudio, Result};
+pub use evaluate::{
+    Confusion, CurvePoint, DEFAULT_THRESHOLDS, EvaluatedQuery, Evaluation, LabelledQuery,
+    ThresholdMetrics, load_labels,
+};
+pub use export::{
+    ExportData, ExportFormat, FingerprintRecord, PeakRecord, export_fingerprints, export_peaks,
+    export_spectrogram,
+};
+pub use fft::{
+    FrequencyScale, MagnitudeScale, Spectrogram, SpectrogramConfig, compute_spectrogram,
+};
+pub use fingerprint::{
+    AnalysisParameters, Fingerprint, FingerprintConfig, FingerprintDB, MatchResult,
+    generate_fingerprints,
+};
+pub use fingerprinter::{Analysis, DEFAULT_MIN_QUERY_SECONDS, Fingerprinter, Recognizer};
+pub use ingest::IngestReport;
+pub use metadata::{MetadataEntry, MetadataManifest, SongMetaData};
+pub use peaks::{Peak, PeakConfig, extract_peaks};
+pub use postings::{PostingIter, PostingList};
+pub use preprocess::{Normalization, PreprocessConfig, preprocess};
+pub use render::{ColorMap, Image, Inspection, RenderOptions, aligned_peaks, render_spectrogram};
+pub use scan::ScanOptions;
+pub use storage::{
+    Backend, FingerprintStore, MmapStore, ShardedStore, SqliteStore, Votes, open_store,
+};
+
+// Fingerprints a song and adds it to the database. For backends with a journal, the song is
+// appended to the journal and the main index is left untouched until the next call to
+// `compact_database`. Metadata given for the song in `manifest` takes precedence over that read
+// from the file. Only `range` of the song is fingerprinted, and its duration is that of the range.
+pub fn analyze_song(
+    song_path: &str,
+    config: &Config,
+    manifest: Option<&MetadataManifest>,
+    range: &TimeRange,
+) -> Result<u32> {
+    let mut writer = SongWriter::open(config.backend, &config.db_path())?;
+    let entry = match manifest {
+        Some(manifest) => manifest.get(song_path)?,
+        None => None,
+    };
 
-pub fn analyze_song(song_path: &str) {
-    let mut db = fingerprint::FingerprintDB::load_or_create("audio_fingerprint.db")
-        .expect("Unable to create database");
+    analyze_into(
+        &mut writer,
+        Path::new(song_path),
+        entry,
+        &config.fingerprinter(),
+        range,
+    )
+}
 
-    log::debug!("Adding {} to song database", song_path);
-    let samples = audio::load_wav(song_path).expect("Unable to read wav file");
-    let config = fft::SpectrogramConfig::default();
-    let spectrogram = fft::compute_spectrogram(&samples, config);
-    let peaks = peaks::extract_peaks(&spectrogram);
+// Ingests every row of a CSV or JSON Lines catalogue manifest, see `MetadataManifest`. A row
+// which fails is reported and skipped, rather than stopping the ingestion. Rows already ingested
+// by an earlier, interrupted run over the same manifest are skipped, unless `restart` is set.
+pub fn ingest_manifest(
+    manifest_path: &Path,
+    config: &Config,
+    restart: bool,
+) -> Result<IngestReport> {
+    let manifest = MetadataManifest::load(manifest_path)?;
+    let mut checkpoint = Checkpoint::open(manifest_path, restart)?;
+    let mut writer = SongWriter::open(config.backend, &config.db_path())?;
+    let fingerprinter = config.fingerprinter();
 
-    let song_metadata = SongMetaData {
-        song_id: db.songs.len() as u32,
-        title: String::from(song_path),
+    let mut report = IngestReport {
+        checkpoint: Some(checkpoint.path().to_path_buf()),
+        rows: manifest.entries().len(),
+        ..IngestReport::default()
     };
+    for (index, entry) in manifest.entries().iter().enumerate() {
+        let row = index + 1;
+        let song_path = manifest.song_path(entry);
+        // A file replaced or edited since it was ingested is ingested again, as another song.
+        let stamp = FileStamp::of(&song_path);
+        if let Ok(stamp) = &stamp {
+            if checkpoint.contains(&entry.path, stamp) {
+                report.skipped += 1;
+                continue;
+            }
+            if checkpoint.contains_key(&entry.path) {
+                log::warn!(
+                    "{} changed since it was ingested, ingesting it again",
+                    entry.path
+                );
+            }
+        }
+
+        log::info!("Ingesting row {} of {}: {}", row, report.rows, entry.path);
+        let ingested = stamp.map_err(Error::from).and_then(|stamp| {
+            let song_id = analyze_into(
+                &mut writer,
+                &song_path,
+                Some(entry),
+                &fingerprinter,
+                &TimeRange::default(),
+            )?;
+            Ok((song_id, stamp))
+        });
+        match ingested {
+            Ok((song_id, stamp)) => {
+                checkpoint.record(&entry.path, stamp)?;
+                report.ingested.push(IngestedRow {
+                    row,
+                    path: entry.path.clone(),
+                    song_id,
+                });
+            }
+            Err(err) => {
+                log::error!("Unable to ingest row {} ({}): {}", row, entry.path, err);
+                report.failed.push(FailedRow {
+                    row,
+                    path: entry.path.clone(),
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// Fingerprints every file under `directory` selected by `options`, on `jobs` threads, and adds
+// them to the database in a single write once all are done. Files which cannot be analyzed are
+// reported and skipped.
+pub fn analyze_directory(
+    directory: &Path,
+    config: &Config,
+    manifest: Option<&MetadataManifest>,
+    options: &ScanOptions,
+    jobs: usize,
+) -> Result<IngestReport> {
+    let file_paths = scan::find_audio_files(directory, options)?;
+    log::info!("Analyzing {} files on {} threads", file_paths.len(), jobs);
+    let fingerprinter = config.fingerprinter();
+
+    // Every thread takes the next file not yet taken, until none are left.
+    let next_file = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<AnalyzedSong, String>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next_file.fetch_add(1, Ordering::Relaxed);
+                        let Some(song_path) = file_paths.get(index) else {
+                            break;
+                        };
+                        let result = manifest
+                            .map(|manifest| manifest.get(song_path))
+                            .transpose()
+                            .and_then(|entry| {
+                                analyze_file(
+                                    song_path,
+                                    entry.flatten(),
+                                    &fingerprinter,
+                                    &TimeRange::default(),
+                                )
+                            })
+                            .map_err(|err| err.to_string());
+                        results.push((index, result));
+                    }
+                    results
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Analysis thread panicked"))
+            .collect()
+    });
+    // Add the songs in path order, whichever thread finished first.
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut report = IngestReport {
+        rows: file_paths.len(),
+        ..IngestReport::default()
+    };
+    let mut songs = Vec::new();
+    let mut song_paths = Vec::new();
+    for (index, result) in results {
+        let path = file_paths[index].to_string_lossy().into_owned();
+        match result {
+            Ok(song) => {
+                songs.push(song);
+                song_paths.push((index + 1, path));
+            }
+            Err(error) => {
+                log::error!("Unable to analyze {}: {}", path, error);
+                report.failed.push(FailedRow {
+                    row: index + 1,
+                    path,
+                    error,
+                });
+            }
+        }
+    }
+
+    let mut writer = SongWriter::open(config.backend, &config.db_path())?;
+    let song_ids = writer.write_batch(songs, &config.analysis)?;
+    for ((row, path), song_id) in song_paths.into_iter().zip(song_ids) {
+        report.ingested.push(IngestedRow { row, path, song_id });
+    }
+
+    Ok(report)
+}
+
+fn analyze_into(
+    writer: &mut SongWriter,
+    song_path: &Path,
+    entry: Option<&MetadataEntry>,
+    fingerprinter: &Fingerprinter,
+    range: &TimeRange,
+) -> Result<u32> {
+    let (song_metadata, peaks) = analyze_file(song_path, entry, fingerprinter, range)?;
+    writer.write(song_metadata, &peaks, fingerprinter.config())
+}
+
+// Fingerprints a song and reads its metadata, without writing anything to the database.
+fn analyze_file(
+    song_path: &Path,
+    entry: Option<&MetadataEntry>,
+    fingerprinter: &Fingerprinter,
+    range: &TimeRange,
+) -> Result<AnalyzedSong> {
+    log::debug!("Analyzing {:?} of {:?}", range, song_path);
+    let samples = fingerprinter.load_range(song_path, range)?;
+    let peaks = fingerprinter.extract_peaks(&samples)?;
+
+    let mut song_metadata = fingerprinter.metadata(song_path)?;
+    if !range.is_whole() {
+        // Samples are loaded at the configured rate, whatever the rate of the file.
+        song_metadata.duration =
+            samples.len() as f32 / fingerprinter.config().spectrogram.sample_rate;
+    }
+    if let Some(entry) = entry {
+        song_metadata.apply(entry);
+    }
+    song_metadata.set_ingested_now();
+
+    Ok((song_metadata, peaks))
+}
+
+// Merges all journal segments into the main index.
+pub fn compact_database(config: &Config) -> Result<()> {
+    let db_path = config.db_path();
 
-    db.add_song(song_metadata, &peaks, &spectrogram.config);
+    match config.backend {
+        Backend::Memory => {
+            let db = FingerprintDB::compact(&db_path)?;
 
-    let total_fingerprints: usize = db.database.values().map(|v| v.len()).sum();
-    let unique_fingerprints: usize = db.database.len();
+            let total_fingerprints: usize = db.database.values().map(|v| v.len()).sum();
+            let unique_fingerprints: usize = db.database.len();
+            let encoded_bytes: usize = db.database.values().map(|v| v.encoded_len()).sum();
 
-    log::debug!("Generated {} total fingerprints", total_fingerprints);
-    log::debug!("Reduced to {} unique fingerprints", unique_fingerprints);
-    log::debug!(
-        "Average collisions per fingerprint: {:.2}",
-        total_fingerprints as f32 / unique_fingerprints as f32
-    );
+            log::debug!("Index holds {} total fingerprints", total_fingerprints);
+            log::debug!("Reduced to {} unique fingerprints", unique_fingerprints);
+            log::debug!(
+                "Average collisions per fingerprint: {:.2}",
+                total_fingerprints as f32 / unique_fingerprints as f32
+            );
+            log::debug!(
+                "Postings compressed to {:.2} bytes per fingerprint",
+                encoded_bytes as f32 / total_fingerprints as f32
+            );
+        }
+        Backend::Mmap => {
+            MmapStore::compact(db_path)?;
+        }
+        Backend::Sharded => {
+            ShardedStore::compact(db_path)?;
+        }
+        Backend::Sqlite => log::info!("The SQLite backend has no journal to compact"),
+    }
 
-    db.save("audio_fingerprint.db")
-        .expect("Unable to write to database");
+    Ok(())
 }
 
-pub fn recognize_song(song_query_path: &str) -> Option<(SongMetaData, MatchResult)> {
-    let samples = audio::load_wav(song_query_path).expect("Unable to read wav file");
-    let config = fft::SpectrogramConfig::default();
-    let spectrogram = fft::compute_spectrogram(&samples, config);
-    let peaks = peaks::extract_peaks(&spectrogram);
+// Merges the databases at `paths` into the database of the memory backend, remapping the ids of
+// songs which conflict with those already in it. Fails if the databases were generated with
+// different analysis parameters.
+pub fn merge_databases(config: &Config, paths: &[PathBuf]) -> Result<()> {
+    let db_path = config.path_for(Backend::Memory);
+    let mut db = FingerprintDB::load_or_create(&db_path)?;
+
+    for path in paths {
+        log::info!("Merging {:?} into {:?}", path, db_path);
+        let other = FingerprintDB::load(path)?;
+        db.merge(other)?;
+    }
+
+    // The journal was replayed into `db`, so it is contained in the saved index.
+    db.save(&db_path)?;
+    Journal::for_database(db_path).clear()?;
+
+    Ok(())
+}
+
+// Exports the given songs of the memory backend's database into a new database at `output_path`.
+// With `remove`, the songs are also removed from the source database, splitting it in two.
+pub fn extract_songs(
+    config: &Config,
+    song_ids: &[u32],
+    output_path: &Path,
+    remove: bool,
+) -> Result<()> {
+    let db_path = config.path_for(Backend::Memory);
+    let mut db = FingerprintDB::load(&db_path)?;
+
+    let extracted = db.extract(song_ids)?;
+    extracted.save(output_path)?;
+
+    if remove {
+        for song_id in song_ids {
+            db.remove_song(*song_id)?;
+        }
+        db.save(&db_path)?;
+        Journal::for_database(&db_path).clear()?;
+    }
+
+    Ok(())
+}
+
+// Partitions the database stored with the `source` backend into `shard_count` shards of equal
+// fingerprint ranges, replacing any existing sharded database.
+pub fn build_shards(config: &Config, source: Backend, shard_count: usize) -> Result<()> {
+    let store = open_store(source, config.path_for(source))?;
+
+    let sharded = ShardedStore::build(
+        store.as_ref(),
+        config.path_for(Backend::Sharded),
+        storage::equal_width_boundaries(shard_count),
+    )?;
+    log_shard_sizes(&sharded);
+
+    Ok(())
+}
+
+// Redistributes the postings of the sharded database evenly across its shards, optionally
+// changing the number of shards.
+pub fn rebalance_shards(config: &Config, shard_count: Option<usize>) -> Result<()> {
+    let sharded = ShardedStore::rebalance(config.path_for(Backend::Sharded), shard_count)?;
+    log_shard_sizes(&sharded);
+
+    Ok(())
+}
+
+// Checks the consistency of the sharded database, returning a description of every problem found.
+pub fn verify_shards(config: &Config) -> Result<Vec<String>> {
+    let sharded = ShardedStore::open_index(config.path_for(Backend::Sharded))?;
+
+    sharded.verify()
+}
+
+fn log_shard_sizes(sharded: &ShardedStore) {
+    for (index, count) in sharded.posting_counts().iter().enumerate() {
+        log::info!("Shard {} holds {} postings", index, count);
+    }
+}
+
+// Recognizes `range` of every query with the database loaded once, on `jobs` threads. A query
+// which cannot be recognized is reported in its result, without failing the others.
+pub fn recognize_batch(
+    queries: &[PathBuf],
+    config: &Config,
+    range: &TimeRange,
+    jobs: usize,
+) -> Result<Vec<QueryResult>> {
+    let path = config.db_path();
+    let fingerprinter = config.fingerprinter();
+    // No more threads than there are queries.
+    let jobs = jobs.min(queries.len()).max(1);
+    log::info!("Recognizing {} queries on {} threads", queries.len(), jobs);
+
+    // Every backend but SQLite can be looked up from many threads at once, so all of them share it.
+    let shared = |store: &(dyn FingerprintStore + Sync)| {
+        batch::recognize_queries(vec![store; jobs], &fingerprinter, queries, range)
+    };
+    Ok(match config.backend {
+        Backend::Memory => shared(&FingerprintDB::load(&path)?),
+        Backend::Mmap => shared(&MmapStore::open(&path)?),
+        Backend::Sharded => shared(&ShardedStore::open(&path)?),
+        Backend::Sqlite => {
+            // A connection is only used by one thread at a time, so every thread opens its own,
+            // after the database has been opened as usual to create it if it does not exist.
+            drop(SqliteStore::open(&path)?);
+            let stores = (0..jobs)
+                .map(|_| SqliteStore::open_read_only(&path).map(Box::new))
+                .collect::<Result<Vec<_>>>()?;
+            batch::recognize_queries(stores, &fingerprinter, queries, range)
+        }
+    })
+}
+
+// Recognizes the queries listed in the labels file at `labels_path`, see `load_labels`, and
+// scores the results against the expected songs, with precision and recall at every threshold.
+pub fn evaluate_queries(
+    labels_path: &Path,
+    config: &Config,
+    thresholds: &[f32],
+    jobs: usize,
+) -> Result<Evaluation> {
+    let labels = load_labels(labels_path)?;
+    let queries: Vec<PathBuf> = labels.iter().map(|label| label.query.clone()).collect();
+    let results = recognize_batch(&queries, config, &TimeRange::default(), jobs)?;
+
+    Ok(evaluate::evaluate(&labels, &results, thresholds))
+}
+
+// Makes degraded queries of the songs with `song_ids`, or every song in the database if none are
+// given, into the directory `output`, see `degrade::degrade_songs`.
+pub fn generate_queries(
+    config: &Config,
+    song_ids: &[u32],
+    options: &DegradeOptions,
+    output: &Path,
+) -> Result<Vec<DegradedQuery>> {
+    let store = open_store(config.backend, config.db_path())?;
+    let songs = if song_ids.is_empty() {
+        let mut songs = store.songs()?;
+        songs.sort_by_key(|song| song.song_id);
+        songs
+    } else {
+        song_ids
+            .iter()
+            .map(|&song_id| {
+                store.get_song(song_id)?.ok_or_else(|| {
+                    Error::InvalidInput(format!("song {song_id} is not in the database"))
+                })
+            })
+            .collect::<Result<_>>()?
+    };
+
+    degrade::degrade_songs(&config.fingerprinter(), &songs, options, output)
+}
+
+// Returns the song in the database best matching `range` of the query, if any matches at all.
+pub fn recognize_song(
+    song_query_path: &str,
+    config: &Config,
+    range: &TimeRange,
+) -> Result<Option<(SongMetaData, MatchResult)>> {
+    let store = open_store(config.backend, config.db_path())?;
+    let recognizer = Recognizer::with_fingerprinter(store, config.fingerprinter());
+
+    recognizer.recognize(
+        &recognizer
+            .fingerprinter()
+            .load_range(song_query_path, range)?,
+    )
+}
+
+// Analyzes `range` of a song and writes its spectrogram, peaks or fingerprints to `output`, in
+// the format named by its extension, see `ExportFormat`. Returns the windows, peaks or
+// fingerprints written.
+pub fn export_song(
+    song_path: &str,
+    config: &Config,
+    range: &TimeRange,
+    data: ExportData,
+    output: &Path,
+) -> Result<usize> {
+    let format = ExportFormat::from_path(output)?;
+    data.check_format(format)?;
+    let fingerprinter = config.fingerprinter();
+    let analysis = fingerprinter.analyze(&fingerprinter.load_range(song_path, range)?)?;
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let written = match data {
+        ExportData::Spectrogram => {
+            export_spectrogram(&analysis.spectrogram, format, &mut writer)?;
+            analysis.spectrogram.data.len()
+        }
+        ExportData::Peaks => {
+            let config = &analysis.spectrogram.config;
+            export_peaks(&analysis.peaks, config, format, &mut writer)?;
+            analysis.peaks.len()
+        }
+        ExportData::Fingerprints => {
+            export_fingerprints(&analysis.fingerprints, format, &mut writer)?;
+            analysis.fingerprints.len()
+        }
+    };
+    writer.flush()?;
+
+    Ok(written)
+}
+
+// Renders the spectrogram of `range` of a song with its peaks, see `render_spectrogram`. With
+// `compare`, the song is recognized against the database, and the peaks of the part of the
+// matched song it lines up with are overlaid as well, decoded from the file the song was analyzed
+// from.
+pub fn inspect_song(
+    song_path: &str,
+    config: &Config,
+    range: &TimeRange,
+    options: &RenderOptions,
+    compare: bool,
+) -> Result<Inspection> {
+    let fingerprinter = config.fingerprinter();
+    let samples = fingerprinter.load_range(song_path, range)?;
+    let analysis = fingerprinter.analyze(&samples)?;
+
+    let mut matched = None;
+    let mut song_peaks = Vec::new();
+    if compare {
+        let store = open_store(config.backend, config.db_path())?;
+        let recognizer = Recognizer::with_fingerprinter(store, fingerprinter);
+        matched = recognizer.recognize(&samples)?;
+        if let Some((song, result)) = &matched {
+            let query_seconds = samples.len() as f32 / config.analysis.spectrogram.sample_rate;
+            song_peaks = aligned_song_peaks(&fingerprinter, song, result, query_seconds)?;
+        }
+    }
+
+    let aligned = aligned_peaks(&analysis.peaks, &song_peaks)
+        .into_iter()
+        .filter(|&aligned| aligned)
+        .count();
+    let image = render_spectrogram(&analysis.spectrogram, &analysis.peaks, &song_peaks, options)?;
+
+    Ok(Inspection {
+        analysis,
+        matched,
+        song_peaks,
+        aligned,
+        image,
+    })
+}
+
+// The peaks of the part of `song` a query of `query_seconds` was matched to, moved into the
+// windows of the query.
+fn aligned_song_peaks(
+    fingerprinter: &Fingerprinter,
+    song: &SongMetaData,
+    matched: &MatchResult,
+    query_seconds: f32,
+) -> Result<Vec<Peak>> {
+    let path = Path::new(&song.source_path);
+    if !path.is_file() {
+        return Err(Error::InvalidInput(format!(
+            "the audio of song {}, {:?}, is not found to compare against",
+            song.song_id, path
+        )));
+    }
+
+    let range = TimeRange {
+        start: matched.song_offset_ms() as f32 / 1000.0,
+        duration: Some(query_seconds),
+    };
+    let samples = fingerprinter.load_range(path, &range)?;
+    let spectrogram = fingerprinter.spectrogram(&samples)?;
+
+    // The song starts this many windows into the query, if the query starts before it.
+    let config = &fingerprinter.config().spectrogram;
+    let shift = (matched.query_offset_ms() as f32 / 1000.0 * config.sample_rate
+        / config.stride as f32)
+        .round() as usize;
+    let mut peaks = fingerprinter.peaks(&spectrogram);
+    peaks.iter_mut().for_each(|peak| peak.time_bin += shift);
+
+    Ok(peaks)
+}
+
+// A path in the temporary directory for a test to write `name` to, unique to the test run.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("audio_fingerprint_{}_{name}", std::process::id()))
+}
+
+#[cfg(test)]
+mod test {
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::path::Path;
+
+    use crate::{
+        Config, Error, Fingerprinter, InsufficientAudio, TimeRange, analyze_file, compact_database,
+        extract_songs, merge_databases,
+    };
+
+    #[test]
+    fn audio_errors() {
+        let missing = Fingerprinter::default().analyze_file(Path::new("does/not/exist.wav"));
+        assert!(matches!(missing, Err(Error::Io(_))));
+
+        let path = crate::temp_path("short.wav");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for _ in 0..100 {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        // The file is silent, and there is nothing at all past its end.
+        let fingerprinter = Fingerprinter::default();
+        let silent = analyze_file(&path, None, &fingerprinter, &TimeRange::default());
+        assert!(matches!(
+            silent,
+            Err(Error::InsufficientAudio(InsufficientAudio::Silent))
+        ));
+        let past_end = TimeRange {
+            start: 1.0,
+            duration: None,
+        };
+        let empty = analyze_file(&path, None, &fingerprinter, &past_end);
+        assert!(matches!(
+            empty,
+            Err(Error::InsufficientAudio(InsufficientAudio::Empty))
+        ));
+
+        std::fs::write(&path, b"RIFF, but not really").unwrap();
+        let garbage = Fingerprinter::default().analyze_file(&path);
+        assert!(matches!(garbage, Err(Error::Decoding(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
 
-    let db =
-        fingerprint::FingerprintDB::load("audio_fingerprint.db").expect("Unable to load database");
+    #[test]
+    fn corrupt_database() {
+        let path = crate::temp_path("corrupt.db");
+        std::fs::write(&path, b"AFPINDEX\x01\x00\x00\x00 cut short").unwrap();
+        let config = Config {
+            db: Some(path.clone()),
+            ..Config::default()
+        };
 
-    db.recognize_song(&peaks, &spectrogram.config)
+        // Every operation rewriting the database fails rather than starting over.
+        let extracted = path.with_extension("extracted");
+        for result in [
+            compact_database(&config),
+            merge_databases(&config, &[]),
+            extract_songs(&config, &[0], &extracted, true),
+        ] {
+            assert!(matches!(result, Err(Error::Corruption(_))), "{result:?}");
+        }
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"AFPINDEX\x01\x00\x00\x00 cut short"
+        );
+        assert!(!extracted.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,13 +1,27 @@
 mod cli;
+mod output;
 
-use std::{fs, io, path::PathBuf};
+use std::{fs, path::PathBuf, process::ExitCode, slice, thread, time::Instant};
 
-use audio_fingerprint::{analyze_song, recognize_song};
+use audio_fingerprint::{
+    Backend, Config, DEFAULT_THRESHOLDS, DegradeOptions, Error, Evaluation, ExportData,
+    IngestReport, MatchResult, MetadataManifest, QueryResult, RawEncoding, RawFormat,
+    RenderOptions, Result, ScanOptions, SongMetaData, TimeRange, analyze_directory, analyze_song,
+    build_shards, compact_database, evaluate_queries, export_song, extract_songs, find_queries,
+    generate_queries, ingest_manifest, inspect_song, merge_databases, rebalance_shards,
+    recognize_batch, verify_shards,
+};
 use clap::Parser;
 
-use crate::cli::Cli;
+use crate::{
+    cli::{Cli, Format, RawArgs},
+    output::{
+        AnalyzeRecord, BatchSummary, ExportRecord, IngestRecord, InspectRecord, MatchRecord,
+        ProblemRecord,
+    },
+};
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
     // Configure a simple logger
 
@@ -15,63 +29,490 @@ fn main() {
         .filter_level(cli.verbosity.into())
         .init();
 
+    match run(cli) {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(error_exit_code(&err))
+        }
+    }
+}
+
+// Exit codes for errors, by category, so scripts can tell them apart. 1 is used for commands
+// which ran, but did not succeed, such as a query without a match, and clap uses 2 for invalid
+// arguments.
+fn error_exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Io(_) => 3,
+        Error::Decoding(_) => 4,
+        Error::UnsupportedFormat(_) => 5,
+        Error::InsufficientAudio(_) => 6,
+        Error::Corruption(_) => 7,
+        Error::ParameterMismatch { .. } => 8,
+        Error::Sqlite(_) => 9,
+        Error::InvalidInput(_) => 10,
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    let config = load_config(&cli)?;
+    let format = cli.format;
+
     match cli.command {
         cli::Commands::Analyze(args) => {
             log::info!(
                 "Analyzing {} and committing fingerprint to database",
                 args.path_to_song
             );
-            analyze_song(&args.path_to_song)
+            let manifest = load_manifest(args.metadata)?;
+            let song_id = analyze_song(
+                &args.path_to_song,
+                &config,
+                manifest.as_ref(),
+                &args.range.range(),
+            )?;
+
+            let record = AnalyzeRecord {
+                path: args.path_to_song,
+                song_id,
+            };
+            output::print(format, &record, slice::from_ref(&record))?;
         }
         cli::Commands::Recognize(args) => {
             log::info!("Attempting to recognize {}", args.path_to_song);
-            match recognize_song(&args.path_to_song) {
-                Some((song_metadata, match_result)) => {
-                    println!("Match found:");
-                    println!("Song ID: {}", song_metadata.song_id);
-                    println!("Title: {}", song_metadata.title);
-                    println!("Confidence: {}", match_result.confidence);
+            let queries = [PathBuf::from(&args.path_to_song)];
+            let QueryResult {
+                query,
+                outcome,
+                timings,
+            } = recognize_batch(&queries, &config, &args.range.range(), 1)?.remove(0);
+            let outcome = outcome?;
+            let found = outcome.is_some();
+
+            if format == Format::Text {
+                print_match(outcome);
+            } else {
+                let record = MatchRecord::new(&QueryResult {
+                    query,
+                    outcome: Ok(outcome),
+                    timings,
+                });
+                output::print(format, &record, slice::from_ref(&record))?;
+            }
+            if !found {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        cli::Commands::Inspect(args) => {
+            log::info!("Inspecting {}", args.path_to_song);
+            let options = RenderOptions {
+                color_map: args.color_map.into(),
+                min_frequency_hz: args.min_freq,
+                max_frequency_hz: args.max_freq,
+                dynamic_range_db: args.dynamic_range,
+            };
+            let inspection = inspect_song(
+                &args.path_to_song,
+                &config,
+                &args.range.range(),
+                &options,
+                args.compare,
+            )?;
+            inspection.image.save_png(&args.output)?;
+
+            let analysis = &inspection.analysis;
+            let matched = inspection.matched.as_ref();
+            let record = InspectRecord {
+                path: args.path_to_song,
+                image: args.output.to_string_lossy().into_owned(),
+                windows: analysis.spectrogram.data.len(),
+                frequency_bins: analysis.spectrogram.config.num_bins(),
+                peaks: analysis.peaks.len(),
+                fingerprints: analysis.fingerprints.len(),
+                song_id: matched.map(|(song, _)| song.song_id),
+                song_offset_ms: matched.map(|(_, matched)| matched.song_offset_ms()),
+                query_offset_ms: matched.map(|(_, matched)| matched.query_offset_ms()),
+                aligned_peaks: matched.map(|_| inspection.aligned),
+            };
+            if format == Format::Text {
+                println!("Windows: {}", record.windows);
+                println!("Frequency bins: {}", record.frequency_bins);
+                println!("Peaks: {}", record.peaks);
+                println!("Fingerprints: {}", record.fingerprints);
+                match matched {
+                    Some((song, matched)) => println!(
+                        "Matched song {} ({}) {} ms in, {} of the peaks line up with it",
+                        song.song_id,
+                        song.title,
+                        matched.song_offset_ms(),
+                        inspection.aligned
+                    ),
+                    None if args.compare => println!("No match found"),
+                    None => {}
                 }
-                None => todo!(),
+                println!("Image: {}", record.image);
+            } else {
+                output::print(format, &record, slice::from_ref(&record))?;
+            }
+        }
+        cli::Commands::Export(args) => {
+            let (data, name, args) = match args.command {
+                cli::ExportCommands::Spectrogram(args) => {
+                    (ExportData::Spectrogram, "spectrogram", args)
+                }
+                cli::ExportCommands::Peaks(args) => (ExportData::Peaks, "peaks", args),
+                cli::ExportCommands::Fingerprints(args) => {
+                    (ExportData::Fingerprints, "fingerprints", args)
+                }
+            };
+            log::info!("Exporting the {} of {}", name, args.path_to_song);
+            let count = export_song(
+                &args.path_to_song,
+                &config,
+                &args.range.range(),
+                data,
+                &args.output,
+            )?;
+
+            let record = ExportRecord {
+                path: args.path_to_song,
+                output: args.output.to_string_lossy().into_owned(),
+                data: name,
+                count,
+            };
+            if format == Format::Text {
+                let unit = match data {
+                    ExportData::Spectrogram => "windows",
+                    ExportData::Peaks => "peaks",
+                    ExportData::Fingerprints => "fingerprints",
+                };
+                println!("Wrote {} {} to {}", count, unit, record.output);
+            } else {
+                output::print(format, &record, slice::from_ref(&record))?;
+            }
+        }
+        cli::Commands::RecognizeBatch(args) => {
+            let options = ScanOptions {
+                include: args.include,
+                exclude: args.exclude,
+                follow_symlinks: args.follow_symlinks,
+            };
+            let queries = find_queries(&args.path, &options)?;
+            let start = Instant::now();
+            let results =
+                recognize_batch(&queries, &config, &TimeRange::default(), jobs(args.jobs))?;
+            let records: Vec<MatchRecord> = results.iter().map(MatchRecord::new).collect();
+            let summary = BatchSummary::new(&records, start.elapsed());
+
+            if format == Format::Text {
+                for record in records.iter() {
+                    match (&record.song, &record.error) {
+                        (Some(song), _) => println!(
+                            "{}: {} (song {}, score {}) in {:.1} ms",
+                            record.query,
+                            song.title,
+                            song.song_id,
+                            record.score.unwrap_or_default(),
+                            record.total_ms
+                        ),
+                        (None, Some(error)) => println!("{}: failed: {}", record.query, error),
+                        (None, None) => {
+                            println!("{}: no match in {:.1} ms", record.query, record.total_ms)
+                        }
+                    }
+                }
+                println!("Queries: {}", summary.queries);
+                println!("Matched: {}", summary.matched);
+                println!("No match: {}", summary.no_match);
+                println!("Failed: {}", summary.failed);
+                println!("Elapsed: {:.1} ms", summary.elapsed_ms);
+            } else {
+                output::print(format, &summary, &records)?;
+            }
+            if summary.failed > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        cli::Commands::Evaluate(args) => {
+            log::info!("Evaluating the queries labelled in {:?}", args.labels);
+            let thresholds = if args.thresholds.is_empty() {
+                DEFAULT_THRESHOLDS.to_vec()
+            } else {
+                args.thresholds
+            };
+            let evaluation = evaluate_queries(&args.labels, &config, &thresholds, jobs(args.jobs))?;
+
+            if format == Format::Text {
+                print_evaluation(&evaluation);
+            } else {
+                output::print(format, &evaluation, &evaluation.thresholds)?;
+            }
+        }
+        cli::Commands::Degrade(args) => {
+            log::info!("Making degraded queries in {:?}", args.output);
+            let options = DegradeOptions {
+                seed: args.seed,
+                clips_per_song: args.clips_per_song,
+                clip_seconds: args.clip_seconds,
+                distortions: args.distortions,
+            };
+            let queries = generate_queries(&config, &args.song_ids, &options, &args.output)?;
+
+            if format == Format::Text {
+                println!("Queries: {}", queries.len());
+                println!("Labels: {:?}", args.output.join("labels.csv"));
+            } else {
+                output::print(format, &queries, &queries)?;
             }
         }
         cli::Commands::AnalyzeDirectory(args) => {
-            log::info!("Analyzing all .wav files in {:?}", args.path_to_directory);
+            let manifest = load_manifest(args.metadata)?;
+            let options = ScanOptions {
+                include: args.include,
+                exclude: args.exclude,
+                follow_symlinks: args.follow_symlinks,
+            };
+            log::info!(
+                "Analyzing the files matching {:?} in {:?}",
+                options.include,
+                args.path_to_directory
+            );
+            let report = analyze_directory(
+                &args.path_to_directory,
+                &config,
+                manifest.as_ref(),
+                &options,
+                jobs(args.jobs),
+            )?;
 
-            let file_paths = get_file_paths_from_directory(&args.path_to_directory);
-            match file_paths {
-                Ok(file_paths) => {
-                    for fp in file_paths.iter() {
-                        analyze_song(fp);
+            if format == Format::Text {
+                println!("Files found: {}", report.rows);
+            }
+            return print_report(format, &report);
+        }
+        cli::Commands::Ingest(args) => {
+            log::info!("Ingesting the songs listed in {:?}", args.manifest);
+            let report = ingest_manifest(&args.manifest, &config, args.restart)?;
+
+            if format == Format::Text {
+                println!("Rows in manifest: {}", report.rows);
+                println!("Skipped, already ingested: {}", report.skipped);
+            }
+            if let Some(report_path) = args.report {
+                let file = fs::File::create(report_path)?;
+                serde_json::to_writer_pretty(file, &report).map_err(std::io::Error::from)?;
+            }
+            return print_report(format, &report);
+        }
+        cli::Commands::Compact => {
+            log::info!("Compacting the database journal into the main index");
+            compact_database(&config)?;
+        }
+        cli::Commands::Merge(args) => {
+            log::info!("Merging {} databases", args.paths.len());
+            merge_databases(&config, &args.paths)?;
+        }
+        cli::Commands::Extract(args) => {
+            log::info!(
+                "Extracting {} songs into {:?}",
+                args.song_ids.len(),
+                args.output
+            );
+            extract_songs(&config, &args.song_ids, &args.output, args.remove)?;
+        }
+        cli::Commands::Shards(args) => match args.command {
+            cli::ShardCommands::Build(args) => {
+                log::info!("Building {} shards from {:?}", args.shard_count, args.from);
+                build_shards(&config, args.from.into(), args.shard_count)?;
+            }
+            cli::ShardCommands::Rebalance(args) => {
+                log::info!("Rebalancing shards");
+                rebalance_shards(&config, args.shard_count)?;
+            }
+            cli::ShardCommands::Verify => {
+                log::info!("Verifying shards");
+                let problems = verify_shards(&config)?;
+                if format == Format::Text {
+                    if problems.is_empty() {
+                        println!("All shards are consistent");
                     }
+                    for problem in problems.iter() {
+                        println!("{problem}");
+                    }
+                } else {
+                    let records: Vec<ProblemRecord> = problems
+                        .iter()
+                        .map(|problem| ProblemRecord { problem })
+                        .collect();
+                    output::print(format, &records, &records)?;
+                }
+                if !problems.is_empty() {
+                    return Ok(ExitCode::FAILURE);
                 }
-                Err(_) => todo!(),
             }
+        },
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Settings are taken from the options on the command line first, then their environment
+// variables, then the configuration file, and lastly the defaults.
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(backend) = cli.backend.map(Backend::from) {
+        // The database in the file belongs to the backend in the file.
+        if backend != config.backend {
+            config.db = None;
         }
+        config.backend = backend;
+    }
+    if let Some(db) = &cli.db {
+        config.db = Some(db.clone());
+    }
+    cli.analysis.apply(&mut config.analysis);
+    config.raw = raw_format(&cli.raw, config.raw)?;
+    if let Some(seconds) = cli.min_query_seconds {
+        config.min_query_seconds = Some(seconds);
     }
+    config.validate()?;
+    log::debug!("Using {:?}", config);
+
+    Ok(config)
 }
 
-fn get_file_paths_from_directory(path_to_directory: &PathBuf) -> Result<Vec<String>, io::Error> {
-    // Recursively find all .wav files in directory
-    let mut file_paths = Vec::<String>::new();
-
-    for entry in fs::read_dir(path_to_directory)? {
-        let file = entry?;
-        let path = file.path();
-
-        if path.is_dir() {
-            // file_paths.extend(get_file_paths_from_directory(path)?);
-            continue;
-        } else {
-            let path_as_string = path.to_str();
-            match path_as_string {
-                Some(string) => file_paths.push(String::from(string)),
-                None => {
-                    continue;
-                }
-            }
+// The raw format from the options, filling in what they leave out from that of the file.
+fn raw_format(args: &RawArgs, file: Option<RawFormat>) -> Result<Option<RawFormat>> {
+    let mut format = file;
+    if let Some(encoding) = args.raw.map(RawEncoding::from) {
+        format = Some(match format {
+            Some(format) => RawFormat { encoding, ..format },
+            None => RawFormat {
+                encoding,
+                sample_rate: args.rate.ok_or_else(|| {
+                    Error::InvalidInput(String::from(
+                        "raw input needs its sample rate, given with --rate",
+                    ))
+                })?,
+                channels: 1,
+            },
+        });
+    }
+
+    let Some(format) = &mut format else {
+        if args.rate.is_some() || args.channels.is_some() {
+            return Err(Error::InvalidInput(String::from(
+                "--rate and --channels only apply to raw input, given with --raw",
+            )));
         }
+        return Ok(None);
+    };
+    if let Some(rate) = args.rate {
+        format.sample_rate = rate;
+    }
+    if let Some(channels) = args.channels {
+        format.channels = channels;
     }
+    Ok(Some(*format))
+}
+
+// The number of threads to run, by default one per CPU.
+fn jobs(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get()))
+}
+
+fn load_manifest(path: Option<PathBuf>) -> Result<Option<MetadataManifest>> {
+    path.map(MetadataManifest::load).transpose()
+}
 
-    Ok(file_paths)
+// Prints the outcome of analyzing many songs, failing if any of them failed.
+fn print_report(format: Format, report: &IngestReport) -> Result<ExitCode> {
+    if format == Format::Text {
+        println!("Ingested: {}", report.ingested.len());
+        println!("Failed: {}", report.failed.len());
+        for failure in report.failed.iter() {
+            println!(
+                "  row {} ({}): {}",
+                failure.row, failure.path, failure.error
+            );
+        }
+    } else {
+        output::print(format, report, &IngestRecord::from_report(report))?;
+    }
+
+    if report.failed.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn print_evaluation(evaluation: &Evaluation) {
+    println!(
+        "Queries: {} ({} of songs in the database, {} of none)",
+        evaluation.queries, evaluation.positives, evaluation.negatives
+    );
+    println!("Failed: {}", evaluation.failed);
+    println!("Accuracy: {:.3}", evaluation.accuracy);
+    if let Some(time) = evaluation.mean_time_to_identify_ms {
+        println!("Mean time to identify: {time:.1} ms");
+    }
+    if let Some(time) = evaluation.mean_query_time_ms {
+        println!("Mean query time: {time:.1} ms");
+    }
+
+    let format_rate =
+        |rate: Option<f64>| rate.map_or(String::from("-"), |rate| format!("{rate:.3}"));
+    println!("Threshold  Precision  Recall  FPR");
+    for metrics in evaluation.thresholds.iter() {
+        println!(
+            "{:<9}  {:<9}  {:<6}  {}",
+            metrics.threshold,
+            format_rate(metrics.precision),
+            format_rate(metrics.recall),
+            format_rate(metrics.false_positive_rate)
+        );
+    }
+
+    let song = |song_id: Option<u32>| song_id.map_or(String::from("none"), |id| id.to_string());
+    for confusion in evaluation.confusions.iter() {
+        println!(
+            "Expected {}, matched {}: {} queries",
+            song(confusion.expected),
+            song(confusion.matched),
+            confusion.count
+        );
+    }
+}
+
+fn print_match(result: Option<(SongMetaData, MatchResult)>) {
+    let Some((song_metadata, match_result)) = result else {
+        println!("No match found");
+        return;
+    };
+
+    println!("Match found:");
+    println!("Song ID: {}", song_metadata.song_id);
+    if let Some(external_id) = &song_metadata.external_id {
+        println!("External ID: {external_id}");
+    }
+    println!("Title: {}", song_metadata.title);
+    if let Some(artist) = &song_metadata.artist {
+        println!("Artist: {artist}");
+    }
+    if let Some(album) = &song_metadata.album {
+        println!("Album: {album}");
+    }
+    if let Some(isrc) = &song_metadata.isrc {
+        println!("ISRC: {isrc}");
+    }
+    println!("Duration: {:.1}s", song_metadata.duration);
+    println!("Source: {}", song_metadata.source_path);
+    for (key, value) in song_metadata.tags.iter() {
+        println!("{key}: {value}");
+    }
+    println!("Confidence: {}", match_result.confidence);
 }
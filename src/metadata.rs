@@ -0,0 +1,526 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    audio::{AudioFormat, RawFormat, wav},
+    error::{AudioError, Error, Result},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SongMetaData {
+    pub song_id: u32,
+    // The id of the song in the catalogue it was ingested from.
+    pub external_id: Option<String>,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+    // In seconds.
+    pub duration: f32,
+    pub sample_rate: u32,
+    pub source_path: String,
+    // The SHA-256 of the source file, hex encoded.
+    pub content_hash: String,
+    // Seconds since the Unix epoch.
+    pub ingested_at: u64,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl SongMetaData {
+    // Reads the metadata of the audio file at `path`: its format, content hash and whatever tags
+    // it holds, the `LIST/INFO` chunk of a WAV file or the Vorbis comments of a FLAC file.
+    // Without a title among them, the title is the file name. Only the headers and tags are
+    // read, besides hashing the file as it streams past.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut metadata = Self::from_path(path)?;
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = Vec::new();
+        (&mut reader)
+            .take(AudioFormat::HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        match AudioFormat::detect(&header) {
+            Some(AudioFormat::Wav) => metadata.read_wav(reader)?,
+            Some(AudioFormat::Flac) => metadata.read_flac(reader)?,
+            #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+            Some(format) if format.is_supported() => metadata.read_lossy(reader.into_inner())?,
+            Some(format) => return Err(format.unsupported().into()),
+            None => return Err(AudioError::UnknownFormat.into()),
+        }
+
+        Ok(metadata)
+    }
+
+    // Like `from_file`, for headerless PCM in `format`, which holds no tags.
+    pub fn from_raw<P: AsRef<Path>>(path: P, format: &RawFormat) -> Result<Self> {
+        let path = path.as_ref();
+        let mut metadata = Self::from_path(path)?;
+
+        let frames = File::open(path)?.metadata()?.len() / format.frame_bytes() as u64;
+        metadata.duration = frames as f32 / format.sample_rate as f32;
+        metadata.sample_rate = format.sample_rate;
+
+        Ok(metadata)
+    }
+
+    fn from_path(path: &Path) -> Result<Self> {
+        Ok(Self {
+            title: path
+                .file_stem()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+            source_path: path.to_string_lossy().into_owned(),
+            content_hash: hash_file(path)?,
+            ..Self::default()
+        })
+    }
+
+    fn read_wav<R: Read + Seek>(&mut self, mut reader: R) -> Result<()> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let header = wav::read_header(&mut reader)?;
+        // The data chunk may claim more than the file holds.
+        let data_len = header
+            .data_len
+            .min(file_len.saturating_sub(reader.stream_position()?));
+        self.duration = (data_len / header.block_align()) as f32 / header.sample_rate as f32;
+        self.sample_rate = header.sample_rate;
+
+        for (id, value) in read_info_chunk(&mut reader)? {
+            match &id {
+                b"INAM" => self.title = value,
+                b"IART" => self.artist = Some(value),
+                b"IPRD" => self.album = Some(value),
+                _ => {
+                    self.tags.insert(info_tag_name(&id), value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_flac<R: Read>(&mut self, reader: R) -> Result<()> {
+        let flac_reader = claxon::FlacReader::new(reader).map_err(AudioError::from)?;
+        let info = flac_reader.streaminfo();
+        self.duration = info.samples.unwrap_or(0) as f32 / info.sample_rate as f32;
+        self.sample_rate = info.sample_rate;
+
+        // Vorbis comment names are case-insensitive.
+        self.apply_tags(
+            flac_reader
+                .tags()
+                .map(|(name, value)| (name.to_lowercase(), String::from(value))),
+        );
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "mp3", feature = "ogg", feature = "aac"))]
+    fn read_lossy(&mut self, file: File) -> Result<()> {
+        let info = crate::audio::lossy::read_info(file)?;
+        if info.sample_rate > 0 {
+            self.duration = info.frames.unwrap_or(0) as f32 / info.sample_rate as f32;
+        }
+        self.sample_rate = info.sample_rate;
+        self.apply_tags(info.tags.into_iter());
+
+        Ok(())
+    }
+
+    // Sets the fields of named tags such as Vorbis comments, keeping the rest as tags. Names may
+    // repeat, in which case the first wins.
+    fn apply_tags(&mut self, named_tags: impl Iterator<Item = (String, String)>) {
+        let mut tags = BTreeMap::new();
+        for (name, value) in named_tags {
+            let value = value.trim();
+            if !value.is_empty() {
+                tags.entry(name).or_insert_with(|| String::from(value));
+            }
+        }
+        if let Some(title) = tags.remove("title") {
+            self.title = title;
+        }
+        self.artist = tags.remove("artist");
+        self.album = tags.remove("album");
+        self.isrc = tags.remove("isrc");
+        self.tags = tags;
+    }
+
+    // Overrides every field set in a manifest entry.
+    pub fn apply(&mut self, entry: &MetadataEntry) {
+        if let Some(external_id) = &entry.external_id {
+            self.external_id = Some(external_id.clone());
+        }
+        if let Some(title) = &entry.title {
+            self.title = title.clone();
+        }
+        if let Some(artist) = &entry.artist {
+            self.artist = Some(artist.clone());
+        }
+        if let Some(album) = &entry.album {
+            self.album = Some(album.clone());
+        }
+        if let Some(isrc) = &entry.isrc {
+            self.isrc = Some(isrc.clone());
+        }
+        self.tags
+            .extend(entry.tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    pub fn set_ingested_now(&mut self) {
+        self.ingested_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+    }
+}
+
+// The metadata of a single file, as given in a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct MetadataEntry {
+    pub path: String,
+    pub external_id: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+// Metadata for songs kept in a file next to the audio rather than in it. Either:
+//
+//   - CSV with a header row, holding a `path` column, any of `external_id`, `title`, `artist`,
+//     `album` and `isrc`, and any other column as a tag.
+//   - JSON, an array of objects with the fields of `MetadataEntry`, where `tags` is an object.
+//   - JSON Lines, one such object per line.
+//
+// Relative paths are relative to the manifest. Songs are matched on their path, falling back to
+// the file name alone when no other entry shares it.
+#[derive(Debug, Default)]
+pub struct MetadataManifest {
+    directory: PathBuf,
+    entries: Vec<MetadataEntry>,
+    by_path: HashMap<PathBuf, usize>,
+    by_file_name: HashMap<OsString, Vec<usize>>,
+}
+
+impl MetadataManifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        log::info!("Loading song metadata from {:?}", path);
+
+        let entries = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => read_csv_entries(File::open(path)?)?,
+            Some("json") => serde_json::from_reader(BufReader::new(File::open(path)?))?,
+            Some("jsonl") => {
+                let mut entries = Vec::new();
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        entries.push(serde_json::from_str(&line)?);
+                    }
+                }
+                entries
+            }
+            _ => {
+                return Err(Error::InvalidInput(format!(
+                    "unknown metadata manifest format: {path:?}"
+                )));
+            }
+        };
+
+        Ok(Self::new(entries, path.parent().unwrap_or(Path::new(""))))
+    }
+
+    // Resolves the paths of `entries` relative to `directory`.
+    pub fn new(entries: Vec<MetadataEntry>, directory: &Path) -> Self {
+        let mut manifest = Self {
+            directory: directory.to_path_buf(),
+            ..Self::default()
+        };
+        for (index, entry) in entries.iter().enumerate() {
+            let path = directory.join(&entry.path);
+            if let Some(file_name) = path.file_name() {
+                manifest
+                    .by_file_name
+                    .entry(file_name.to_owned())
+                    .or_default()
+                    .push(index);
+            }
+            manifest.by_path.insert(path, index);
+        }
+        manifest.entries = entries;
+
+        manifest
+    }
+
+    pub fn entries(&self) -> &[MetadataEntry] {
+        &self.entries
+    }
+
+    // The path of the audio file of an entry, resolved relative to the manifest.
+    pub fn song_path(&self, entry: &MetadataEntry) -> PathBuf {
+        self.directory.join(&entry.path)
+    }
+
+    // Returns the entry of the song at `song_path`, if any. Fails if the song is only matched by
+    // its file name, and several entries have that name, as in `01 - Intro.wav` of every album.
+    pub fn get<P: AsRef<Path>>(&self, song_path: P) -> Result<Option<&MetadataEntry>> {
+        let song_path = song_path.as_ref();
+        if let Some(&index) = self.by_path.get(song_path) {
+            return Ok(Some(&self.entries[index]));
+        }
+
+        let Some(file_name) = song_path.file_name() else {
+            return Ok(None);
+        };
+        match self.by_file_name.get(file_name).map(Vec::as_slice) {
+            None | Some([]) => Ok(None),
+            Some(&[index]) => Ok(Some(&self.entries[index])),
+            Some(indices) => Err(Error::InvalidInput(format!(
+                "{song_path:?} is not in the metadata manifest, and {} of its entries are named {:?}",
+                indices.len(),
+                file_name
+            ))),
+        }
+    }
+}
+
+fn read_csv_entries<R: std::io::Read>(reader: R) -> Result<Vec<MetadataEntry>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    if !headers.iter().any(|header| header == "path") {
+        return Err(Error::InvalidInput(String::from(
+            "metadata manifest has no path column",
+        )));
+    }
+
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let mut entry = MetadataEntry::default();
+        for (header, value) in headers.iter().zip(record?.iter()) {
+            if value.is_empty() {
+                continue;
+            }
+            let value = String::from(value);
+            match header {
+                "path" => entry.path = value,
+                "external_id" => entry.external_id = Some(value),
+                "title" => entry.title = Some(value),
+                "artist" => entry.artist = Some(value),
+                "album" => entry.album = Some(value),
+                "isrc" => entry.isrc = Some(value),
+                _ => {
+                    entry.tags.insert(String::from(header), value);
+                }
+            }
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+// The SHA-256 of the file at `path`, hex encoded, hashed a buffer at a time.
+fn hash_file(path: &Path) -> Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        hasher.update(buffer);
+        let len = buffer.len();
+        reader.consume(len);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+// Returns the entries of the `LIST/INFO` chunk of a RIFF WAVE file, if it has one. Each entry is
+// a four letter id, such as `INAM` for the title, and a NUL terminated string. Only the bodies of
+// `LIST` chunks are read, every other chunk is skipped, stopping at the first truncated one.
+fn read_info_chunk<R: Read + Seek>(reader: &mut R) -> Result<Vec<([u8; 4], String)>, io::Error> {
+    let mut entries = Vec::new();
+    reader.seek(SeekFrom::Start(0))?;
+    let mut riff = [0u8; 12];
+    if reader.read_exact(&mut riff).is_err() || &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Ok(entries);
+    }
+
+    let mut chunk = [0u8; 8];
+    while reader.read_exact(&mut chunk).is_ok() {
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as u64;
+        // Chunks are padded to an even size.
+        let padding = (size % 2) as i64;
+        if &chunk[..4] != b"LIST" {
+            reader.seek(SeekFrom::Current(size as i64 + padding))?;
+            continue;
+        }
+
+        let mut body = Vec::new();
+        reader.by_ref().take(size).read_to_end(&mut body)?;
+        if (body.len() as u64) < size {
+            break;
+        }
+        reader.seek(SeekFrom::Current(padding))?;
+        if body.len() < 4 || &body[..4] != b"INFO" {
+            continue;
+        }
+        for (id, value) in riff_chunks(&body[4..]) {
+            let value = String::from_utf8_lossy(value);
+            let value = value.trim_end_matches('\0').trim();
+            if !value.is_empty() {
+                entries.push((id, String::from(value)));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// Iterates over the (id, body) of consecutive RIFF chunks, stopping at the first truncated one.
+fn riff_chunks(mut bytes: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let id: [u8; 4] = bytes[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let body = bytes.get(8..8 + size)?;
+        // Chunks are padded to an even size.
+        bytes = bytes.get(8 + size + size % 2..).unwrap_or(&[]);
+
+        Some((id, body))
+    })
+}
+
+fn info_tag_name(id: &[u8; 4]) -> String {
+    match id {
+        b"ICMT" => String::from("comment"),
+        b"ICOP" => String::from("copyright"),
+        b"ICRD" => String::from("date"),
+        b"IENG" => String::from("engineer"),
+        b"IGNR" => String::from("genre"),
+        b"ISFT" => String::from("software"),
+        b"ISRC" => String::from("source"),
+        b"ITRK" | b"IPRT" => String::from("track"),
+        _ => String::from_utf8_lossy(id).to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::{io::Cursor, path::Path};
+
+    use crate::{
+        error::Error,
+        metadata::{MetadataManifest, SongMetaData, read_csv_entries},
+    };
+
+    #[test]
+    fn wav_info_chunk() {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut cursor, spec).unwrap();
+            for i in 0..16000 {
+                writer.write_sample((i % 100) as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        let mut bytes = cursor.into_inner();
+
+        // Append a LIST/INFO chunk, with an odd sized entry to exercise the padding.
+        let mut info = b"INFO".to_vec();
+        for (id, value) in [
+            (b"INAM", "Title\0"),
+            (b"IART", "Artist\0"),
+            (b"IGNR", "Jazz\0"),
+        ] {
+            info.extend_from_slice(id);
+            info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            info.extend_from_slice(value.as_bytes());
+            if value.len() % 2 == 1 {
+                info.push(0);
+            }
+        }
+        bytes.extend_from_slice(b"LIST");
+        bytes.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&info);
+        let riff_size = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+        let path = crate::temp_path("metadata.wav");
+        std::fs::write(&path, &bytes).unwrap();
+        let metadata = SongMetaData::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(metadata.title, "Title");
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album, None);
+        assert_eq!(metadata.tags["genre"], "Jazz");
+        assert_eq!(metadata.sample_rate, 8000);
+        assert_eq!(metadata.duration, 2.0);
+        assert_eq!(metadata.content_hash.len(), 64);
+    }
+
+    #[test]
+    fn csv_manifest() {
+        let csv = "path,title,isrc,mood\nalbum/a.wav,First,USABC2500001,calm\nb.wav,,,\n";
+        let entries = read_csv_entries(csv.as_bytes()).unwrap();
+        let manifest = MetadataManifest::new(entries, Path::new("music"));
+
+        let entry = manifest.get("music/album/a.wav").unwrap().unwrap();
+        assert_eq!(entry.title.as_deref(), Some("First"));
+        assert_eq!(entry.tags["mood"], "calm");
+        // Matched on the file name alone.
+        let matched = manifest.get("elsewhere/b.wav").unwrap().unwrap();
+        assert_eq!(matched.path, "b.wav");
+        assert!(manifest.get("c.wav").unwrap().is_none());
+
+        // A file name shared by several entries only matches on the path.
+        let csv = "path,title\none/01 - Intro.wav,One\ntwo/01 - Intro.wav,Two\n";
+        let entries = read_csv_entries(csv.as_bytes()).unwrap();
+        let manifest = MetadataManifest::new(entries, Path::new("music"));
+        let matched = manifest.get("music/two/01 - Intro.wav").unwrap().unwrap();
+        assert_eq!(matched.title.as_deref(), Some("Two"));
+        assert!(matches!(
+            manifest.get("elsewhere/01 - Intro.wav"),
+            Err(Error::InvalidInput(_))
+        ));
+
+        let mut metadata = SongMetaData {
+            title: String::from("a"),
+            ..SongMetaData::default()
+        };
+        metadata.apply(entry);
+        assert_eq!(metadata.title, "First");
+        assert_eq!(metadata.isrc.as_deref(), Some("USABC2500001"));
+        assert_eq!(metadata.artist, None);
+    }
+}
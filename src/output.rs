@@ -0,0 +1,348 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use audio_fingerprint::{
+    DegradedQuery, IngestReport, QueryResult, Result, SongMetaData, ThresholdMetrics,
+};
+
+use crate::cli::Format;
+
+// A record printed by a command with `--format jsonl` or `--format csv`, one per line or row.
+// Records must be flat for CSV, which those with nested fields take care of by overriding
+// `write_csv`.
+pub(crate) trait Record: Serialize {
+    fn write_csv<W: Write>(&self, writer: &mut csv::Writer<W>) -> csv::Result<()> {
+        writer.serialize(self)
+    }
+}
+
+// Prints the result of a command in a machine-readable `format`: `document` as a single JSON
+// value, or `records` as JSON Lines or CSV. Nothing is printed as text, which commands handle
+// themselves.
+pub(crate) fn print<D: Serialize, R: Record>(
+    format: Format,
+    document: &D,
+    records: &[R],
+) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    match format {
+        Format::Text => {}
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut stdout, document)?;
+            writeln!(stdout)?;
+        }
+        Format::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut stdout, record)?;
+                writeln!(stdout)?;
+            }
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout);
+            for record in records {
+                record.write_csv(&mut writer)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+// The outcome of recognizing a query. `status` is `matched`, `no_match` or `failed`, and `song`
+// and the match fields are only set for a match.
+#[derive(Debug, Serialize)]
+pub(crate) struct MatchRecord {
+    pub query: String,
+    pub status: &'static str,
+    pub song: Option<SongMetaData>,
+    // The share of query fingerprints which voted for the match.
+    pub score: Option<f32>,
+    // Where in the song the query starts, or where in the query the song starts, in ms.
+    pub song_offset_ms: Option<u32>,
+    pub query_offset_ms: Option<u32>,
+    pub votes: Option<u32>,
+    pub error: Option<String>,
+    pub load_ms: f64,
+    pub fingerprint_ms: f64,
+    pub lookup_ms: f64,
+    pub total_ms: f64,
+}
+
+impl MatchRecord {
+    pub fn new(result: &QueryResult) -> Self {
+        let (status, matched, error) = match &result.outcome {
+            Ok(Some(matched)) => ("matched", Some(matched), None),
+            Ok(None) => ("no_match", None, None),
+            Err(err) => ("failed", None, Some(err.to_string())),
+        };
+        let song = matched.map(|(song, _)| song.clone());
+        let matched = matched.map(|(_, matched)| matched);
+        let timings = &result.timings;
+
+        Self {
+            query: result.query.to_string_lossy().into_owned(),
+            status,
+            song,
+            score: matched.map(|matched| matched.confidence),
+            song_offset_ms: matched.map(|matched| matched.song_offset_ms()),
+            query_offset_ms: matched.map(|matched| matched.query_offset_ms()),
+            votes: matched.map(|matched| matched.votes),
+            error,
+            load_ms: milliseconds(timings.load),
+            fingerprint_ms: milliseconds(timings.fingerprint),
+            lookup_ms: milliseconds(timings.lookup),
+            total_ms: milliseconds(timings.total()),
+        }
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// The columns of a `MatchRecord` in CSV, with the song metadata inlined and its tags joined as
+// `key=value` pairs separated by `;`.
+#[derive(Serialize)]
+struct MatchRow<'a> {
+    query: &'a str,
+    status: &'a str,
+    song_id: Option<u32>,
+    external_id: Option<&'a str>,
+    title: Option<&'a str>,
+    artist: Option<&'a str>,
+    album: Option<&'a str>,
+    isrc: Option<&'a str>,
+    duration: Option<f32>,
+    source_path: Option<&'a str>,
+    tags: Option<String>,
+    score: Option<f32>,
+    song_offset_ms: Option<u32>,
+    query_offset_ms: Option<u32>,
+    votes: Option<u32>,
+    error: Option<&'a str>,
+    load_ms: f64,
+    fingerprint_ms: f64,
+    lookup_ms: f64,
+    total_ms: f64,
+}
+
+impl Record for MatchRecord {
+    fn write_csv<W: Write>(&self, writer: &mut csv::Writer<W>) -> csv::Result<()> {
+        let song = self.song.as_ref();
+        writer.serialize(MatchRow {
+            query: &self.query,
+            status: self.status,
+            song_id: song.map(|song| song.song_id),
+            external_id: song.and_then(|song| song.external_id.as_deref()),
+            title: song.map(|song| song.title.as_str()),
+            artist: song.and_then(|song| song.artist.as_deref()),
+            album: song.and_then(|song| song.album.as_deref()),
+            isrc: song.and_then(|song| song.isrc.as_deref()),
+            duration: song.map(|song| song.duration),
+            source_path: song.map(|song| song.source_path.as_str()),
+            tags: song.map(|song| join_tags(&song.tags)),
+            score: self.score,
+            song_offset_ms: self.song_offset_ms,
+            query_offset_ms: self.query_offset_ms,
+            votes: self.votes,
+            error: self.error.as_deref(),
+            load_ms: self.load_ms,
+            fingerprint_ms: self.fingerprint_ms,
+            lookup_ms: self.lookup_ms,
+            total_ms: self.total_ms,
+        })
+    }
+}
+
+fn join_tags(tags: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = tags
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    pairs.join(";")
+}
+
+// The outcome of `recognize-batch` as a JSON document.
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchSummary<'a> {
+    pub queries: usize,
+    pub matched: usize,
+    pub no_match: usize,
+    pub failed: usize,
+    // Wall-clock time of recognizing all queries, including loading the database.
+    pub elapsed_ms: f64,
+    pub results: &'a [MatchRecord],
+}
+
+impl<'a> BatchSummary<'a> {
+    pub fn new(records: &'a [MatchRecord], elapsed: Duration) -> Self {
+        let count = |status| {
+            records
+                .iter()
+                .filter(|record| record.status == status)
+                .count()
+        };
+        Self {
+            queries: records.len(),
+            matched: count("matched"),
+            no_match: count("no_match"),
+            failed: count("failed"),
+            elapsed_ms: milliseconds(elapsed),
+            results: records,
+        }
+    }
+}
+
+// A song added to the database by `analyze`.
+#[derive(Debug, Serialize)]
+pub(crate) struct AnalyzeRecord {
+    pub path: String,
+    pub song_id: u32,
+}
+
+impl Record for AnalyzeRecord {}
+
+// A row of an `IngestReport`, whether it was ingested or failed.
+#[derive(Debug, Serialize)]
+pub(crate) struct IngestRecord<'a> {
+    pub row: usize,
+    pub path: &'a str,
+    pub status: &'static str,
+    pub song_id: Option<u32>,
+    pub error: Option<&'a str>,
+}
+
+impl Record for IngestRecord<'_> {}
+
+impl<'a> IngestRecord<'a> {
+    // Every ingested and failed row of the report, in row order.
+    pub fn from_report(report: &'a IngestReport) -> Vec<Self> {
+        let ingested = report.ingested.iter().map(|row| IngestRecord {
+            row: row.row,
+            path: &row.path,
+            status: "ingested",
+            song_id: Some(row.song_id),
+            error: None,
+        });
+        let failed = report.failed.iter().map(|row| IngestRecord {
+            row: row.row,
+            path: &row.path,
+            status: "failed",
+            song_id: None,
+            error: Some(&row.error),
+        });
+
+        let mut records: Vec<Self> = ingested.chain(failed).collect();
+        records.sort_by_key(|record| record.row);
+        records
+    }
+}
+
+// What `inspect` found in a song, and where its image was written.
+#[derive(Debug, Serialize)]
+pub(crate) struct InspectRecord {
+    pub path: String,
+    pub image: String,
+    pub windows: usize,
+    pub frequency_bins: usize,
+    pub peaks: usize,
+    pub fingerprints: usize,
+    // Set with `--compare` when the song matched.
+    pub song_id: Option<u32>,
+    pub song_offset_ms: Option<u32>,
+    pub query_offset_ms: Option<u32>,
+    pub aligned_peaks: Option<usize>,
+}
+
+impl Record for InspectRecord {}
+
+// The analysis data written by `export`.
+#[derive(Debug, Serialize)]
+pub(crate) struct ExportRecord {
+    pub path: String,
+    pub output: String,
+    pub data: &'static str,
+    // The windows, peaks or fingerprints written.
+    pub count: usize,
+}
+
+impl Record for ExportRecord {}
+
+// A problem found by `shards verify`.
+#[derive(Debug, Serialize)]
+pub(crate) struct ProblemRecord<'a> {
+    pub problem: &'a str,
+}
+
+impl Record for ProblemRecord<'_> {}
+
+impl Record for ThresholdMetrics {}
+
+impl Record for DegradedQuery {}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+    use audio_fingerprint::{Error, MatchResult, QueryResult, QueryTimings, SongMetaData};
+
+    use crate::output::{MatchRecord, Record};
+
+    #[test]
+    fn match_record_csv() {
+        let song = SongMetaData {
+            song_id: 3,
+            title: String::from("Song, with a comma"),
+            tags: BTreeMap::from([
+                (String::from("genre"), String::from("jazz")),
+                (String::from("mood"), String::from("calm")),
+            ]),
+            ..SongMetaData::default()
+        };
+        let timings = QueryTimings {
+            load: Duration::from_millis(2),
+            fingerprint: Duration::from_millis(5),
+            lookup: Duration::from_millis(1),
+        };
+        let results = [
+            QueryResult {
+                query: PathBuf::from("query.wav"),
+                // The query starts 40 ms before the song.
+                outcome: Ok(Some((song, MatchResult::new(3, 0.5, -40, 12)))),
+                timings,
+            },
+            QueryResult {
+                query: PathBuf::from("other.wav"),
+                outcome: Ok(None),
+                timings,
+            },
+            QueryResult {
+                query: PathBuf::from("broken.wav"),
+                outcome: Err(Error::Decoding(String::from("bad header"))),
+                timings: QueryTimings::default(),
+            },
+        ];
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for result in results.iter() {
+            MatchRecord::new(result).write_csv(&mut writer).unwrap();
+        }
+
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "query,status,song_id,external_id,title,artist,album,isrc,duration,source_path,tags,score,song_offset_ms,query_offset_ms,votes,error,load_ms,fingerprint_ms,lookup_ms,total_ms",
+                "query.wav,matched,3,,\"Song, with a comma\",,,,0.0,,genre=jazz;mood=calm,0.5,0,40,12,,2.0,5.0,1.0,8.0",
+                "other.wav,no_match,,,,,,,,,,,,,,,2.0,5.0,1.0,8.0",
+                "broken.wav,failed,,,,,,,,,,,,,,Unable to decode audio: bad header,0.0,0.0,0.0,0.0",
+            ]
+        );
+    }
+}
@@ -1,5 +1,23 @@
+use serde::{Deserialize, Serialize};
+
 use crate::fft::{Spectrogram, SpectrogramConfig};
 
+// How peaks are picked from the spectrogram.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeakConfig {
+    // The number of strongest peaks kept in every time slice.
+    pub peaks_per_window: usize,
+}
+
+impl Default for PeakConfig {
+    fn default() -> Self {
+        Self {
+            peaks_per_window: 5,
+        }
+    }
+}
+
 // A peak represents a prominent point in the 2D time-frequency grid we compute using
 // compute_spectrogram.
 // A Peak is made up of a reference to which point in time, and which part of the frequency
@@ -21,10 +39,11 @@ impl Peak {
     }
 
     pub fn frequency_hz(&self, config: &SpectrogramConfig) -> f32 {
-        // Each frequency bin contains `sample_rate / window_size` number of herz. E.g., 44100 /
-        // 1024 = 43 Hz per bin. So the frequency bin with index `freq_bin` corresponds to the
-        // frequency `freq_bin * sample_rate / window_size`.
-        self.freq_bin as f32 * config.sample_rate / config.window_size as f32
+        // Each FFT bin contains `sample_rate / window_size` number of herz. E.g., 44100 / 1024 =
+        // 43 Hz per bin. So the frequency bin with index `freq_bin` corresponds to the frequency
+        // `freq_bin * sample_rate / window_size`. Bands of other frequency scales are taken at
+        // their centre.
+        config.bin_frequency_hz(self.freq_bin)
     }
 
     pub fn time_seconds(&self, config: &SpectrogramConfig) -> f32 {
@@ -34,14 +53,14 @@ impl Peak {
     }
 }
 
-pub fn extract_peaks(spectrogram: &Spectrogram) -> Vec<Peak> {
+pub fn extract_peaks(spectrogram: &Spectrogram, config: &PeakConfig) -> Vec<Peak> {
     log::debug!("Extracting peaks");
     let mut all_peaks = Vec::<Peak>::new();
 
     // We iterate over each time-slice in the time-frequency grid, and compute peaks in each
     // window.
     for (time_bin, freq_magnitudes) in spectrogram.data.iter().enumerate() {
-        let peaks_in_this_window = find_frequency_peaks(&freq_magnitudes, time_bin);
+        let peaks_in_this_window = find_frequency_peaks(freq_magnitudes, time_bin, config);
         all_peaks.extend(peaks_in_this_window);
     }
 
@@ -49,7 +68,7 @@ pub fn extract_peaks(spectrogram: &Spectrogram) -> Vec<Peak> {
     all_peaks
 }
 
-fn find_frequency_peaks(magnitudes: &[f32], time_bin: usize) -> Vec<Peak> {
+fn find_frequency_peaks(magnitudes: &[f32], time_bin: usize, config: &PeakConfig) -> Vec<Peak> {
     let mut peaks = Vec::<Peak>::new();
     let num_magnitudes = magnitudes.len();
 
@@ -59,9 +78,9 @@ fn find_frequency_peaks(magnitudes: &[f32], time_bin: usize) -> Vec<Peak> {
         }
     }
 
-    // Sort by strongest peaks first, and pick the 5 largest.
+    // Sort by strongest peaks first, and pick the largest.
     peaks.sort_by(|a, b| b.magnitude.partial_cmp(&a.magnitude).unwrap());
-    peaks.truncate(5);
+    peaks.truncate(config.peaks_per_window);
 
     peaks
 }
@@ -82,7 +101,7 @@ mod test {
 
         let h = p.frequency_hz(&c);
         let t = p.time_seconds(&c);
-        assert_eq!(h, 1981.0546875);
-        assert_eq!(t, 1.74149659864);
+        assert_eq!(h, 2156.25);
+        assert_eq!(t, 1.6);
     }
 }
@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// A compressed list of the (song_id, time_offset) postings of a single fingerprint.
+//
+// Stored as 8 bytes per posting, the postings make up nearly all of a database. Instead, postings
+// are kept sorted by (song_id, time_offset) and each one is encoded as the difference to the one
+// before it, with every difference written as a variable-length integer:
+//
+//   [song_id delta][time_offset]        when the song changes
+//   [0][time_offset delta]              for further postings within the same song
+//
+// Both deltas tend to be small, so most postings take 2-4 bytes. The same encoding is used in
+// memory and on disk.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "EncodedPostings", into = "EncodedPostings")]
+pub struct PostingList {
+    len: u32,
+    bytes: Vec<u8>,
+    // The posting encoded last, which new postings are encoded relative to. Kept so appending
+    // does not decode the whole list, and recovered by decoding it once when deserializing,
+    // which also rejects corrupt lists.
+    last: Option<(u32, u32)>,
+}
+
+// How a `PostingList` is serialized.
+#[derive(Serialize, Deserialize)]
+struct EncodedPostings {
+    len: u32,
+    bytes: Vec<u8>,
+}
+
+impl TryFrom<EncodedPostings> for PostingList {
+    type Error = Error;
+
+    fn try_from(encoded: EncodedPostings) -> Result<Self> {
+        let mut len = 0;
+        let mut last = None;
+        for posting in PostingIter::new(&encoded.bytes) {
+            len += 1;
+            last = Some(posting?);
+        }
+        if len != encoded.len {
+            return Err(Error::Corruption(format!(
+                "a posting list holds {len} postings, but records {}",
+                encoded.len
+            )));
+        }
+
+        Ok(Self {
+            len,
+            bytes: encoded.bytes,
+            last,
+        })
+    }
+}
+
+impl From<PostingList> for EncodedPostings {
+    fn from(list: PostingList) -> Self {
+        Self {
+            len: list.len,
+            bytes: list.bytes,
+        }
+    }
+}
+
+impl PostingList {
+    pub fn new(mut postings: Vec<(u32, u32)>) -> Self {
+        postings.sort_unstable();
+
+        let mut list = Self::default();
+        list.append_sorted(&postings);
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The number of bytes used by the encoded postings.
+    pub fn encoded_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn iter(&self) -> PostingIter<'_> {
+        PostingIter::new(&self.bytes)
+    }
+
+    pub fn decode(&self) -> Result<Vec<(u32, u32)>> {
+        let mut postings = Vec::with_capacity(self.len());
+        for posting in self.iter() {
+            postings.push(posting?);
+        }
+        Ok(postings)
+    }
+
+    // Adds postings to the list. When they all sort after the postings already in the list, as
+    // is the case when adding a new song, they are encoded onto the end. Otherwise the list is
+    // decoded and encoded again.
+    pub fn extend(&mut self, mut postings: Vec<(u32, u32)>) -> Result<()> {
+        postings.sort_unstable();
+
+        match (self.last, postings.first()) {
+            (_, None) => {}
+            (Some(last), Some(first)) if *first < last => {
+                postings.extend(self.decode()?);
+                *self = Self::new(postings);
+            }
+            _ => self.append_sorted(&postings),
+        }
+
+        Ok(())
+    }
+
+    // Removes the postings of the songs for which `keep` returns false.
+    pub fn retain_songs<F: Fn(u32) -> bool>(&mut self, keep: F) -> Result<()> {
+        let mut retained = Vec::new();
+        for posting in self.iter() {
+            let posting = posting?;
+            if keep(posting.0) {
+                retained.push(posting);
+            }
+        }
+        if retained.len() != self.len() {
+            *self = Self::new(retained);
+        }
+
+        Ok(())
+    }
+
+    fn append_sorted(&mut self, postings: &[(u32, u32)]) {
+        for &(song_id, time_offset) in postings {
+            match self.last {
+                Some((previous_song_id, previous_time_offset)) if previous_song_id == song_id => {
+                    write_varint(&mut self.bytes, 0);
+                    write_varint(&mut self.bytes, time_offset - previous_time_offset);
+                }
+                Some((previous_song_id, _)) => {
+                    write_varint(&mut self.bytes, song_id - previous_song_id);
+                    write_varint(&mut self.bytes, time_offset);
+                }
+                None => {
+                    write_varint(&mut self.bytes, song_id);
+                    write_varint(&mut self.bytes, time_offset);
+                }
+            }
+            self.last = Some((song_id, time_offset));
+        }
+        self.len += postings.len() as u32;
+    }
+}
+
+// Decodes postings from their encoded bytes, either those of a `PostingList` or a slice of a
+// file using the same encoding. Bytes which do not decode, such as those of a truncated file,
+// end the iteration with `Error::Corruption`.
+pub struct PostingIter<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    previous: Option<(u32, u32)>,
+}
+
+impl<'a> PostingIter<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            previous: None,
+        }
+    }
+}
+
+impl PostingIter<'_> {
+    fn decode_next(&mut self) -> Result<(u32, u32)> {
+        let song_delta = read_varint(self.bytes, &mut self.position)?;
+        let value = read_varint(self.bytes, &mut self.position)?;
+
+        let posting = match self.previous {
+            Some((song_id, time_offset)) if song_delta == 0 => {
+                (Some(song_id), time_offset.checked_add(value))
+            }
+            Some((song_id, _)) => (song_id.checked_add(song_delta), Some(value)),
+            None => (Some(song_delta), Some(value)),
+        };
+        match posting {
+            (Some(song_id), Some(time_offset)) => Ok((song_id, time_offset)),
+            _ => Err(Error::Corruption(String::from(
+                "a posting lies beyond the largest song id or time offset",
+            ))),
+        }
+    }
+}
+
+impl Iterator for PostingIter<'_> {
+    type Item = Result<(u32, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.bytes.len() {
+            return None;
+        }
+
+        let result = self.decode_next();
+        match &result {
+            Ok(posting) => self.previous = Some(*posting),
+            // Nothing after a corrupt posting can be decoded.
+            Err(_) => self.position = self.bytes.len(),
+        }
+        Some(result)
+    }
+}
+
+// LEB128: 7 bits per byte, least significant first, with the high bit set on all but the last.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// Fails for varints which are cut off, or too long for a u32.
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+    loop {
+        let Some(&byte) = bytes.get(*position) else {
+            return Err(Error::Corruption(String::from("a posting list ends early")));
+        };
+        *position += 1;
+        if shift == 28 && byte > 0x0F {
+            return Err(Error::Corruption(String::from(
+                "a posting does not fit in 32 bits",
+            )));
+        }
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::Error,
+        postings::{PostingIter, PostingList},
+    };
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let postings = vec![
+            (7, 120_000),
+            (0, 5),
+            (7, 300),
+            (u32::MAX, u32::MAX),
+            (0, 0),
+            (7, 300),
+        ];
+        let mut list = PostingList::new(postings.clone());
+
+        let mut sorted = postings.clone();
+        sorted.sort();
+        assert_eq!(list.len(), 6);
+        assert_eq!(list.decode().unwrap(), sorted);
+        assert!(list.encoded_len() < 8 * list.len());
+
+        // Appended after the last posting, and inserted before it.
+        list.extend(vec![(u32::MAX, u32::MAX), (9, 10)]).unwrap();
+        list.extend(vec![(8, 1)]).unwrap();
+        sorted.extend([(u32::MAX, u32::MAX), (9, 10), (8, 1)]);
+        sorted.sort();
+        assert_eq!(list.decode().unwrap(), sorted);
+
+        // Appending after a round trip through serialization picks up from the last posting.
+        let bincode_config = bincode::config::standard();
+        let encoded = bincode::serde::encode_to_vec(&list, bincode_config).unwrap();
+        let (mut decoded, _): (PostingList, usize) =
+            bincode::serde::decode_from_slice(&encoded, bincode_config).unwrap();
+        assert_eq!(decoded, list);
+        decoded.extend(vec![(u32::MAX, u32::MAX)]).unwrap();
+        list.extend(vec![(u32::MAX, u32::MAX)]).unwrap();
+        sorted.push((u32::MAX, u32::MAX));
+        assert_eq!(decoded.decode().unwrap(), sorted);
+
+        list.retain_songs(|song_id| song_id != 7).unwrap();
+        assert!(
+            list.decode()
+                .unwrap()
+                .iter()
+                .all(|(song_id, _)| *song_id != 7)
+        );
+        assert_eq!(list.len(), sorted.len() - 3);
+    }
+
+    #[test]
+    fn corrupt_postings() {
+        let list = PostingList::new(vec![(3, 200_000), (9, 70)]);
+        let bytes = list.as_bytes();
+
+        // Cut off in the middle of a varint, or running past 32 bits.
+        let mut postings = PostingIter::new(&bytes[..2]);
+        assert!(matches!(postings.next(), Some(Err(Error::Corruption(_)))));
+        assert!(postings.next().is_none());
+        let overlong = [0xFF, 0xFF, 0xFF, 0xFF, 0x1F, 0x00];
+        assert!(matches!(
+            PostingIter::new(&overlong).next(),
+            Some(Err(Error::Corruption(_)))
+        ));
+
+        // Deserializing validates the list.
+        let bincode_config = bincode::config::standard();
+        let encoded =
+            bincode::serde::encode_to_vec((2u32, &bytes[..bytes.len() - 1]), bincode_config)
+                .unwrap();
+        let decoded = bincode::serde::decode_from_slice::<PostingList, _>(&encoded, bincode_config);
+        assert!(decoded.is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use crate::error::{Error, Result};
+
+// The pole of the DC blocking filter, which puts its cutoff at about 5 Hz at 8000 Hz and below
+// 40 Hz at 48000 Hz.
+const DC_BLOCK_POLE: f32 = 0.995;
+
+// The length of the frames whose level decides what is silence, in seconds.
+const SILENCE_FRAME_SECONDS: f32 = 0.01;
+
+// How the level of audio is evened out before it is fingerprinted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    None,
+    // Scales the loudest sample to the target level.
+    Peak,
+    // Scales the RMS level, a measure of loudness, to the target level.
+    Rms,
+}
+
+impl Normalization {
+    // The level audio is scaled to without a target, in dBFS.
+    fn default_target_db(self) -> f32 {
+        match self {
+            Normalization::Rms => -20.0,
+            _ => -1.0,
+        }
+    }
+}
+
+// How samples are conditioned before their spectrogram is computed, in the order the steps are
+// applied. Every step is off by default. As with the other analysis parameters, songs and queries
+// must be conditioned alike to match, which databases enforce by recording the steps.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreprocessConfig {
+    // Removes the DC offset with a one-pole high-pass filter.
+    pub dc_block: bool,
+    // A band-pass, as a high-pass at the low cut and a low-pass at the high cut, in Hz.
+    pub low_cut_hz: Option<f32>,
+    pub high_cut_hz: Option<f32>,
+    // The coefficient of a pre-emphasis filter, y[n] = x[n] - c x[n - 1], such as 0.97, which
+    // lifts the high frequencies.
+    pub pre_emphasis: Option<f32>,
+    // Trims leading and trailing frames quieter than this, in dB below the loudest frame.
+    pub trim_silence_db: Option<f32>,
+    pub normalize: Normalization,
+    // The level normalization scales to, in dBFS. Defaults to -1 for peak and -20 for RMS.
+    pub target_db: Option<f32>,
+}
+
+impl PreprocessConfig {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    // Fails with `Error::InvalidInput` for parameters which cannot be applied at `sample_rate`.
+    pub fn validate(&self, sample_rate: f32) -> Result<()> {
+        let invalid = |message: &str| Err(Error::InvalidInput(String::from(message)));
+        let nyquist = sample_rate / 2.0;
+        let in_band = |hz: f32| hz.is_finite() && hz > 0.0 && hz < nyquist;
+
+        if self.low_cut_hz.is_some_and(|hz| !in_band(hz))
+            || self.high_cut_hz.is_some_and(|hz| !in_band(hz))
+        {
+            return invalid("band-pass cutoffs must be between 0 Hz and half the sample rate");
+        }
+        if let (Some(low), Some(high)) = (self.low_cut_hz, self.high_cut_hz)
+            && low >= high
+        {
+            return invalid("the low cut of the band-pass must be below its high cut");
+        }
+        if self
+            .pre_emphasis
+            .is_some_and(|coefficient| !(0.0..1.0).contains(&coefficient))
+        {
+            return invalid("the pre-emphasis coefficient must be at least 0 and below 1");
+        }
+        if self
+            .trim_silence_db
+            .is_some_and(|db| !(db.is_finite() && db < 0.0))
+        {
+            return invalid("the silence threshold must be below 0 dB");
+        }
+        if self
+            .target_db
+            .is_some_and(|db| !(db.is_finite() && db <= 0.0))
+        {
+            return invalid("the normalization target must be at most 0 dBFS");
+        }
+
+        Ok(())
+    }
+}
+
+// Applies every enabled step of `config` to mono samples at `sample_rate`.
+pub fn preprocess(samples: &[f32], config: &PreprocessConfig, sample_rate: f32) -> Vec<f32> {
+    let mut samples = samples.to_vec();
+
+    if config.dc_block {
+        samples = dc_block(&samples);
+    }
+    if let Some(cutoff_hz) = config.low_cut_hz {
+        samples = Biquad::high_pass(cutoff_hz, sample_rate).filter(&samples);
+    }
+    if let Some(cutoff_hz) = config.high_cut_hz {
+        samples = Biquad::low_pass(cutoff_hz, sample_rate).filter(&samples);
+    }
+    if let Some(coefficient) = config.pre_emphasis {
+        samples = pre_emphasis(&samples, coefficient);
+    }
+    if let Some(threshold_db) = config.trim_silence_db {
+        let frame_len = ((SILENCE_FRAME_SECONDS * sample_rate) as usize).max(1);
+        samples = trim_silence(&samples, threshold_db, frame_len).to_vec();
+    }
+    // Silence is left alone, as there is no level to scale it to.
+    let target = 10f32.powf(
+        config
+            .target_db
+            .unwrap_or(config.normalize.default_target_db())
+            / 20.0,
+    );
+    let level = match config.normalize {
+        Normalization::None => 0.0,
+        Normalization::Peak => samples.iter().fold(0.0, |peak, s| s.abs().max(peak)),
+        Normalization::Rms => rms(&samples),
+    };
+    if level > 0.0 {
+        let gain = target / level;
+        samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+
+    samples
+}
+
+// y[n] = x[n] - x[n - 1] + p y[n - 1], a zero at DC and a pole just inside it.
+fn dc_block(samples: &[f32]) -> Vec<f32> {
+    let (mut x1, mut y1) = (0.0, 0.0);
+    samples
+        .iter()
+        .map(|&x| {
+            let y = x - x1 + DC_BLOCK_POLE * y1;
+            (x1, y1) = (x, y);
+            y
+        })
+        .collect()
+}
+
+fn pre_emphasis(samples: &[f32], coefficient: f32) -> Vec<f32> {
+    let mut previous = 0.0;
+    samples
+        .iter()
+        .map(|&x| {
+            let y = x - coefficient * previous;
+            previous = x;
+            y
+        })
+        .collect()
+}
+
+// The samples from the first to the last frame within `threshold_db` of the loudest frame.
+fn trim_silence(samples: &[f32], threshold_db: f32, frame_len: usize) -> &[f32] {
+    let levels: Vec<f32> = samples.chunks(frame_len).map(rms).collect();
+    let loudest = levels
+        .iter()
+        .fold(0.0f32, |loudest, &level| loudest.max(level));
+    if loudest == 0.0 {
+        return &samples[..0];
+    }
+
+    let threshold = loudest * 10f32.powf(threshold_db / 20.0);
+    let first = levels.iter().position(|&level| level >= threshold);
+    let last = levels.iter().rposition(|&level| level >= threshold);
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            &samples[first * frame_len..((last + 1) * frame_len).min(samples.len())]
+        }
+        _ => &samples[..0],
+    }
+}
+
+// Converts mono samples from `from_rate` to `to_rate`. Samples are low-pass filtered first when
+// the rate goes down, so the frequencies above the new Nyquist frequency don't fold over into
+// lower ones.
+pub(crate) fn resample(samples: &[f32], from_rate: f32, to_rate: f32) -> Vec<f32> {
+    let step = from_rate as f64 / to_rate as f64;
+    let len = (samples.len() as f64 / step).round() as usize;
+    if step > 1.0 {
+        let filtered = Biquad::low_pass(0.45 * to_rate, from_rate).filter(samples);
+        interpolate(&filtered, len, step)
+    } else {
+        interpolate(samples, len, step)
+    }
+}
+
+// Reads through `samples` `ratio` times as fast, interpolating linearly between them.
+pub(crate) fn stretch(samples: &[f32], ratio: f32) -> Vec<f32> {
+    let len = (samples.len() as f64 / ratio as f64) as usize;
+    interpolate(samples, len, ratio as f64)
+}
+
+// `len` samples read from `samples` every `step` samples, interpolating linearly between them.
+fn interpolate(samples: &[f32], len: usize, step: f64) -> Vec<f32> {
+    (0..len)
+        .map(|index| {
+            let position = index as f64 * step;
+            let before = position as usize;
+            let fraction = (position - before as f64) as f32;
+            let first = samples.get(before).copied().unwrap_or(0.0);
+            let second = samples.get(before + 1).copied().unwrap_or(first);
+            first + (second - first) * fraction
+        })
+        .collect()
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+// A second order IIR filter, with the coefficients of the Audio EQ Cookbook and a Q of 1/√2 for
+// a flat pass band.
+pub(crate) struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    pub fn low_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate);
+        Self::normalized(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            cos,
+            alpha,
+        )
+    }
+
+    pub fn high_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate);
+        Self::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            cos,
+            alpha,
+        )
+    }
+
+    fn prewarp(cutoff_hz: f32, sample_rate: f32) -> (f32, f32) {
+        // The cutoff must stay below the Nyquist frequency.
+        let cutoff_hz = cutoff_hz.min(0.49 * sample_rate);
+        let omega = 2.0 * PI * cutoff_hz / sample_rate;
+        (omega.cos(), omega.sin() / (2.0 * FRAC_1_SQRT_2))
+    }
+
+    fn normalized(b: [f32; 3], cos: f32, alpha: f32) -> Self {
+        let a0 = 1.0 + alpha;
+        Self {
+            b: b.map(|b| b / a0),
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        }
+    }
+
+    pub fn filter(&self, samples: &[f32]) -> Vec<f32> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+
+    use crate::preprocess::{Normalization, PreprocessConfig, preprocess, resample, rms};
+
+    #[test]
+    fn resampling() {
+        let tone = |frequency: f32, sample_rate: f32| -> Vec<f32> {
+            (0..sample_rate as usize)
+                .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate).sin())
+                .collect()
+        };
+
+        // Up to a higher rate, the tone is only interpolated.
+        let resampled = resample(&tone(1000.0, 44100.0), 44100.0, 48000.0);
+        assert_eq!(resampled.len(), 48000);
+        assert!((rms(&resampled) - rms(&tone(1000.0, 48000.0))).abs() < 0.01);
+
+        // Down to a lower rate, tones below the new Nyquist frequency pass, while those above it
+        // are filtered out rather than folding over.
+        let resampled = resample(&tone(1000.0, 48000.0), 48000.0, 8000.0);
+        assert_eq!(resampled.len(), 8000);
+        assert!(rms(&resampled) > 0.65, "{}", rms(&resampled));
+        let resampled = resample(&tone(6000.0, 48000.0), 48000.0, 8000.0);
+        assert!(rms(&resampled) < 0.3, "{}", rms(&resampled));
+    }
+
+    #[test]
+    fn preprocessing_chain() {
+        let sample_rate = 8000.0;
+        // A second of quiet silence, a second of a quiet 440 Hz tone on a DC offset, and another
+        // second of silence.
+        let tone: Vec<f32> = (0..8000)
+            .map(|i| 0.2 + 0.01 * (2.0 * PI * 440.0 * i as f32 / sample_rate).sin())
+            .collect();
+        let samples: Vec<f32> = [vec![0.0; 8000], tone, vec![0.0; 8000]].concat();
+
+        // Without any step, the samples are left as they are.
+        let config = PreprocessConfig::default();
+        assert!(!config.is_enabled());
+        assert_eq!(preprocess(&samples, &config, sample_rate), samples);
+
+        let config = PreprocessConfig {
+            dc_block: true,
+            trim_silence_db: Some(-40.0),
+            normalize: Normalization::Peak,
+            ..PreprocessConfig::default()
+        };
+        config.validate(sample_rate).unwrap();
+        let processed = preprocess(&samples, &config, sample_rate);
+        // Only the tone is left, along with the frames the DC blocker takes to settle after it.
+        assert!(
+            (8000..=9600).contains(&processed.len()),
+            "{}",
+            processed.len()
+        );
+        let peak = processed.iter().fold(0.0f32, |peak, s| s.abs().max(peak));
+        assert!((peak - 10f32.powf(-1.0 / 20.0)).abs() < 1e-4);
+        // Once settled, the DC offset is gone.
+        let settled = &processed[4000..8000];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        assert!(mean.abs() < 0.01 * peak, "{mean}");
+
+        let config = PreprocessConfig {
+            low_cut_hz: Some(300.0),
+            high_cut_hz: Some(1000.0),
+            pre_emphasis: Some(0.97),
+            normalize: Normalization::Rms,
+            ..PreprocessConfig::default()
+        };
+        config.validate(sample_rate).unwrap();
+        let processed = preprocess(&samples, &config, sample_rate);
+        assert_eq!(processed.len(), samples.len());
+        assert!((rms(&processed) - 0.1).abs() < 1e-4);
+
+        for invalid in [
+            PreprocessConfig {
+                low_cut_hz: Some(1000.0),
+                high_cut_hz: Some(300.0),
+                ..PreprocessConfig::default()
+            },
+            PreprocessConfig {
+                high_cut_hz: Some(4000.0),
+                ..PreprocessConfig::default()
+            },
+            PreprocessConfig {
+                pre_emphasis: Some(1.0),
+                ..PreprocessConfig::default()
+            },
+            PreprocessConfig {
+                trim_silence_db: Some(10.0),
+                ..PreprocessConfig::default()
+            },
+        ] {
+            assert!(invalid.validate(sample_rate).is_err(), "{invalid:?}");
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    error::{Error, Result},
+    fft::{MagnitudeScale, Spectrogram},
+    fingerprint::MatchResult,
+    fingerprinter::Analysis,
+    metadata::SongMetaData,
+    peaks::Peak,
+};
+
+// Peaks of the query, and those lining up with a peak of the matched song.
+const PEAK_COLOUR: [u8; 3] = [230, 30, 30];
+const ALIGNED_PEAK_COLOUR: [u8; 3] = [40, 255, 40];
+// Peaks of the matched song, drawn as hollow squares around where query peaks should be.
+const SONG_PEAK_COLOUR: [u8; 3] = [255, 255, 255];
+
+// The magnitude of -100 dB, the quietest the heatmap tells apart.
+const DB_FLOOR_MAGNITUDE: f32 = 1e-5;
+
+// How the magnitudes of the spectrogram are coloured, from quiet to loud.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ColorMap {
+    // Perceptually uniform, from dark blue through green to yellow.
+    #[default]
+    Viridis,
+    // Perceptually uniform, from black through purple and orange to pale yellow.
+    Magma,
+    Gray,
+}
+
+impl ColorMap {
+    // The colour at `t` in [0, 1], between evenly spaced stops of the matplotlib colour maps.
+    fn colour(self, t: f32) -> [u8; 3] {
+        let stops: &[[u8; 3]] = match self {
+            ColorMap::Viridis => &[
+                [68, 1, 84],
+                [71, 44, 122],
+                [59, 81, 139],
+                [44, 113, 142],
+                [33, 144, 141],
+                [39, 173, 129],
+                [92, 200, 99],
+                [170, 220, 50],
+                [253, 231, 37],
+            ],
+            ColorMap::Magma => &[
+                [0, 0, 4],
+                [28, 16, 68],
+                [79, 18, 123],
+                [129, 37, 129],
+                [181, 54, 122],
+                [229, 80, 100],
+                [251, 135, 97],
+                [254, 194, 135],
+                [252, 253, 191],
+            ],
+            ColorMap::Gray => &[[0, 0, 0], [255, 255, 255]],
+        };
+
+        let position = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let index = (position as usize).min(stops.len() - 2);
+        let fraction = position - index as f32;
+        let (from, to) = (stops[index], stops[index + 1]);
+        [0, 1, 2].map(|channel| {
+            (from[channel] as f32 + (to[channel] as f32 - from[channel] as f32) * fraction).round()
+                as u8
+        })
+    }
+}
+
+// How a spectrogram is rendered.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RenderOptions {
+    pub color_map: ColorMap,
+    // The frequencies shown, in Hz, by default all of them.
+    pub min_frequency_hz: Option<f32>,
+    pub max_frequency_hz: Option<f32>,
+    // Magnitudes this many dB below the loudest are drawn in the quietest colour.
+    pub dynamic_range_db: f32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            color_map: ColorMap::default(),
+            min_frequency_hz: None,
+            max_frequency_hz: None,
+            dynamic_range_db: 80.0,
+        }
+    }
+}
+
+// What `inspect_song` found in a query, and its rendering.
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub analysis: Analysis,
+    // The song best matching the query, when compared against the database.
+    pub matched: Option<(SongMetaData, MatchResult)>,
+    // The peaks of the part of the matched song the query lines up with, in the windows of the
+    // query.
+    pub song_peaks: Vec<Peak>,
+    // The peaks of the query lining up with one of the song.
+    pub aligned: usize,
+    pub image: Image,
+}
+
+// An RGB image, row by row from the top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    fn set(&mut self, x: usize, y: usize, colour: [u8; 3]) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = colour;
+        }
+    }
+
+    pub fn write_png<W: Write>(&self, writer: W) -> Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let data = self.pixels.concat();
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(|err| match err {
+                png::EncodingError::IoError(err) => Error::Io(err),
+                err => Error::InvalidInput(format!("the image cannot be encoded: {err}")),
+            })
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_png(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Renders a spectrogram as a heatmap of its magnitudes in dB, a pixel per window and frequency
+// bin, with time running to the right and frequency up. Its `peaks` are overlaid, along with the
+// peaks of a matched song lined up with them, see `aligned_peaks`.
+pub fn render_spectrogram(
+    spectrogram: &Spectrogram,
+    peaks: &[Peak],
+    song_peaks: &[Peak],
+    options: &RenderOptions,
+) -> Result<Image> {
+    let config = &spectrogram.config;
+    let min_hz = options.min_frequency_hz.unwrap_or(f32::NEG_INFINITY);
+    let max_hz = options.max_frequency_hz.unwrap_or(f32::INFINITY);
+    let bins: Vec<usize> = (0..config.num_bins())
+        .filter(|&bin| (min_hz..=max_hz).contains(&config.bin_frequency_hz(bin)))
+        .collect();
+    let (Some(&lowest), Some(&highest)) = (bins.first(), bins.last()) else {
+        return Err(Error::InvalidInput(String::from(
+            "the spectrogram has no frequency bins in the range to render",
+        )));
+    };
+    if spectrogram.data.is_empty() {
+        return Err(Error::InvalidInput(String::from(
+            "the spectrogram has no windows to render",
+        )));
+    }
+    if !(options.dynamic_range_db.is_finite() && options.dynamic_range_db > 0.0) {
+        return Err(Error::InvalidInput(String::from(
+            "the dynamic range must be positive",
+        )));
+    }
+
+    let decibels = |magnitude: f32| match config.magnitude_scale {
+        MagnitudeScale::Linear => 20.0 * magnitude.max(DB_FLOOR_MAGNITUDE).log10(),
+        MagnitudeScale::Db => magnitude,
+        MagnitudeScale::Log => 20.0 * magnitude.exp_m1().max(DB_FLOOR_MAGNITUDE).log10(),
+    };
+    let loudest = spectrogram
+        .data
+        .iter()
+        .flat_map(|window| &window[lowest..=highest])
+        .fold(f32::NEG_INFINITY, |loudest, &magnitude| {
+            loudest.max(decibels(magnitude))
+        });
+
+    let width = spectrogram.data.len();
+    let height = highest - lowest + 1;
+    let mut image = Image {
+        width,
+        height,
+        pixels: vec![[0; 3]; width * height],
+    };
+    for (x, window) in spectrogram.data.iter().enumerate() {
+        for (bin, &magnitude) in window.iter().enumerate().take(highest + 1).skip(lowest) {
+            let t = 1.0 - (loudest - decibels(magnitude)) / options.dynamic_range_db;
+            image.set(x, highest - bin, options.color_map.colour(t));
+        }
+    }
+
+    // The pixel of a peak, if it is within the image.
+    let pixel = |peak: &Peak| {
+        (lowest..=highest)
+            .contains(&peak.freq_bin)
+            .then(|| (peak.time_bin, highest - peak.freq_bin))
+    };
+    for (x, y) in song_peaks.iter().filter_map(pixel) {
+        // Pixels left of or above the image wrap around to far past its end, and are skipped.
+        let (left, top) = (x.wrapping_sub(2), y.wrapping_sub(2));
+        let (right, bottom) = (x + 2, y + 2);
+        for offset in 0..5 {
+            image.set(left.wrapping_add(offset), top, SONG_PEAK_COLOUR);
+            image.set(left.wrapping_add(offset), bottom, SONG_PEAK_COLOUR);
+            image.set(left, top.wrapping_add(offset), SONG_PEAK_COLOUR);
+            image.set(right, top.wrapping_add(offset), SONG_PEAK_COLOUR);
+        }
+    }
+    let aligned = aligned_peaks(peaks, song_peaks);
+    for (peak, aligned) in peaks.iter().zip(aligned) {
+        let Some((x, y)) = pixel(peak) else {
+            continue;
+        };
+        let colour = if aligned {
+            ALIGNED_PEAK_COLOUR
+        } else {
+            PEAK_COLOUR
+        };
+        for (dx, dy) in [(0, 1), (1, 0), (1, 1), (1, 2), (2, 1)] {
+            image.set((x + dx).wrapping_sub(1), (y + dy).wrapping_sub(1), colour);
+        }
+    }
+
+    Ok(image)
+}
+
+// Whether every peak lines up with a peak of `song_peaks`, in the same frequency bin and at most
+// one window apart, as offsets between songs and queries rarely fall on a whole window.
+pub fn aligned_peaks(peaks: &[Peak], song_peaks: &[Peak]) -> Vec<bool> {
+    peaks
+        .iter()
+        .map(|peak| {
+            song_peaks.iter().any(|song_peak| {
+                song_peak.freq_bin == peak.freq_bin
+                    && song_peak.time_bin.abs_diff(peak.time_bin) <= 1
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        fft::{Spectrogram, SpectrogramConfig},
+        peaks::Peak,
+        render::{ColorMap, RenderOptions, aligned_peaks, render_spectrogram},
+    };
+
+    #[test]
+    fn render_png() {
+        // Four windows of eight bins at 1000 Hz each, growing louder up to bin 5.
+        let config = SpectrogramConfig {
+            window_size: 16,
+            stride: 16,
+            sample_rate: 16000.0,
+            ..SpectrogramConfig::default()
+        };
+        let window: Vec<f32> = (0..8).map(|bin| 10f32.powi(bin.min(5))).collect();
+        let spectrogram = Spectrogram {
+            data: vec![window; 4],
+            config,
+        };
+        let peaks = [Peak::new(1, 5, 1e5), Peak::new(3, 2, 1e2)];
+        let song_peaks = [Peak::new(2, 5, 1e5)];
+        assert_eq!(aligned_peaks(&peaks, &song_peaks), [true, false]);
+
+        // Bins 0 to 1000 Hz are left out, and 100 dB is drawn from black to white.
+        let options = RenderOptions {
+            color_map: ColorMap::Gray,
+            min_frequency_hz: Some(1500.0),
+            dynamic_range_db: 100.0,
+            ..RenderOptions::default()
+        };
+        let image = render_spectrogram(&spectrogram, &[], &[], &options).unwrap();
+        assert_eq!((image.width, image.height), (4, 6));
+        // The top row is bin 7, and the bottom row bin 2, 60 dB below it.
+        assert_eq!(image.pixels[0], [255, 255, 255]);
+        assert_eq!(image.pixels[5 * 4], [102, 102, 102]);
+
+        let image = render_spectrogram(&spectrogram, &peaks, &song_peaks, &options).unwrap();
+        // The aligned peak at bin 5, the third row, and the other at bin 2, the bottom row.
+        assert_eq!(image.pixels[2 * 4 + 1], [40, 255, 40]);
+        assert_eq!(image.pixels[5 * 4 + 3], [230, 30, 30]);
+
+        let mut png = Vec::new();
+        image.write_png(Cursor::new(&mut png)).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let options = RenderOptions {
+            min_frequency_hz: Some(9000.0),
+            ..options
+        };
+        assert!(render_spectrogram(&spectrogram, &peaks, &[], &options).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{audio::AudioFormat, error::Result};
+
+// Which files in a directory tree to analyze. By default, files with the extension of any format
+// this build decodes.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    // Glob patterns matched against the path relative to the scanned directory, ignoring case.
+    // A file is analyzed when it matches any of `include` and none of `exclude`.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // Descend into symlinked directories and analyze symlinked files. Otherwise, symlinks are
+    // skipped.
+    pub follow_symlinks: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            include: AudioFormat::ALL
+                .into_iter()
+                .filter(|format| format.is_supported())
+                .flat_map(AudioFormat::extensions)
+                .map(|extension| format!("*.{extension}"))
+                .collect(),
+            exclude: Vec::new(),
+            follow_symlinks: false,
+        }
+    }
+}
+
+// Recursively finds the files under `directory` selected by `options`, sorted by path so songs
+// are always added in the same order.
+pub fn find_audio_files(directory: &Path, options: &ScanOptions) -> Result<Vec<PathBuf>> {
+    let include = build_glob_set(&options.include)?;
+    let exclude = build_glob_set(&options.exclude)?;
+
+    let mut files = Vec::new();
+    // The directories already visited, to not loop forever on symlink cycles.
+    let mut visited = HashSet::new();
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        if !visited.insert(fs::canonicalize(&current)?) {
+            log::debug!("Skipping {:?}, already visited", current);
+            continue;
+        }
+
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            let mut file_type = fs::symlink_metadata(&path)?.file_type();
+            if file_type.is_symlink() {
+                if !options.follow_symlinks {
+                    log::debug!("Skipping symlink {:?}", path);
+                    continue;
+                }
+                file_type = match fs::metadata(&path) {
+                    Ok(metadata) => metadata.file_type(),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        log::warn!("Skipping broken symlink {:?}", path);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+            }
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                let relative_path = path.strip_prefix(directory).unwrap_or(&path);
+                if include.is_match(relative_path) && !exclude.is_match(relative_path) {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    files.sort();
+    if options.follow_symlinks {
+        // The same file may be reachable through several links, only keep the first path to it.
+        let mut seen = HashSet::new();
+        files.retain(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone())));
+    }
+
+    Ok(files)
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(glob(pattern)?);
+    }
+    builder.build()
+}
+
+fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern).case_insensitive(true).build()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::scan::{ScanOptions, find_audio_files};
+
+    #[test]
+    fn recursive_filtered_scan() {
+        let root = crate::temp_path("scan");
+        let directory = root.join("tree");
+        for file in [
+            "tree/a.wav",
+            "tree/notes.txt",
+            "tree/album/b.WAV",
+            "tree/album/demos/c.wav",
+            "tree/other/d.wav",
+            "tree/other/f.mp3",
+            "outside/e.wav",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let relative = |options: &ScanOptions| -> Vec<String> {
+            find_audio_files(&directory, options)
+                .unwrap()
+                .iter()
+                .map(|path| {
+                    let path = path.strip_prefix(&directory).unwrap();
+                    path.to_string_lossy().replace('\\', "/")
+                })
+                .collect()
+        };
+
+        // By default, MP3 files are only found when they can be decoded.
+        let options = ScanOptions::default();
+        let mut expected = vec!["a.wav", "album/b.WAV", "album/demos/c.wav", "other/d.wav"];
+        if cfg!(feature = "mp3") {
+            expected.push("other/f.mp3");
+        }
+        assert_eq!(relative(&options), expected);
+
+        let options = ScanOptions {
+            exclude: vec![String::from("**/demos/**"), String::from("other/*")],
+            ..ScanOptions::default()
+        };
+        assert_eq!(relative(&options), ["a.wav", "album/b.WAV"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink(root.join("outside"), directory.join("external")).unwrap();
+            // A cycle, which must not be followed forever.
+            symlink(&directory, directory.join("album/up")).unwrap();
+
+            let options = ScanOptions {
+                include: vec![String::from("external/*.wav"), String::from("a.wav")],
+                ..ScanOptions::default()
+            };
+            assert_eq!(relative(&options), ["a.wav"]);
+
+            let options = ScanOptions {
+                follow_symlinks: true,
+                ..options
+            };
+            assert_eq!(relative(&options), ["a.wav", "external/e.wav"]);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::AnalysisConfig,
+    error::Result,
+    fingerprint::{AnalysisParameters, Fingerprint, generate_fingerprints},
+    metadata::SongMetaData,
+    peaks::Peak,
+    storage::{IndexSummary, write_temporary},
+};
+
+// Rewriting the whole index every time a song is added gets slower the larger the index grows.
+// Instead, every ingestion appends a self-contained segment to the journal, a directory next to
+// the index. Segments are replayed on top of the index when loading, and merged into it by
+// `FingerprintDB::compact`.
+//
+// `songs` and `parameters` come first, see `IndexSummary`.
+#[derive(Serialize, Deserialize)]
+pub struct Segment {
+    pub songs: Vec<SongMetaData>,
+    pub parameters: AnalysisParameters,
+    pub postings: Vec<(Fingerprint, u32, u32)>, // (fingerprint, song_id, time_offset)
+}
+
+impl Segment {
+    pub fn new(metadata: SongMetaData, peaks: &[Peak], config: &AnalysisConfig) -> Self {
+        log::info!(
+            "Creating segment for song: {} with title: {}",
+            metadata.song_id,
+            metadata.title
+        );
+        let postings = generate_fingerprints(peaks, config)
+            .into_iter()
+            .map(|(fingerprint, time_offset)| (fingerprint, metadata.song_id, time_offset))
+            .collect();
+
+        Self {
+            songs: vec![metadata],
+            parameters: AnalysisParameters::new(config),
+            postings,
+        }
+    }
+
+    // Moves the songs of `other` into this segment, so they are written together.
+    pub fn append(&mut self, mut other: Segment) -> Result<()> {
+        self.parameters.check(&other.parameters)?;
+        self.songs.append(&mut other.songs);
+        self.postings.append(&mut other.postings);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct SegmentHeader {
+    songs: Vec<SongMetaData>,
+    parameters: AnalysisParameters,
+}
+
+pub struct Journal {
+    directory: PathBuf,
+}
+
+impl Journal {
+    const SEGMENT_PREFIX: &str = "segment-";
+    const SEGMENT_EXTENSION: &str = "bin";
+
+    // The journal of `audio_fingerprint.db` lives in `audio_fingerprint.db.journal/`.
+    pub fn for_database<P: AsRef<Path>>(db_path: P) -> Self {
+        let mut directory = OsString::from(db_path.as_ref());
+        directory.push(".journal");
+
+        Self {
+            directory: PathBuf::from(directory),
+        }
+    }
+
+    pub fn append(&self, segment: &Segment) -> Result<PathBuf> {
+        fs::create_dir_all(&self.directory)?;
+
+        let temporary_path = write_temporary(&self.directory.join("segment"), |writer| {
+            let bincode_config = bincode::config::standard();
+            bincode::serde::encode_into_std_write(segment, writer, bincode_config)?;
+            Ok(())
+        })?;
+
+        // Another writer may append a segment between listing the journal and linking this one,
+        // taking the same sequence number. Linking never replaces an existing file, so whoever
+        // comes second moves on to the next number, and no segment is ever overwritten.
+        let mut sequence_number = match self.segment_paths()?.last() {
+            Some(path) => Self::sequence_number(path).map_or(0, |n| n + 1),
+            None => 0,
+        };
+        let linked = loop {
+            let segment_path = self.directory.join(format!(
+                "{}{:010}.{}",
+                Self::SEGMENT_PREFIX,
+                sequence_number,
+                Self::SEGMENT_EXTENSION
+            ));
+            match fs::hard_link(&temporary_path, &segment_path) {
+                Ok(()) => break Ok(segment_path),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => sequence_number += 1,
+                Err(err) => break Err(err),
+            }
+        };
+        fs::remove_file(&temporary_path)?;
+        let segment_path = linked?;
+        log::info!(
+            "Appended segment with {} songs and {} fingerprints to {:?}",
+            segment.songs.len(),
+            segment.postings.len(),
+            segment_path
+        );
+
+        Ok(segment_path)
+    }
+
+    // Lists the segment files in the order they were appended.
+    pub fn segment_paths(&self) -> Result<Vec<PathBuf>, io::Error> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if Self::sequence_number(&path).is_some() {
+                paths.push(path);
+            }
+        }
+        // The sequence numbers are zero-padded, so lexicographic order is append order.
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    pub fn read_segments(&self) -> Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+        for path in self.segment_paths()? {
+            let reader = BufReader::new(File::open(path)?);
+            let bincode_config = bincode::config::standard();
+            segments.push(bincode::serde::decode_from_reader(reader, bincode_config)?);
+        }
+
+        Ok(segments)
+    }
+
+    // Returns the ids of all songs in the journal and the parameters they were analyzed with,
+    // without decoding any postings.
+    pub fn summary(&self) -> Result<IndexSummary> {
+        let mut summary = IndexSummary::default();
+        for path in self.segment_paths()? {
+            let reader = BufReader::new(File::open(path)?);
+            let bincode_config = bincode::config::standard();
+            let header: SegmentHeader = bincode::serde::decode_from_reader(reader, bincode_config)?;
+            summary
+                .song_ids
+                .extend(header.songs.iter().map(|song| song.song_id));
+            summary.parameters.get_or_insert(header.parameters);
+        }
+
+        Ok(summary)
+    }
+
+    // Returns the song id to assign to the next ingested song, given the ids of the songs already
+    // in the main index.
+    pub fn next_song_id(&self, indexed_song_ids: Vec<u32>) -> Result<u32> {
+        let mut song_ids = indexed_song_ids;
+        song_ids.extend(self.summary()?.song_ids);
+
+        Ok(song_ids.iter().max().map_or(0, |id| id + 1))
+    }
+
+    pub fn clear(&self) -> Result<(), io::Error> {
+        for path in self.segment_paths()? {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn sequence_number(path: &Path) -> Option<u32> {
+        if path.extension()? != Self::SEGMENT_EXTENSION {
+            return None;
+        }
+        path.file_stem()?
+            .to_str()?
+            .strip_prefix(Self::SEGMENT_PREFIX)?
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::AnalysisConfig,
+        fingerprint::FingerprintDB,
+        metadata::SongMetaData,
+        peaks::Peak,
+        segment::{Journal, Segment},
+    };
+
+    #[test]
+    fn journal_replay_and_compaction() {
+        let db_path = crate::temp_path("journal.db");
+        let journal = Journal::for_database(&db_path);
+        let config = AnalysisConfig::default();
+        let peaks: Vec<Peak> = (0..50)
+            .map(|i| Peak::new(i * 4, 10 + (i * 7) % 200, 1.0))
+            .collect();
+
+        for title in ["first", "second"] {
+            let song_id = journal
+                .next_song_id(FingerprintDB::index_summary(&db_path).unwrap().song_ids)
+                .unwrap();
+            let metadata = SongMetaData {
+                song_id,
+                title: String::from(title),
+                ..SongMetaData::default()
+            };
+            journal
+                .append(&Segment::new(metadata, &peaks, &config))
+                .unwrap();
+        }
+        assert_eq!(journal.segment_paths().unwrap().len(), 2);
+
+        let replayed = FingerprintDB::load_or_create(&db_path).unwrap();
+        assert_eq!(replayed.songs.len(), 2);
+
+        let compacted = FingerprintDB::compact(&db_path).unwrap();
+        assert!(journal.segment_paths().unwrap().is_empty());
+        assert_eq!(compacted.total_fingerprints, replayed.total_fingerprints);
+        assert_eq!(
+            journal
+                .next_song_id(FingerprintDB::index_summary(&db_path).unwrap().song_ids)
+                .unwrap(),
+            2
+        );
+
+        let loaded = FingerprintDB::load(&db_path).unwrap();
+        assert_eq!(loaded.songs.len(), 2);
+
+        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_dir(journal.directory).unwrap();
+    }
+
+    #[test]
+    fn concurrent_appends() {
+        let db_path = crate::temp_path("concurrent.db");
+        let config = AnalysisConfig::default();
+        let peaks: Vec<Peak> = (0..50)
+            .map(|i| Peak::new(i * 4, 10 + (i * 7) % 200, 1.0))
+            .collect();
+
+        // Every writer lists the journal on its own, so many of them pick the same sequence number
+        // and have to move on to the next.
+        let mut segment_paths: Vec<_> = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|song_id| {
+                    let (db_path, config, peaks) = (&db_path, &config, &peaks);
+                    scope.spawn(move || {
+                        let metadata = SongMetaData {
+                            song_id,
+                            ..SongMetaData::default()
+                        };
+                        Journal::for_database(db_path)
+                            .append(&Segment::new(metadata, peaks, config))
+                            .unwrap()
+                    })
+                })
+                .collect();
+            writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .collect()
+        });
+        segment_paths.sort();
+        segment_paths.dedup();
+        assert_eq!(segment_paths.len(), 8);
+
+        let journal = Journal::for_database(&db_path);
+        assert_eq!(journal.segment_paths().unwrap(), segment_paths);
+        let mut song_ids: Vec<u32> = journal
+            .read_segments()
+            .unwrap()
+            .iter()
+            .map(|segment| segment.songs[0].song_id)
+            .collect();
+        song_ids.sort();
+        assert_eq!(song_ids, (0..8).collect::<Vec<_>>());
+
+        // Only the segments are left, none of the temporary files they were written to.
+        for path in segment_paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_dir(journal.directory).unwrap();
+    }
+}
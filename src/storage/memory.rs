@@ -0,0 +1,82 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::{
+    fingerprint::{AnalysisParameters, Fingerprint, FingerprintDB},
+    metadata::SongMetaData,
+    storage::{FingerprintStore, Posting, StoreResult},
+};
+
+impl FingerprintStore for FingerprintDB {
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()> {
+        self.total_fingerprints += postings.len();
+
+        // Group the postings first, so every posting list is only extended once.
+        let mut grouped: HashMap<Fingerprint, Vec<(u32, u32)>> = HashMap::new();
+        for &(fingerprint, song_id, time_offset) in postings {
+            grouped
+                .entry(fingerprint)
+                .or_default()
+                .push((song_id, time_offset))
+        }
+        for (fingerprint, postings) in grouped {
+            self.database
+                .entry(fingerprint)
+                .or_default()
+                .extend(postings)?;
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>> {
+        Ok(match self.database.get(&fingerprint) {
+            Some(postings) => Cow::Owned(postings.decode()?),
+            None => Cow::Borrowed(&[]),
+        })
+    }
+
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()> {
+        self.songs.insert(metadata.song_id, metadata);
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        Ok(self.songs.get(&song_id).cloned())
+    }
+
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        for postings in self.database.values_mut() {
+            let before = postings.len();
+            postings.retain_songs(|id| id != song_id)?;
+            self.total_fingerprints -= before - postings.len();
+        }
+        self.database.retain(|_, postings| !postings.is_empty());
+
+        Ok(self.songs.remove(&song_id))
+    }
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>> {
+        let mut songs: Vec<SongMetaData> = self.songs.values().cloned().collect();
+        songs.sort_by_key(|song| song.song_id);
+        Ok(songs)
+    }
+
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>> {
+        Ok(Box::new(self.database.iter().flat_map(
+            |(fingerprint, postings)| {
+                postings.iter().map(|posting| {
+                    posting.map(|(song_id, time_offset)| (*fingerprint, song_id, time_offset))
+                })
+            },
+        )))
+    }
+
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>> {
+        Ok(self.parameters)
+    }
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()> {
+        self.parameters = Some(parameters);
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::Error,
+    fingerprint::{AnalysisParameters, Fingerprint, FingerprintDB},
+    metadata::SongMetaData,
+    postings::{PostingIter, PostingList},
+    segment::Journal,
+    storage::{FingerprintStore, IndexSummary, Posting, StoreResult, write_atomically},
+};
+
+// An index stored in a flat file which is memory-mapped rather than decoded up front, so opening
+// even a large database is instant and only the pages touched by lookups are ever read.
+//
+// File layout, all integers little endian:
+//
+//   magic             8 bytes, `MAGIC`
+//   version           u32, `VERSION`
+//   catalogue_length  u64
+//   catalogue         bincode encoded `Catalogue`, `catalogue_length` bytes
+//   entry_count       u64
+//   entries           `entry_count` x [fingerprint u32][first_byte u64], sorted by fingerprint
+//   postings          the encoded `PostingList` of every entry, back to back, with the list of
+//                     an entry starting at its `first_byte` and ending where the next one starts
+//
+// The mapped file is never modified in place. Songs added after opening are kept in memory
+// until `save` writes a new file and swaps it in.
+pub struct MmapStore {
+    path: PathBuf,
+    mmap: Option<Mmap>,
+    entry_count: usize,
+    entries_start: usize,
+    postings_start: usize,
+    songs: HashMap<u32, SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+    // Postings inserted since the file was written.
+    pending: FingerprintDB,
+    // Songs whose postings are still in the file, but have been removed.
+    removed: HashSet<u32>,
+}
+
+// The songs of the index and the parameters they were analyzed with.
+#[derive(Serialize, Deserialize)]
+struct Catalogue {
+    songs: Vec<SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+}
+
+const MAGIC: &[u8; 8] = b"AFPMMIDX";
+const VERSION: u32 = 1;
+// Magic, version and catalogue length.
+const HEADER_SIZE: usize = 20;
+const ENTRY_SIZE: usize = 12;
+
+impl MmapStore {
+    // Maps the index at `path`, if there is one, and replays its journal on top.
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let mut store = Self::open_index(&path)?;
+        store.replay_journal(&Journal::for_database(&path))?;
+
+        Ok(store)
+    }
+
+    // Maps only the index at `path`, ignoring the journal.
+    pub fn open_index<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        log::debug!(
+            "Opening memory-mapped fingerprint database {:?}",
+            path.as_ref()
+        );
+        let mut store = Self::empty(path);
+        store.map_file()?;
+
+        Ok(store)
+    }
+
+    // An empty store which will be written to `path` on `save`.
+    pub fn empty<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            mmap: None,
+            entry_count: 0,
+            entries_start: 0,
+            postings_start: 0,
+            songs: HashMap::new(),
+            parameters: None,
+            pending: FingerprintDB::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    // Whether any postings were inserted or removed since the file was written.
+    pub fn has_changes(&self) -> bool {
+        !self.pending.database.is_empty() || !self.removed.is_empty()
+    }
+
+    // Writes every song and posting, including those held in memory, to a new index file which
+    // then replaces the mapped one.
+    pub fn save(&mut self) -> StoreResult<()> {
+        let catalogue = Catalogue {
+            songs: self.songs()?,
+            parameters: self.parameters,
+        };
+        let mut grouped: BTreeMap<u32, Vec<(u32, u32)>> = BTreeMap::new();
+        for posting in self.postings()? {
+            let (fingerprint, song_id, time_offset) = posting?;
+            grouped
+                .entry(fingerprint.to_bits())
+                .or_default()
+                .push((song_id, time_offset));
+        }
+        log::info!(
+            "Saving memory-mapped fingerprint database with {} songs and {} unique fingerprints",
+            catalogue.songs.len(),
+            grouped.len()
+        );
+
+        write_atomically(&self.path, |writer| {
+            let bincode_config = bincode::config::standard();
+            let encoded_catalogue = bincode::serde::encode_to_vec(&catalogue, bincode_config)?;
+
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            writer.write_all(&(encoded_catalogue.len() as u64).to_le_bytes())?;
+            writer.write_all(&encoded_catalogue)?;
+            writer.write_all(&(grouped.len() as u64).to_le_bytes())?;
+            let fingerprints: Vec<u32> = grouped.keys().copied().collect();
+
+            let posting_lists: Vec<PostingList> =
+                grouped.into_values().map(PostingList::new).collect();
+
+            let mut first_byte = 0u64;
+            for (fingerprint, postings) in fingerprints.iter().zip(posting_lists.iter()) {
+                writer.write_all(&fingerprint.to_le_bytes())?;
+                writer.write_all(&first_byte.to_le_bytes())?;
+                first_byte += postings.encoded_len() as u64;
+            }
+            for postings in posting_lists.iter() {
+                writer.write_all(postings.as_bytes())?;
+            }
+            Ok(())
+        })?;
+
+        self.pending = FingerprintDB::new();
+        self.removed.clear();
+        self.map_file()
+    }
+
+    // Writes the journal segments into the index file and clears the journal.
+    pub fn compact<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        log::info!("Compacting memory-mapped fingerprint database");
+        let mut store = Self::open(&path)?;
+        store.save()?;
+        Journal::for_database(&path).clear()?;
+
+        Ok(store)
+    }
+
+    // Returns the songs and parameters of the index file, reading only the catalogue section.
+    pub fn index_summary<P: AsRef<Path>>(path: P) -> StoreResult<IndexSummary> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(IndexSummary::default());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let file_length = file.metadata()?.len() as usize;
+        let mut header = [0u8; HEADER_SIZE];
+        if file_length < header.len() {
+            return Err(ends_early());
+        }
+        file.read_exact(&mut header)?;
+        let catalogue_end = check_header(&header, file_length)?;
+        let mut encoded_catalogue = vec![0u8; catalogue_end - HEADER_SIZE];
+        file.read_exact(&mut encoded_catalogue)?;
+
+        let bincode_config = bincode::config::standard();
+        let (catalogue, _): (Catalogue, usize) =
+            bincode::serde::decode_from_slice(&encoded_catalogue, bincode_config)?;
+        Ok(IndexSummary {
+            song_ids: catalogue.songs.iter().map(|song| song.song_id).collect(),
+            parameters: catalogue.parameters,
+        })
+    }
+
+    fn map_file(&mut self) -> StoreResult<()> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        // SAFETY: The index file is only ever replaced through a rename, never written in place,
+        // so the mapped contents do not change underneath us.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let catalogue_end = check_header(&mmap, mmap.len())?;
+        let bincode_config = bincode::config::standard();
+        let (catalogue, _): (Catalogue, usize) =
+            bincode::serde::decode_from_slice(&mmap[HEADER_SIZE..catalogue_end], bincode_config)?;
+
+        // Every entry is checked to lie within the file here, so looking one up cannot fail.
+        // Where its postings lie is only checked once they are read.
+        let entry_count = read_u64(&mmap, catalogue_end)?;
+        self.entries_start = catalogue_end + 8;
+        self.postings_start = section_end(
+            self.entries_start,
+            entry_count.saturating_mul(ENTRY_SIZE as u64),
+            mmap.len(),
+        )?;
+        self.entry_count = entry_count as usize;
+        self.songs = catalogue
+            .songs
+            .into_iter()
+            .map(|song| (song.song_id, song))
+            .collect();
+        self.parameters = catalogue.parameters;
+        self.mmap = Some(mmap);
+
+        Ok(())
+    }
+
+    // Returns (fingerprint, first_byte) of the entry at `index`.
+    fn entry(&self, mmap: &[u8], index: usize) -> StoreResult<(u32, usize)> {
+        let position = self.entries_start + index * ENTRY_SIZE;
+        Ok((
+            read_u32(mmap, position)?,
+            read_u64(mmap, position + 4)? as usize,
+        ))
+    }
+
+    fn mapped_postings<'a>(
+        &'a self,
+        mmap: &'a [u8],
+        index: usize,
+    ) -> StoreResult<impl Iterator<Item = StoreResult<(u32, u32)>> + 'a> {
+        let (_, first_byte) = self.entry(mmap, index)?;
+        let end_byte = if index + 1 < self.entry_count {
+            self.entry(mmap, index + 1)?.1
+        } else {
+            mmap.len() - self.postings_start
+        };
+
+        let bytes = mmap[self.postings_start..]
+            .get(first_byte..end_byte)
+            .ok_or_else(|| {
+                Error::Corruption(format!(
+                    "the postings of entry {index} lie outside the index file"
+                ))
+            })?;
+        Ok(PostingIter::new(bytes).filter(
+            |posting| !matches!(posting, Ok((song_id, _)) if self.removed.contains(song_id)),
+        ))
+    }
+}
+
+impl FingerprintStore for MmapStore {
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()> {
+        self.pending.insert_postings(postings)
+    }
+
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>> {
+        let mut postings = Vec::new();
+        if let Some(mmap) = &self.mmap {
+            let bits = fingerprint.to_bits();
+            // Binary search over the sorted entries.
+            let (mut low, mut high) = (0, self.entry_count);
+            while low < high {
+                let middle = (low + high) / 2;
+                let (entry_fingerprint, _) = self.entry(mmap, middle)?;
+                if entry_fingerprint < bits {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            if low < self.entry_count && self.entry(mmap, low)?.0 == bits {
+                for posting in self.mapped_postings(mmap, low)? {
+                    postings.push(posting?);
+                }
+            }
+        }
+        postings.extend_from_slice(&self.pending.lookup(fingerprint)?);
+
+        Ok(Cow::Owned(postings))
+    }
+
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()> {
+        self.songs.insert(metadata.song_id, metadata);
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        Ok(self.songs.get(&song_id).cloned())
+    }
+
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        self.pending.remove_song(song_id)?;
+        self.removed.insert(song_id);
+
+        Ok(self.songs.remove(&song_id))
+    }
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>> {
+        let mut songs: Vec<SongMetaData> = self.songs.values().cloned().collect();
+        songs.sort_by_key(|song| song.song_id);
+        Ok(songs)
+    }
+
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>> {
+        let mapped = self.mmap.iter().flat_map(move |mmap| {
+            (0..self.entry_count).flat_map(move |index| {
+                let postings: Box<dyn Iterator<Item = StoreResult<Posting>>> = match self
+                    .entry(mmap, index)
+                    .and_then(|(bits, _)| {
+                        Ok((
+                            Fingerprint::from_bits(bits),
+                            self.mapped_postings(mmap, index)?,
+                        ))
+                    }) {
+                    Ok((fingerprint, postings)) => Box::new(postings.map(move |posting| {
+                        posting.map(|(song_id, time_offset)| (fingerprint, song_id, time_offset))
+                    })),
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                };
+                postings
+            })
+        });
+
+        Ok(Box::new(mapped.chain(self.pending.postings()?)))
+    }
+
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>> {
+        Ok(self.parameters)
+    }
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()> {
+        self.parameters = Some(parameters);
+        Ok(())
+    }
+}
+
+// Checks the magic bytes and version at the start of `bytes`, the start of an index file of
+// `file_length` bytes, and returns where its catalogue ends.
+fn check_header(bytes: &[u8], file_length: usize) -> StoreResult<usize> {
+    if bytes.get(..8) != Some(&MAGIC[..]) {
+        return Err(Error::Corruption(String::from(
+            "not a memory-mapped fingerprint database",
+        )));
+    }
+    let version = read_u32(bytes, 8)?;
+    if version != VERSION {
+        return Err(Error::Corruption(format!(
+            "version {version} of the memory-mapped index is not supported, only {VERSION}"
+        )));
+    }
+    section_end(HEADER_SIZE, read_u64(bytes, 12)?, file_length)
+}
+
+fn ends_early() -> Error {
+    Error::Corruption(String::from("the index file ends early"))
+}
+
+// Returns where a section of `length` bytes starting at `start` ends, failing if that is past
+// `file_length`.
+fn section_end(start: usize, length: u64, file_length: usize) -> StoreResult<usize> {
+    usize::try_from(length)
+        .ok()
+        .and_then(|length| start.checked_add(length))
+        .filter(|&end| end <= file_length)
+        .ok_or_else(ends_early)
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> StoreResult<u32> {
+    match bytes.get(position..).and_then(|bytes| bytes.first_chunk()) {
+        Some(chunk) => Ok(u32::from_le_bytes(*chunk)),
+        None => Err(ends_early()),
+    }
+}
+
+fn read_u64(bytes: &[u8], position: usize) -> StoreResult<u64> {
+    match bytes.get(position..).and_then(|bytes| bytes.first_chunk()) {
+        Some(chunk) => Ok(u64::from_le_bytes(*chunk)),
+        None => Err(ends_early()),
+    }
+}
@@ -0,0 +1,633 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    config::AnalysisConfig,
+    fingerprint::{
+        AnalysisParameters, Fingerprint, FingerprintDB, MatchResult, generate_fingerprints,
+    },
+    metadata::SongMetaData,
+    peaks::Peak,
+    segment::{Journal, Segment},
+};
+
+mod memory;
+mod mmap;
+mod sharded;
+mod sqlite;
+
+pub use mmap::MmapStore;
+pub use sharded::{ShardedStore, equal_width_boundaries};
+pub use sqlite::SqliteStore;
+
+pub type StoreResult<T> = crate::error::Result<T>;
+
+// Writes the file at `path` to a temporary file first, which is only renamed into place once it
+// was written completely. A crash halfway through never leaves a truncated file behind, and
+// readers never see a partial one.
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> StoreResult<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> StoreResult<()>,
+{
+    let temporary_path = write_temporary(path, write)?;
+    if let Err(err) = fs::rename(&temporary_path, path) {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+// Writes a file next to `path` and syncs it to disk, returning its path. The name is unique to
+// this process and call, `<file name>.<pid>.<n>.tmp`, so concurrent writers never share one, even
+// of files with the same stem. The file is removed again if writing it fails.
+pub(crate) fn write_temporary<F>(path: &Path, write: F) -> StoreResult<PathBuf>
+where
+    F: FnOnce(&mut BufWriter<File>) -> StoreResult<()>,
+{
+    static NEXT_TEMPORARY: AtomicUsize = AtomicUsize::new(0);
+
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        NEXT_TEMPORARY.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temporary_path = path.with_file_name(file_name);
+
+    let written = File::create(&temporary_path)
+        .map_err(Into::into)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+            Ok(())
+        });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(err);
+    }
+
+    Ok(temporary_path)
+}
+
+// A posting records where a fingerprint was found: (fingerprint, song_id, time_offset)
+pub type Posting = (Fingerprint, u32, u32);
+
+// Map (song_id, alignment_offset) to the number of votes, where the alignment offset is the time
+// in the song minus the time in the query, in ms
+pub type Votes = HashMap<(u32, i64), u32>;
+
+// What an index holds, read without decoding any postings. Every file format puts its songs and
+// parameters first, ahead of the postings, so that a header struct holding only those fields
+// decodes them and stops there, as `IndexHeader`, `SegmentHeader` and `ManifestHeader` do.
+#[derive(Debug, Default)]
+pub struct IndexSummary {
+    pub song_ids: Vec<u32>,
+    // Unset until the first song is added.
+    pub parameters: Option<AnalysisParameters>,
+}
+
+// The operations recognition and ingestion need from wherever the fingerprint index is stored.
+// Implemented by the in-memory `FingerprintDB`, the memory-mapped `MmapStore` and the SQLite
+// backed `SqliteStore`.
+pub trait FingerprintStore {
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()>;
+
+    // Returns every (song_id, time_offset) the fingerprint was found at.
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>>;
+
+    // Inserts the metadata of a song, replacing any previous metadata with the same song id.
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()>;
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>>;
+
+    // Removes a song along with all of its postings.
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>>;
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>>;
+
+    // Every posting in the store. Postings which cannot be read end the iteration with an error.
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>>;
+
+    // The parameters the fingerprints in the store were generated with, unset until the first
+    // song is added.
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>>;
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()>;
+
+    // Adopts the parameters of incoming fingerprints if the store has none yet, and fails if they
+    // differ from those of the fingerprints already in the store.
+    fn check_parameters(&mut self, parameters: &AnalysisParameters) -> StoreResult<()> {
+        match self.parameters()? {
+            Some(existing) => existing.check(parameters),
+            None => self.set_parameters(*parameters),
+        }
+    }
+
+    fn add_segment(&mut self, segment: Segment) -> StoreResult<()> {
+        self.check_parameters(&segment.parameters)?;
+        self.insert_postings(&segment.postings)?;
+        for song in segment.songs {
+            self.insert_song(song)?;
+        }
+
+        Ok(())
+    }
+
+    // Adds the segments of a journal to the store. Segments whose songs are already present are
+    // skipped, which makes replaying the journal idempotent should a compaction be interrupted
+    // after the index was written but before the journal was cleared.
+    fn replay_journal(&mut self, journal: &Journal) -> StoreResult<()> {
+        let segments = journal.read_segments()?;
+        log::info!("Replaying {} journal segments", segments.len());
+        for segment in segments {
+            let mut already_added = true;
+            for song in segment.songs.iter() {
+                already_added &= self.get_song(song.song_id)?.is_some();
+            }
+            if already_added {
+                log::debug!("Skipping segment already contained in the index");
+                continue;
+            }
+
+            self.add_segment(segment)?;
+        }
+
+        Ok(())
+    }
+
+    fn next_song_id(&self) -> StoreResult<u32> {
+        let songs = self.songs()?;
+        Ok(songs
+            .iter()
+            .map(|song| song.song_id)
+            .max()
+            .map_or(0, |id| id + 1))
+    }
+
+    fn recognize_song(
+        &self,
+        peaks: &[Peak],
+        config: &AnalysisConfig,
+    ) -> StoreResult<Option<(SongMetaData, MatchResult)>> {
+        log::info!("Recognizing song");
+        // A query analyzed differently from the songs would not match any of them.
+        if let Some(parameters) = self.parameters()? {
+            parameters.check(&AnalysisParameters::new(config))?;
+        }
+
+        let query_fingerprints = generate_fingerprints(peaks, config);
+        let total_query_fingerprints = query_fingerprints.len();
+        let vote_counter = self.count_votes(&query_fingerprints)?;
+
+        // Fetch the key corresponding to the highest number of votes
+        let result = vote_counter.iter().max_by_key(|(_key, value)| **value);
+
+        match result {
+            Some(((song_id, offset), votes)) => {
+                let confidence = *votes as f32 / total_query_fingerprints as f32;
+                let match_result = MatchResult::new(*song_id, confidence, *offset, *votes);
+                Ok(self
+                    .get_song(match_result.song_id)?
+                    .map(|metadata| (metadata, match_result)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Every posting matching a query fingerprint is a vote for the song it belongs to, at the
+    // alignment between song and query it implies.
+    fn count_votes(&self, query_fingerprints: &[(Fingerprint, u32)]) -> StoreResult<Votes> {
+        let mut vote_counter = Votes::new();
+
+        for &(query_fingerprint, time_offset) in query_fingerprints {
+            let fingerprint_match = self.lookup(query_fingerprint)?;
+
+            for (song_id, offset) in fingerprint_match.iter() {
+                // Database time - query time
+                let alignment_offset = i64::from(*offset) - i64::from(time_offset);
+                *vote_counter
+                    .entry((*song_id, alignment_offset))
+                    .or_insert(0) += 1
+            }
+        }
+
+        Ok(vote_counter)
+    }
+}
+
+// Lets a store opened with `open_store` be used wherever a concrete store is expected, such as in
+// a `Recognizer`. Every method is forwarded, so that overrides of the default methods still apply.
+impl<S: FingerprintStore + ?Sized> FingerprintStore for Box<S> {
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()> {
+        (**self).insert_postings(postings)
+    }
+
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>> {
+        (**self).lookup(fingerprint)
+    }
+
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()> {
+        (**self).insert_song(metadata)
+    }
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        (**self).get_song(song_id)
+    }
+
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        (**self).remove_song(song_id)
+    }
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>> {
+        (**self).songs()
+    }
+
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>> {
+        (**self).postings()
+    }
+
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>> {
+        (**self).parameters()
+    }
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()> {
+        (**self).set_parameters(parameters)
+    }
+
+    fn check_parameters(&mut self, parameters: &AnalysisParameters) -> StoreResult<()> {
+        (**self).check_parameters(parameters)
+    }
+
+    fn add_segment(&mut self, segment: Segment) -> StoreResult<()> {
+        (**self).add_segment(segment)
+    }
+
+    fn replay_journal(&mut self, journal: &Journal) -> StoreResult<()> {
+        (**self).replay_journal(journal)
+    }
+
+    fn next_song_id(&self) -> StoreResult<u32> {
+        (**self).next_song_id()
+    }
+
+    fn recognize_song(
+        &self,
+        peaks: &[Peak],
+        config: &AnalysisConfig,
+    ) -> StoreResult<Option<(SongMetaData, MatchResult)>> {
+        (**self).recognize_song(peaks, config)
+    }
+
+    fn count_votes(&self, query_fingerprints: &[(Fingerprint, u32)]) -> StoreResult<Votes> {
+        (**self).count_votes(query_fingerprints)
+    }
+}
+
+// Which `FingerprintStore` implementation to use for the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // The whole index is decoded into memory from a bincode file.
+    #[default]
+    Memory,
+    // The index is memory-mapped, and postings are decoded on lookup.
+    Mmap,
+    // The index is stored in an embedded SQLite database.
+    Sqlite,
+    // The index is partitioned by fingerprint into memory-mapped shards.
+    Sharded,
+}
+
+impl Backend {
+    // The default database file name for each backend.
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            Backend::Memory => "audio_fingerprint.db",
+            Backend::Mmap => "audio_fingerprint.mmap",
+            Backend::Sqlite => "audio_fingerprint.sqlite",
+            Backend::Sharded => "audio_fingerprint.shards",
+        }
+    }
+
+    // Whether newly analyzed songs are appended to a journal, which needs compacting, instead
+    // of being written to the store directly.
+    pub fn uses_journal(&self) -> bool {
+        !matches!(self, Backend::Sqlite)
+    }
+}
+
+pub fn open_store<P: AsRef<Path>>(
+    backend: Backend,
+    path: P,
+) -> StoreResult<Box<dyn FingerprintStore + Send>> {
+    Ok(match backend {
+        Backend::Memory => Box::new(FingerprintDB::load(path)?),
+        Backend::Mmap => Box::new(MmapStore::open(path)?),
+        Backend::Sqlite => Box::new(SqliteStore::open(path)?),
+        Backend::Sharded => Box::new(ShardedStore::open(path)?),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::AnalysisConfig,
+        error::Error,
+        fingerprint::{AnalysisParameters, Fingerprint, FingerprintDB},
+        metadata::SongMetaData,
+        peaks::Peak,
+        segment::Segment,
+        storage::{
+            FingerprintStore, MmapStore, Posting, ShardedStore, SqliteStore, StoreResult,
+            equal_width_boundaries,
+        },
+    };
+
+    fn check_store(store: &mut dyn FingerprintStore) {
+        let config = AnalysisConfig::default();
+        let peaks: Vec<Peak> = (0..200)
+            .map(|i| Peak::new(i * 4, 10 + (i * 7) % 200, 1.0))
+            .collect();
+
+        for song_id in 0..2 {
+            let metadata = SongMetaData {
+                song_id,
+                title: format!("song {song_id}"),
+                ..SongMetaData::default()
+            };
+            let song_peaks: Vec<Peak> = peaks
+                .iter()
+                .map(|p| Peak::new(p.time_bin, p.freq_bin + song_id as usize * 240, 1.0))
+                .collect();
+            store
+                .add_segment(Segment::new(metadata, &song_peaks, &config))
+                .unwrap();
+        }
+        assert_eq!(store.songs().unwrap().len(), 2);
+        assert_eq!(store.next_song_id().unwrap(), 2);
+
+        let query: Vec<Peak> = peaks[40..120].to_vec();
+        let (metadata, _) = store.recognize_song(&query, &config).unwrap().unwrap();
+        assert_eq!(metadata.song_id, 0);
+
+        // A query which starts 100 frames before the song does is aligned at a negative offset.
+        let query: Vec<Peak> = peaks[..80]
+            .iter()
+            .map(|p| Peak::new(p.time_bin + 100, p.freq_bin, 1.0))
+            .collect();
+        let (metadata, matched) = store.recognize_song(&query, &config).unwrap().unwrap();
+        let lead_ms = Peak::new(100, 0, 1.0).time_seconds(&config.spectrogram) * 1000.0;
+        assert_eq!(metadata.song_id, 0);
+        assert_eq!(matched.song_offset_ms(), 0);
+        assert!((matched.query_offset_ms() as f32 - lead_ms).abs() <= 1.0);
+
+        // Songs and queries analyzed with other parameters are refused, without writing anything.
+        let total_postings = store.postings().unwrap().count();
+        let mut mismatched = config;
+        mismatched.spectrogram.sample_rate = 44100.0;
+        let metadata = SongMetaData {
+            song_id: 2,
+            ..SongMetaData::default()
+        };
+        assert!(matches!(
+            store.add_segment(Segment::new(metadata, &peaks, &mismatched)),
+            Err(Error::ParameterMismatch { .. })
+        ));
+        assert!(matches!(
+            store.recognize_song(&query, &mismatched),
+            Err(Error::ParameterMismatch { .. })
+        ));
+        assert_eq!(store.songs().unwrap().len(), 2);
+        assert_eq!(store.postings().unwrap().count(), total_postings);
+
+        let removed = store.remove_song(0).unwrap().unwrap();
+        assert_eq!(removed.title, "song 0");
+        assert!(store.get_song(0).unwrap().is_none());
+        assert!(store.postings().unwrap().count() < total_postings);
+        assert!(
+            store
+                .postings()
+                .unwrap()
+                .all(|posting| matches!(posting, Ok((_, 1, _))))
+        );
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&mut FingerprintDB::new());
+    }
+
+    #[test]
+    fn mmap_store() {
+        let path = crate::temp_path("store.mmap");
+        let mut store = MmapStore::open(&path).unwrap();
+        check_store(&mut store);
+
+        // Persisting moves everything from memory into the mapped file.
+        store.save().unwrap();
+        let reopened = MmapStore::open(&path).unwrap();
+        assert_eq!(reopened.songs().unwrap().len(), 1);
+        assert_eq!(
+            reopened.parameters().unwrap(),
+            Some(AnalysisParameters::new(&AnalysisConfig::default()))
+        );
+        assert_eq!(
+            reopened.postings().unwrap().count(),
+            store.postings().unwrap().count()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sqlite_store() {
+        check_store(&mut SqliteStore::open_in_memory().unwrap());
+
+        // Postings are read a page at a time.
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let postings: Vec<Posting> = (0..10_000)
+            .map(|i| (Fingerprint::from_bits(i % 97), i % 3, i))
+            .collect();
+        store.insert_postings(&postings).unwrap();
+        let read = store
+            .postings()
+            .unwrap()
+            .collect::<StoreResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, postings);
+    }
+
+    #[test]
+    fn sharded_store() {
+        let directory = crate::temp_path("store.shards");
+        let mut store = ShardedStore::create(&directory, equal_width_boundaries(4));
+        check_store(&mut store);
+        store.save().unwrap();
+        assert!(store.verify().unwrap().is_empty());
+
+        // Saving a song added later writes only its shard, whose count is updated along with it.
+        let config = AnalysisConfig::default();
+        let peaks: Vec<Peak> = (0..50)
+            .map(|i| Peak::new(i * 4, 10 + (i * 3) % 200, 1.0))
+            .collect();
+        let metadata = SongMetaData {
+            song_id: 5,
+            ..SongMetaData::default()
+        };
+        let total_postings = store.posting_counts().iter().sum::<u64>();
+        store
+            .add_segment(Segment::new(metadata, &peaks, &config))
+            .unwrap();
+        store.save().unwrap();
+        assert!(store.verify().unwrap().is_empty());
+        assert!(store.posting_counts().iter().sum::<u64>() > total_postings);
+
+        // All fingerprints in the test share their leading bits, so only rebalancing spreads them.
+        let rebalanced = ShardedStore::rebalance(&directory, Some(3)).unwrap();
+        assert!(rebalanced.verify().unwrap().is_empty());
+        assert_eq!(rebalanced.posting_counts().len(), 3);
+        assert!(rebalanced.posting_counts().iter().all(|&count| count > 0));
+        assert_eq!(
+            rebalanced.postings().unwrap().count(),
+            store.postings().unwrap().count()
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Reads every posting, and looks up every fingerprint, of the store opened with `open`.
+    fn read_store<S: FingerprintStore>(open: impl Fn() -> StoreResult<S>) -> StoreResult<usize> {
+        let store = open()?;
+        let postings = store.postings()?.collect::<StoreResult<Vec<_>>>()?;
+        for (fingerprint, _, _) in postings.iter() {
+            store.lookup(*fingerprint)?;
+        }
+        Ok(postings.len())
+    }
+
+    #[test]
+    fn corrupt_mmap_store() {
+        let path = crate::temp_path("corrupt.mmap");
+        let mut store = MmapStore::open(&path).unwrap();
+        check_store(&mut store);
+        store.save().unwrap();
+        let total_postings = read_store(|| MmapStore::open_index(&path)).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        // Cut short anywhere, the index is either corrupt or missing the postings past the cut.
+        for length in 0..bytes.len() {
+            std::fs::write(&path, &bytes[..length]).unwrap();
+            match read_store(|| MmapStore::open_index(&path)) {
+                Ok(postings) => assert!(postings < total_postings, "cut at {length}"),
+                Err(Error::Corruption(_)) => {}
+                Err(err) => panic!("cut at {length}: {err}"),
+            }
+            let summary = MmapStore::index_summary(&path);
+            assert!(summary.is_ok() || matches!(summary, Err(Error::Corruption(_))));
+        }
+
+        // Lengths and offsets pointing past the end of the file.
+        let catalogue_length = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+        let entries_start = 20 + catalogue_length + 8;
+        for (position, value) in [(12, u64::MAX), (20 + catalogue_length, u64::MAX / 2)] {
+            let mut corrupt = bytes.clone();
+            corrupt[position..position + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&path, &corrupt).unwrap();
+            assert!(matches!(
+                MmapStore::open_index(&path),
+                Err(Error::Corruption(_))
+            ));
+        }
+        let mut corrupt = bytes.clone();
+        corrupt[entries_start + 4..entries_start + 12].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(matches!(
+            read_store(|| MmapStore::open_index(&path)),
+            Err(Error::Corruption(_))
+        ));
+
+        // A layout version this build does not know.
+        let mut corrupt = bytes.clone();
+        corrupt[8..12].copy_from_slice(&2u32.to_le_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(matches!(
+            MmapStore::index_summary(&path),
+            Err(Error::Corruption(message)) if message.contains("version 2")
+        ));
+
+        // A varint left unterminated at the end of the postings.
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() = 0xFF;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(matches!(
+            read_store(|| MmapStore::open_index(&path)),
+            Err(Error::Corruption(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_sharded_store() {
+        let directory = crate::temp_path("corrupt.shards");
+        let mut store = ShardedStore::create(&directory, equal_width_boundaries(4));
+        check_store(&mut store);
+        store.save().unwrap();
+
+        // The fingerprints of the test all route to the first shard.
+        let shard_path = directory.join("shard-0000.mmap");
+        let bytes = std::fs::read(&shard_path).unwrap();
+        std::fs::write(&shard_path, &bytes[..bytes.len() - 3]).unwrap();
+        let verified = ShardedStore::open_index(&directory).and_then(|store| store.verify());
+        match verified {
+            Ok(problems) => assert!(!problems.is_empty()),
+            Err(err) => assert!(matches!(err, Error::Corruption(_)), "{err}"),
+        }
+        std::fs::write(&shard_path, &bytes[..20]).unwrap();
+        assert!(matches!(
+            ShardedStore::open_index(&directory),
+            Err(Error::Corruption(_))
+        ));
+        std::fs::write(&shard_path, &bytes).unwrap();
+
+        // A manifest recording fewer posting counts than it has shards, or no shards at all.
+        let manifest_path = directory.join("manifest.bin");
+        let bincode_config = bincode::config::standard();
+        let songs = store.songs().unwrap();
+        let parameters = store.parameters().unwrap();
+        let manifest = (&songs, parameters, equal_width_boundaries(4), vec![0u64; 2]);
+        let encoded = bincode::serde::encode_to_vec(manifest, bincode_config).unwrap();
+        std::fs::write(&manifest_path, encoded).unwrap();
+        let problems = ShardedStore::open_index(&directory)
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("records none"))
+        );
+
+        let manifest = (&songs, parameters, Vec::<u32>::new(), Vec::<u64>::new());
+        let encoded = bincode::serde::encode_to_vec(manifest, bincode_config).unwrap();
+        std::fs::write(&manifest_path, encoded).unwrap();
+        assert!(matches!(
+            ShardedStore::open_index(&directory),
+            Err(Error::Corruption(_))
+        ));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
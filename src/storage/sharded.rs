@@ -0,0 +1,501 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crate::{
+    error::Error,
+    fingerprint::{AnalysisParameters, Fingerprint},
+    metadata::SongMetaData,
+    segment::Journal,
+    storage::{
+        FingerprintStore, IndexSummary, MmapStore, Posting, StoreResult, Votes, write_atomically,
+    },
+};
+
+// A single index holding every posting does not scale to large catalogues. The sharded store
+// partitions the postings by fingerprint into contiguous ranges of the hash space, each stored in
+// its own memory-mapped shard file. A query only touches the shards its fingerprints fall in,
+// and votes are counted in every shard concurrently before being merged.
+//
+// The directory holds `manifest.bin`, which records the songs, the parameters they were analyzed
+// with, the first fingerprint of every shard and the number of postings written to each, along
+// with the shard files
+// `shard-0000.mmap`, `shard-0001.mmap`, ...
+pub struct ShardedStore {
+    directory: PathBuf,
+    songs: HashMap<u32, SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+    // The first fingerprint in each shard, in ascending order, starting at 0.
+    boundaries: Vec<u32>,
+    // The number of postings in each shard file when the manifest was written.
+    posting_counts: Vec<u64>,
+    shards: Vec<MmapStore>,
+}
+
+// `songs` and `parameters` come first, see `IndexSummary`.
+#[derive(Serialize, Deserialize)]
+struct ShardManifest {
+    songs: Vec<SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+    boundaries: Vec<u32>,
+    posting_counts: Vec<u64>,
+}
+
+#[derive(Deserialize)]
+struct ManifestHeader {
+    songs: Vec<SongMetaData>,
+    parameters: Option<AnalysisParameters>,
+}
+
+const MANIFEST_FILE: &str = "manifest.bin";
+// Used when songs are added before the shards were ever built.
+const DEFAULT_SHARD_COUNT: usize = 16;
+
+impl ShardedStore {
+    // Creates an empty store with the given shard boundaries. Nothing is written until `save`.
+    pub fn create<P: AsRef<Path>>(directory: P, boundaries: Vec<u32>) -> Self {
+        let directory = directory.as_ref().to_path_buf();
+        let shards = (0..boundaries.len())
+            .map(|index| MmapStore::empty(Self::shard_path(&directory, index)))
+            .collect();
+
+        Self {
+            directory,
+            songs: HashMap::new(),
+            parameters: None,
+            posting_counts: vec![0; boundaries.len()],
+            boundaries,
+            shards,
+        }
+    }
+
+    // Opens the shards in `directory` and replays the journal on top.
+    pub fn open<P: AsRef<Path>>(directory: P) -> StoreResult<Self> {
+        let mut store = Self::open_index(&directory)?;
+        store.replay_journal(&Journal::for_database(&directory))?;
+
+        Ok(store)
+    }
+
+    // Opens only the shards in `directory`, ignoring the journal.
+    pub fn open_index<P: AsRef<Path>>(directory: P) -> StoreResult<Self> {
+        log::info!("Opening sharded fingerprint database");
+        let directory = directory.as_ref();
+
+        let file = match File::open(directory.join(MANIFEST_FILE)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::info!("Shard manifest not found, creating new one");
+                return Ok(Self::create(
+                    directory,
+                    equal_width_boundaries(DEFAULT_SHARD_COUNT),
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let bincode_config = bincode::config::standard();
+        let manifest: ShardManifest =
+            bincode::serde::decode_from_reader(BufReader::new(file), bincode_config)?;
+        // Every fingerprint is routed to one of the shards, so there must be at least one.
+        if manifest.boundaries.is_empty() {
+            return Err(Error::Corruption(String::from(
+                "the shard manifest does not list any shards",
+            )));
+        }
+
+        let mut shards = Vec::new();
+        for index in 0..manifest.boundaries.len() {
+            shards.push(MmapStore::open_index(Self::shard_path(directory, index))?);
+        }
+        log::info!("Opened {} shards", shards.len());
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            songs: manifest
+                .songs
+                .into_iter()
+                .map(|song| (song.song_id, song))
+                .collect(),
+            parameters: manifest.parameters,
+            boundaries: manifest.boundaries,
+            posting_counts: manifest.posting_counts,
+            shards,
+        })
+    }
+
+    // Copies every song and posting of `source` into a new sharded store in `directory`,
+    // replacing whatever was there before.
+    pub fn build<P: AsRef<Path>>(
+        source: &dyn FingerprintStore,
+        directory: P,
+        boundaries: Vec<u32>,
+    ) -> StoreResult<Self> {
+        let directory = directory.as_ref();
+        log::info!("Building {} shards in {:?}", boundaries.len(), directory);
+
+        // Build next to the existing shards, and only swap the new ones in once complete.
+        let mut building_directory = OsString::from(directory);
+        building_directory.push(".building");
+        let building_directory = PathBuf::from(building_directory);
+        if building_directory.exists() {
+            fs::remove_dir_all(&building_directory)?;
+        }
+
+        let mut store = Self::create(&building_directory, boundaries);
+        for song in source.songs()? {
+            store.insert_song(song)?;
+        }
+        if let Some(parameters) = source.parameters()? {
+            store.set_parameters(parameters)?;
+        }
+        let mut batch = Vec::new();
+        for posting in source.postings()? {
+            batch.push(posting?);
+            if batch.len() == 1 << 20 {
+                store.insert_postings(&batch)?;
+                batch.clear();
+            }
+        }
+        store.insert_postings(&batch)?;
+        store.save()?;
+        drop(store);
+
+        let mut old_directory = OsString::from(directory);
+        old_directory.push(".old");
+        let old_directory = PathBuf::from(old_directory);
+        if directory.exists() {
+            fs::rename(directory, &old_directory)?;
+        }
+        fs::rename(&building_directory, directory)?;
+        if old_directory.exists() {
+            fs::remove_dir_all(&old_directory)?;
+        }
+
+        Self::open_index(directory)
+    }
+
+    // Redistributes the postings so every shard holds roughly the same number of them,
+    // optionally changing the number of shards.
+    pub fn rebalance<P: AsRef<Path>>(
+        directory: P,
+        shard_count: Option<usize>,
+    ) -> StoreResult<Self> {
+        let store = Self::open_index(&directory)?;
+        let shard_count = shard_count.unwrap_or(store.boundaries.len());
+        log::info!("Rebalancing into {} shards", shard_count);
+
+        let boundaries = balanced_boundaries(&store, shard_count)?;
+        Self::build(&store, directory, boundaries)
+    }
+
+    // Writes every shard holding unsaved postings, followed by the manifest.
+    pub fn save(&mut self) -> StoreResult<()> {
+        fs::create_dir_all(&self.directory)?;
+        let mut posting_counts = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            let written = shard.has_changes() || !Self::shard_path(&self.directory, index).exists();
+            if written {
+                shard.save()?;
+            }
+            // The shards left as they were still hold as many postings as the manifest records,
+            // so only those written anew are counted again.
+            posting_counts.push(match self.posting_counts.get(index) {
+                Some(&count) if !written => count,
+                _ => {
+                    let mut count = 0;
+                    for posting in shard.postings()? {
+                        posting?;
+                        count += 1;
+                    }
+                    count
+                }
+            });
+        }
+        self.posting_counts = posting_counts;
+
+        let manifest = ShardManifest {
+            songs: self.songs()?,
+            parameters: self.parameters,
+            boundaries: self.boundaries.clone(),
+            posting_counts: self.posting_counts.clone(),
+        };
+        log::info!(
+            "Saving shard manifest with {} songs and {} postings in {} shards",
+            manifest.songs.len(),
+            manifest.posting_counts.iter().sum::<u64>(),
+            manifest.boundaries.len()
+        );
+
+        write_atomically(&self.directory.join(MANIFEST_FILE), |writer| {
+            let bincode_config = bincode::config::standard();
+            bincode::serde::encode_into_std_write(&manifest, writer, bincode_config)?;
+            Ok(())
+        })
+    }
+
+    // Writes the journal segments into the shards and clears the journal.
+    pub fn compact<P: AsRef<Path>>(directory: P) -> StoreResult<Self> {
+        log::info!("Compacting sharded fingerprint database");
+        let mut store = Self::open(&directory)?;
+        store.save()?;
+        Journal::for_database(&directory).clear()?;
+
+        Ok(store)
+    }
+
+    // Checks that the shards agree with the manifest, and that every posting is in the shard
+    // its fingerprint routes to and belongs to a known song. Returns a description of every
+    // problem found.
+    pub fn verify(&self) -> StoreResult<Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.boundaries.first() != Some(&0) {
+            problems.push(String::from(
+                "The first shard does not start at fingerprint 0",
+            ));
+        }
+        if self.boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+            problems.push(String::from(
+                "The shard boundaries are not strictly increasing",
+            ));
+        }
+
+        for (index, shard) in self.shards.iter().enumerate() {
+            log::info!("Verifying shard {}", index);
+            let path = Self::shard_path(&self.directory, index);
+            if !path.exists() {
+                problems.push(format!("Shard {index} is missing its file {path:?}"));
+                continue;
+            }
+
+            let (start, end) = self.shard_range(index);
+            let mut posting_count = 0u64;
+            let mut misplaced = 0u64;
+            let mut unknown_songs = 0u64;
+            for posting in shard.postings()? {
+                let (fingerprint, song_id, _) = posting?;
+                posting_count += 1;
+                let bits = fingerprint.to_bits() as u64;
+                if bits < start || bits >= end {
+                    misplaced += 1;
+                }
+                if !self.songs.contains_key(&song_id) {
+                    unknown_songs += 1;
+                }
+            }
+
+            match self.posting_counts.get(index) {
+                Some(&recorded) if recorded != posting_count => problems.push(format!(
+                    "Shard {index} holds {posting_count} postings, but the manifest records {recorded}"
+                )),
+                Some(_) => {}
+                None => problems.push(format!(
+                    "Shard {index} holds {posting_count} postings, but the manifest records none"
+                )),
+            }
+            if misplaced > 0 {
+                problems.push(format!(
+                    "Shard {index} holds {misplaced} postings outside its fingerprint range"
+                ));
+            }
+            if unknown_songs > 0 {
+                problems.push(format!(
+                    "Shard {index} holds {unknown_songs} postings of songs missing from the manifest"
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    // Returns the songs and parameters in the manifest, without opening any shards.
+    pub fn index_summary<P: AsRef<Path>>(directory: P) -> StoreResult<IndexSummary> {
+        match File::open(directory.as_ref().join(MANIFEST_FILE)) {
+            Ok(file) => {
+                let bincode_config = bincode::config::standard();
+                let header: ManifestHeader =
+                    bincode::serde::decode_from_reader(BufReader::new(file), bincode_config)?;
+                Ok(IndexSummary {
+                    song_ids: header.songs.iter().map(|song| song.song_id).collect(),
+                    parameters: header.parameters,
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(IndexSummary::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // The number of postings in each shard, as recorded in the manifest.
+    pub fn posting_counts(&self) -> &[u64] {
+        &self.posting_counts
+    }
+
+    fn shard_path(directory: &Path, index: usize) -> PathBuf {
+        directory.join(format!("shard-{index:04}.mmap"))
+    }
+
+    fn shard_index(&self, fingerprint: Fingerprint) -> usize {
+        self.boundaries
+            .partition_point(|&start| start <= fingerprint.to_bits())
+            .saturating_sub(1)
+    }
+
+    // The half-open range of fingerprints routed to the shard at `index`.
+    fn shard_range(&self, index: usize) -> (u64, u64) {
+        let start = self.boundaries[index] as u64;
+        let end = self
+            .boundaries
+            .get(index + 1)
+            .map_or(1 << 32, |&end| end as u64);
+        (start, end)
+    }
+}
+
+impl FingerprintStore for ShardedStore {
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()> {
+        let mut routed: Vec<Vec<Posting>> = vec![Vec::new(); self.shards.len()];
+        for &posting in postings {
+            routed[self.shard_index(posting.0)].push(posting);
+        }
+        for (shard, postings) in self.shards.iter_mut().zip(routed) {
+            if !postings.is_empty() {
+                shard.insert_postings(&postings)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>> {
+        self.shards[self.shard_index(fingerprint)].lookup(fingerprint)
+    }
+
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()> {
+        self.songs.insert(metadata.song_id, metadata);
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        Ok(self.songs.get(&song_id).cloned())
+    }
+
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        for shard in self.shards.iter_mut() {
+            shard.remove_song(song_id)?;
+        }
+
+        Ok(self.songs.remove(&song_id))
+    }
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>> {
+        let mut songs: Vec<SongMetaData> = self.songs.values().cloned().collect();
+        songs.sort_by_key(|song| song.song_id);
+        Ok(songs)
+    }
+
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>> {
+        let mut postings: Box<dyn Iterator<Item = StoreResult<Posting>> + '_> =
+            Box::new(std::iter::empty());
+        for shard in self.shards.iter() {
+            postings = Box::new(postings.chain(shard.postings()?));
+        }
+
+        Ok(postings)
+    }
+
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>> {
+        Ok(self.parameters)
+    }
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()> {
+        self.parameters = Some(parameters);
+        Ok(())
+    }
+
+    // Fans the query out to the shards its fingerprints route to, counting votes in each of them
+    // on its own thread, and merges the results.
+    fn count_votes(&self, query_fingerprints: &[(Fingerprint, u32)]) -> StoreResult<Votes> {
+        let mut routed: Vec<Vec<(Fingerprint, u32)>> = vec![Vec::new(); self.shards.len()];
+        for &(fingerprint, time_offset) in query_fingerprints {
+            routed[self.shard_index(fingerprint)].push((fingerprint, time_offset));
+        }
+
+        let shard_votes: Vec<StoreResult<Votes>> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .shards
+                .iter()
+                .zip(routed.iter())
+                .filter(|(_, fingerprints)| !fingerprints.is_empty())
+                .map(|(shard, fingerprints)| scope.spawn(move || shard.count_votes(fingerprints)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Shard query panicked"))
+                .collect()
+        });
+
+        let mut vote_counter = Votes::new();
+        for votes in shard_votes {
+            for (key, count) in votes? {
+                *vote_counter.entry(key).or_insert(0) += count;
+            }
+        }
+
+        Ok(vote_counter)
+    }
+}
+
+// Splits the fingerprint space into `shard_count` ranges of equal width, i.e., by the leading bits
+// of the fingerprint when `shard_count` is a power of two.
+pub fn equal_width_boundaries(shard_count: usize) -> Vec<u32> {
+    let shard_count = shard_count.max(1) as u64;
+    (0..shard_count)
+        .map(|index| ((index << 32) / shard_count) as u32)
+        .collect()
+}
+
+// Splits the fingerprint space into `shard_count` ranges holding roughly the same number of the
+// postings in `store`. Fingerprints are not spread evenly, since the leading bits encode the lower
+// of the two peak frequencies, which makes equal width shards lopsided.
+pub fn balanced_boundaries(
+    store: &dyn FingerprintStore,
+    shard_count: usize,
+) -> StoreResult<Vec<u32>> {
+    let shard_count = shard_count.max(1);
+
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+    for posting in store.postings()? {
+        let (fingerprint, _, _) = posting?;
+        *counts.entry(fingerprint.to_bits()).or_insert(0) += 1;
+    }
+    let total: u64 = counts.values().sum();
+
+    let mut boundaries = vec![0];
+    let mut accumulated = 0u64;
+    for (&fingerprint, &count) in counts.iter() {
+        // Start a new shard once the current ones hold their share of the postings.
+        let target = total * boundaries.len() as u64 / shard_count as u64;
+        if boundaries.len() < shard_count && accumulated >= target && fingerprint > 0 {
+            boundaries.push(fingerprint);
+        }
+        accumulated += count;
+    }
+
+    // Too few distinct fingerprints to fill every shard, pad with empty shards at the end.
+    while boundaries.len() < shard_count {
+        let last = *boundaries.last().unwrap();
+        if last == u32::MAX {
+            break;
+        }
+        boundaries.push(last + 1);
+    }
+
+    Ok(boundaries)
+}
@@ -0,0 +1,318 @@
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row, params};
+use std::{borrow::Cow, path::Path, vec};
+
+use crate::{
+    fingerprint::{AnalysisParameters, Fingerprint},
+    metadata::SongMetaData,
+    segment::Segment,
+    storage::{FingerprintStore, Posting, StoreResult},
+};
+
+// An index stored in an embedded SQLite database. Unlike the other backends, every insert is
+// written straight to disk, so there is no journal to compact.
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        log::info!("Opening SQLite fingerprint database");
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    // Opens another connection to an existing database, only for reading, so that it can be
+    // looked up on one thread while other connections are used on others.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        Ok(Self {
+            connection: Connection::open_with_flags(path, flags)?,
+        })
+    }
+
+    pub fn open_in_memory() -> StoreResult<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> StoreResult<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS songs (
+                song_id INTEGER PRIMARY KEY,
+                external_id TEXT,
+                title TEXT NOT NULL,
+                artist TEXT,
+                album TEXT,
+                isrc TEXT,
+                duration REAL NOT NULL,
+                sample_rate INTEGER NOT NULL,
+                source_path TEXT NOT NULL,
+                content_hash TEXT NOT NULL,
+                ingested_at INTEGER NOT NULL,
+                tags TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS postings (
+                fingerprint INTEGER NOT NULL,
+                song_id INTEGER NOT NULL,
+                time_offset INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS postings_by_fingerprint ON postings (fingerprint);
+            CREATE INDEX IF NOT EXISTS postings_by_song ON postings (song_id);
+            CREATE TABLE IF NOT EXISTS parameters (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                parameters TEXT NOT NULL
+            );",
+        )?;
+
+        Ok(Self { connection })
+    }
+}
+
+const SONG_COLUMNS: &str = "song_id, external_id, title, artist, album, isrc, duration, \
+                            sample_rate, source_path, content_hash, ingested_at, tags";
+
+// Reads a row selected with `SONG_COLUMNS`. The tags are stored as a JSON object.
+fn song_from_row(row: &Row) -> rusqlite::Result<SongMetaData> {
+    let tags: String = row.get(11)?;
+    Ok(SongMetaData {
+        song_id: row.get(0)?,
+        external_id: row.get(1)?,
+        title: row.get(2)?,
+        artist: row.get(3)?,
+        album: row.get(4)?,
+        isrc: row.get(5)?,
+        duration: row.get(6)?,
+        sample_rate: row.get(7)?,
+        source_path: row.get(8)?,
+        content_hash: row.get(9)?,
+        ingested_at: row.get::<_, i64>(10)? as u64,
+        tags: serde_json::from_str(&tags).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(11, rusqlite::types::Type::Text, err.into())
+        })?,
+    })
+}
+
+// The writes shared by the methods of the store and `add_segment`, which makes them in a single
+// transaction.
+fn insert_postings(connection: &Connection, postings: &[Posting]) -> StoreResult<()> {
+    let mut statement = connection.prepare_cached(
+        "INSERT INTO postings (fingerprint, song_id, time_offset) VALUES (?1, ?2, ?3)",
+    )?;
+    for (fingerprint, song_id, time_offset) in postings {
+        statement.execute(params![fingerprint.to_bits(), song_id, time_offset])?;
+    }
+
+    Ok(())
+}
+
+fn insert_song(connection: &Connection, metadata: &SongMetaData) -> StoreResult<()> {
+    connection.execute(
+        &format!(
+            "INSERT OR REPLACE INTO songs ({SONG_COLUMNS}) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+        ),
+        params![
+            metadata.song_id,
+            metadata.external_id,
+            metadata.title,
+            metadata.artist,
+            metadata.album,
+            metadata.isrc,
+            metadata.duration,
+            metadata.sample_rate,
+            metadata.source_path,
+            metadata.content_hash,
+            metadata.ingested_at as i64,
+            serde_json::to_string(&metadata.tags)?,
+        ],
+    )?;
+
+    Ok(())
+}
+
+// The parameters are stored as a JSON object, in the only row of the `parameters` table.
+fn read_parameters(connection: &Connection) -> StoreResult<Option<AnalysisParameters>> {
+    let parameters: Option<String> = connection
+        .query_row(
+            "SELECT parameters FROM parameters WHERE id = 0",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match parameters {
+        Some(parameters) => Ok(Some(serde_json::from_str(&parameters)?)),
+        None => Ok(None),
+    }
+}
+
+fn write_parameters(connection: &Connection, parameters: &AnalysisParameters) -> StoreResult<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO parameters (id, parameters) VALUES (0, ?1)",
+        params![serde_json::to_string(parameters)?],
+    )?;
+
+    Ok(())
+}
+
+// Reads the postings a page at a time, in the order they were inserted, so they are never all
+// held in memory at once.
+struct PostingPages<'a> {
+    connection: &'a Connection,
+    page: vec::IntoIter<Posting>,
+    // The rowid of the last posting read, which the next page starts after.
+    last_row: i64,
+    done: bool,
+}
+
+impl PostingPages<'_> {
+    const PAGE_SIZE: usize = 4096;
+
+    fn read_page(&mut self) -> StoreResult<Vec<Posting>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT rowid, fingerprint, song_id, time_offset FROM postings \
+             WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+        )?;
+        let mut rows = statement.query(params![self.last_row, Self::PAGE_SIZE as i64])?;
+
+        let mut page = Vec::with_capacity(Self::PAGE_SIZE);
+        while let Some(row) = rows.next()? {
+            self.last_row = row.get(0)?;
+            page.push((
+                Fingerprint::from_bits(row.get(1)?),
+                row.get(2)?,
+                row.get(3)?,
+            ));
+        }
+        Ok(page)
+    }
+}
+
+impl Iterator for PostingPages<'_> {
+    type Item = StoreResult<Posting>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(posting) = self.page.next() {
+            return Some(Ok(posting));
+        }
+        if self.done {
+            return None;
+        }
+
+        match self.read_page() {
+            Ok(page) => {
+                self.done = page.len() < Self::PAGE_SIZE;
+                self.page = page.into_iter();
+                self.page.next().map(Ok)
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl FingerprintStore for SqliteStore {
+    // Writes the parameters, postings and songs of the segment in a single transaction, so a
+    // crash never leaves postings without their song behind.
+    fn add_segment(&mut self, segment: Segment) -> StoreResult<()> {
+        let transaction = self.connection.transaction()?;
+        match read_parameters(&transaction)? {
+            Some(parameters) => parameters.check(&segment.parameters)?,
+            None => write_parameters(&transaction, &segment.parameters)?,
+        }
+        insert_postings(&transaction, &segment.postings)?;
+        for song in segment.songs.iter() {
+            insert_song(&transaction, song)?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn insert_postings(&mut self, postings: &[Posting]) -> StoreResult<()> {
+        let transaction = self.connection.transaction()?;
+        insert_postings(&transaction, postings)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn lookup(&self, fingerprint: Fingerprint) -> StoreResult<Cow<'_, [(u32, u32)]>> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT song_id, time_offset FROM postings WHERE fingerprint = ?1")?;
+        let postings = statement
+            .query_map(params![fingerprint.to_bits()], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<(u32, u32)>, _>>()?;
+
+        Ok(Cow::Owned(postings))
+    }
+
+    fn insert_song(&mut self, metadata: SongMetaData) -> StoreResult<()> {
+        insert_song(&self.connection, &metadata)
+    }
+
+    fn get_song(&self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        let song = self
+            .connection
+            .query_row(
+                &format!("SELECT {SONG_COLUMNS} FROM songs WHERE song_id = ?1"),
+                params![song_id],
+                song_from_row,
+            )
+            .optional()?;
+
+        Ok(song)
+    }
+
+    fn remove_song(&mut self, song_id: u32) -> StoreResult<Option<SongMetaData>> {
+        let song = self.get_song(song_id)?;
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM postings WHERE song_id = ?1", params![song_id])?;
+        transaction.execute("DELETE FROM songs WHERE song_id = ?1", params![song_id])?;
+        transaction.commit()?;
+
+        Ok(song)
+    }
+
+    fn songs(&self) -> StoreResult<Vec<SongMetaData>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {SONG_COLUMNS} FROM songs ORDER BY song_id"
+        ))?;
+        let songs = statement
+            .query_map([], song_from_row)?
+            .collect::<Result<Vec<SongMetaData>, _>>()?;
+
+        Ok(songs)
+    }
+
+    fn postings(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Posting>> + '_>> {
+        Ok(Box::new(PostingPages {
+            connection: &self.connection,
+            page: Vec::new().into_iter(),
+            last_row: 0,
+            done: false,
+        }))
+    }
+
+    fn next_song_id(&self) -> StoreResult<u32> {
+        let max_song_id: Option<u32> =
+            self.connection
+                .query_row("SELECT MAX(song_id) FROM songs", [], |row| row.get(0))?;
+
+        Ok(max_song_id.map_or(0, |id| id + 1))
+    }
+
+    fn parameters(&self) -> StoreResult<Option<AnalysisParameters>> {
+        read_parameters(&self.connection)
+    }
+
+    fn set_parameters(&mut self, parameters: AnalysisParameters) -> StoreResult<()> {
+        write_parameters(&self.connection, &parameters)
+    }
+}